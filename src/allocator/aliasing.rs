@@ -0,0 +1,185 @@
+use super::{on_same_page, AllocationKind};
+use crate::error::{Result, VkQueueError};
+
+// A resource that only lives from pass `first_use` to pass `last_use`, both inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransientResource {
+    pub size: u64,
+    pub alignment: u64,
+    pub kind: AllocationKind,
+    pub first_use: u32,
+    pub last_use: u32,
+}
+
+impl TransientResource {
+    fn lifetime_overlaps(&self, other: &TransientResource) -> bool {
+        self.first_use <= other.last_use && other.first_use <= self.last_use
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasingPlan {
+    // Offset of every resource, in the order they were passed in.
+    pub offsets: Vec<u64>,
+    pub size: u64,
+    pub alignment: u64,
+}
+
+// Places resources whose lifetimes overlap at disjoint offsets, and lets the others share memory.
+// Largest first, each at the lowest offset that doesn't collide with a resource alive at the same time.
+pub fn plan_aliasing(resources: &[TransientResource], buffer_image_granularity: u64) -> Result<AliasingPlan> {
+    if resources.iter().any(|resource| resource.size == 0) {
+        return Err(VkQueueError::InvalidParameter("transient resource size must be non-zero"));
+    }
+
+    let mut order: Vec<usize> = (0..resources.len()).collect();
+    order.sort_by_key(|&index| (std::cmp::Reverse(resources[index].size), index));
+
+    let mut offsets = vec![0; resources.len()];
+    // End of every placed resource; placing checks that it doesn't overflow.
+    let mut ends = vec![0; resources.len()];
+    let mut placed: Vec<usize> = Vec::with_capacity(resources.len());
+
+    for index in order {
+        let resource = &resources[index];
+        let alignment = resource.alignment.max(1);
+        let alive: Vec<usize> = placed
+            .iter()
+            .copied()
+            .filter(|&other| resources[other].lifetime_overlaps(resource))
+            .collect();
+
+        let collides = |offset: u64| {
+            let Some(end) = offset.checked_add(resource.size) else {
+                return true;
+            };
+            alive.iter().any(|&other| {
+                let other_start = offsets[other];
+                let other_end = ends[other];
+                if offset < other_end && other_start < end {
+                    return true;
+                }
+                resources[other].kind != resource.kind
+                    && ((other_end <= offset && on_same_page(other_end, offset, buffer_image_granularity))
+                        || (end <= other_start && on_same_page(end, other_start, buffer_image_granularity)))
+            })
+        };
+
+        let mut candidates = vec![0];
+        for &other in &alive {
+            let other_end = ends[other];
+            candidates.extend(checked_align_up(other_end, alignment));
+            candidates.extend(
+                checked_align_up(other_end, buffer_image_granularity)
+                    .and_then(|page_end| checked_align_up(page_end, alignment)),
+            );
+        }
+        candidates.sort_unstable();
+
+        // Only fails when even the end of the last live resource is past the address space.
+        let offset = candidates
+            .into_iter()
+            .find(|&offset| !collides(offset))
+            .ok_or(VkQueueError::InvalidParameter("transient resources don't fit in 64-bit offsets"))?;
+        offsets[index] = offset;
+        ends[index] = offset + resource.size;
+        placed.push(index);
+    }
+
+    let size = ends.iter().copied().max().unwrap_or(0);
+    let alignment = resources.iter().map(|resource| resource.alignment.max(1)).max().unwrap_or(1);
+
+    Ok(AliasingPlan {
+        offsets,
+        size,
+        alignment,
+    })
+}
+
+fn checked_align_up(value: u64, alignment: u64) -> Option<u64> {
+    if alignment <= 1 {
+        Some(value)
+    } else {
+        value.div_ceil(alignment).checked_mul(alignment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(size: u64, kind: AllocationKind, first_use: u32, last_use: u32) -> TransientResource {
+        TransientResource {
+            size,
+            alignment: 16,
+            kind,
+            first_use,
+            last_use,
+        }
+    }
+
+    #[test]
+    fn disjoint_lifetimes_share_memory() {
+        let resources = [
+            resource(256, AllocationKind::Linear, 0, 1),
+            resource(128, AllocationKind::Linear, 2, 3),
+            resource(64, AllocationKind::Linear, 4, 4),
+        ];
+        let plan = plan_aliasing(&resources, 1).unwrap();
+        assert_eq!(plan.offsets, [0, 0, 0]);
+        assert_eq!(plan.size, 256);
+        assert_eq!(plan.alignment, 16);
+    }
+
+    #[test]
+    fn overlapping_lifetimes_get_disjoint_ranges() {
+        let resources = [
+            resource(100, AllocationKind::Linear, 0, 2),
+            resource(200, AllocationKind::Linear, 2, 3),
+            resource(50, AllocationKind::Linear, 3, 4),
+        ];
+        let plan = plan_aliasing(&resources, 1).unwrap();
+        // Largest first: 200 at 0, 100 after it, 50 only overlaps the 200 and takes 100's place.
+        assert_eq!(plan.offsets, [208, 0, 208]);
+        assert_eq!(plan.size, 308);
+    }
+
+    #[test]
+    fn mixed_kinds_keep_to_their_own_pages() {
+        let resources = [
+            resource(100, AllocationKind::Linear, 0, 1),
+            resource(100, AllocationKind::Optimal, 0, 1),
+            resource(20, AllocationKind::Linear, 0, 1),
+        ];
+        let plan = plan_aliasing(&resources, 1024).unwrap();
+        // The second buffer can sit right after the first, the image needs the next page.
+        assert_eq!(plan.offsets, [0, 1024, 112]);
+        assert_eq!(plan.size, 1124);
+
+        // Without overlapping lifetimes the granularity doesn't matter.
+        let resources = [
+            resource(100, AllocationKind::Linear, 0, 0),
+            resource(100, AllocationKind::Optimal, 1, 1),
+        ];
+        assert_eq!(plan_aliasing(&resources, 1024).unwrap().offsets, [0, 0]);
+    }
+
+    #[test]
+    fn rejects_empty_and_overflowing_resources() {
+        let empty = [resource(0, AllocationKind::Linear, 0, 0)];
+        assert!(plan_aliasing(&empty, 1024).is_err());
+
+        let huge = [
+            resource(u64::MAX - 8, AllocationKind::Linear, 0, 0),
+            resource(64, AllocationKind::Linear, 0, 0),
+        ];
+        assert!(plan_aliasing(&huge, 1).is_err());
+    }
+
+    #[test]
+    fn no_resources_plan_nothing() {
+        let plan = plan_aliasing(&[], 1024).unwrap();
+        assert!(plan.offsets.is_empty());
+        assert_eq!(plan.size, 0);
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use ash::vk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetPolicy {
+    #[default]
+    Warn,
+    Refuse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetCheck {
+    Ok,
+    OverBudget {
+        heap_index: u32,
+        requested: u64,
+        budget: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeapStats {
+    pub size: u64,
+    pub device_local: bool,
+    // Bytes and number of vkAllocateMemory calls made by this manager.
+    pub allocated_bytes: u64,
+    pub allocation_count: u32,
+    // Bytes handed out from blocks, which is at most `allocated_bytes`.
+    pub used_bytes: u64,
+    pub budget: Option<u64>,
+    // Process-wide usage as reported by VK_EXT_memory_budget.
+    pub usage: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTypeStats {
+    pub heap_index: u32,
+    pub property_flags: vk::MemoryPropertyFlags,
+    pub allocated_bytes: u64,
+    pub allocation_count: u32,
+    pub used_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub heaps: Vec<HeapStats>,
+    pub types: Vec<MemoryTypeStats>,
+}
+
+impl MemoryStats {
+    pub fn total_allocated(&self) -> u64 {
+        self.heaps.iter().map(|heap| heap.allocated_bytes).sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReportedBudget {
+    budget: u64,
+    usage: u64,
+    // Our own bytes on the heap at the time of the query, so later allocations can be added on top.
+    allocated_at_query: u64,
+}
+
+// Keeps count of device memory per heap and type. `M` is the memory handle, so the bookkeeping
+// and budget checks can run against simulated memory properties.
+pub struct MemoryTracker<M = vk::DeviceMemory> {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    type_bytes: Vec<u64>,
+    type_counts: Vec<u32>,
+    limits: Vec<Option<u64>>,
+    reported: Vec<Option<ReportedBudget>>,
+    policy: BudgetPolicy,
+    allocations: HashMap<M, (u32, u64)>,
+}
+
+impl<M: Copy + Eq + Hash> MemoryTracker<M> {
+    pub fn new(memory_properties: &vk::PhysicalDeviceMemoryProperties) -> Self {
+        let type_count = memory_properties.memory_type_count as usize;
+        let heap_count = memory_properties.memory_heap_count as usize;
+        Self {
+            memory_properties: *memory_properties,
+            type_bytes: vec![0; type_count],
+            type_counts: vec![0; type_count],
+            limits: vec![None; heap_count],
+            reported: vec![None; heap_count],
+            policy: BudgetPolicy::default(),
+            allocations: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> BudgetPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: BudgetPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_heap_limit(&mut self, heap_index: u32, limit: Option<u64>) {
+        if let Some(slot) = self.limits.get_mut(heap_index as usize) {
+            *slot = limit;
+        }
+    }
+
+    // Takes the heapBudget/heapUsage arrays of VkPhysicalDeviceMemoryBudgetPropertiesEXT.
+    pub fn set_reported_budget(&mut self, budgets: &[u64], usages: &[u64]) {
+        for heap_index in 0..self.reported.len() {
+            let allocated_at_query = self.heap_allocated(heap_index as u32);
+            self.reported[heap_index] = Some(ReportedBudget {
+                budget: budgets[heap_index],
+                usage: usages[heap_index],
+                allocated_at_query,
+            });
+        }
+    }
+
+    pub fn heap_index(&self, memory_type_index: u32) -> u32 {
+        self.memory_properties.memory_types[memory_type_index as usize].heap_index
+    }
+
+    pub fn heap_allocated(&self, heap_index: u32) -> u64 {
+        (0..self.type_bytes.len())
+            .filter(|&type_index| self.heap_index(type_index as u32) == heap_index)
+            .map(|type_index| self.type_bytes[type_index])
+            .sum()
+    }
+
+    // The lower of the configured limit and what the driver reports, counted in our own bytes.
+    pub fn heap_budget(&self, heap_index: u32) -> Option<u64> {
+        let limit = self.limits[heap_index as usize];
+        let reported = self.reported[heap_index as usize].map(|reported| {
+            let others = reported.usage.saturating_sub(reported.allocated_at_query);
+            reported.budget.saturating_sub(others)
+        });
+        match (limit, reported) {
+            (Some(limit), Some(reported)) => Some(limit.min(reported)),
+            (limit, reported) => limit.or(reported),
+        }
+    }
+
+    pub fn check(&self, memory_type_index: u32, size: u64) -> BudgetCheck {
+        let heap_index = self.heap_index(memory_type_index);
+        match self.heap_budget(heap_index) {
+            Some(budget) if self.heap_allocated(heap_index) + size > budget => BudgetCheck::OverBudget {
+                heap_index,
+                requested: size,
+                budget,
+            },
+            _ => BudgetCheck::Ok,
+        }
+    }
+
+    pub fn record_allocate(&mut self, memory: M, memory_type_index: u32, size: u64) {
+        self.type_bytes[memory_type_index as usize] += size;
+        self.type_counts[memory_type_index as usize] += 1;
+        self.allocations.insert(memory, (memory_type_index, size));
+    }
+
+    pub fn record_free(&mut self, memory: M) {
+        if let Some((memory_type_index, size)) = self.allocations.remove(&memory) {
+            self.type_bytes[memory_type_index as usize] -= size;
+            self.type_counts[memory_type_index as usize] -= 1;
+        }
+    }
+
+    // `used_bytes` gives the sub-allocated bytes per memory type.
+    pub fn stats(&self, used_bytes: impl Fn(u32) -> u64) -> MemoryStats {
+        let types: Vec<_> = (0..self.type_bytes.len())
+            .map(|type_index| {
+                let memory_type = self.memory_properties.memory_types[type_index];
+                MemoryTypeStats {
+                    heap_index: memory_type.heap_index,
+                    property_flags: memory_type.property_flags,
+                    allocated_bytes: self.type_bytes[type_index],
+                    allocation_count: self.type_counts[type_index],
+                    used_bytes: used_bytes(type_index as u32),
+                }
+            })
+            .collect();
+
+        let heaps = (0..self.limits.len())
+            .map(|heap_index| {
+                let heap = self.memory_properties.memory_heaps[heap_index];
+                let on_heap = types.iter().filter(|memory_type| memory_type.heap_index == heap_index as u32);
+                HeapStats {
+                    size: heap.size,
+                    device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                    allocated_bytes: on_heap.clone().map(|memory_type| memory_type.allocated_bytes).sum(),
+                    allocation_count: on_heap.clone().map(|memory_type| memory_type.allocation_count).sum(),
+                    used_bytes: on_heap.map(|memory_type| memory_type.used_bytes).sum(),
+                    budget: self.heap_budget(heap_index as u32),
+                    usage: self.reported[heap_index].map(|reported| {
+                        (reported.usage + self.heap_allocated(heap_index as u32)).saturating_sub(reported.allocated_at_query)
+                    }),
+                }
+            })
+            .collect();
+
+        MemoryStats { heaps, types }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Heap 0 is device local, heap 1 is system memory.
+    fn tracker() -> MemoryTracker<u32> {
+        let mut properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 3,
+            memory_heap_count: 2,
+            ..Default::default()
+        };
+        properties.memory_types[0].heap_index = 0;
+        properties.memory_types[1].heap_index = 1;
+        properties.memory_types[2].heap_index = 1;
+        properties.memory_heaps[0] = vk::MemoryHeap { size: 4096, flags: vk::MemoryHeapFlags::DEVICE_LOCAL };
+        properties.memory_heaps[1] = vk::MemoryHeap { size: 8192, flags: vk::MemoryHeapFlags::empty() };
+        MemoryTracker::new(&properties)
+    }
+
+    #[test]
+    fn unlimited_without_a_budget() {
+        let mut tracker = tracker();
+        tracker.record_allocate(1, 0, 1 << 40);
+        assert_eq!(tracker.check(0, 1 << 40), BudgetCheck::Ok);
+        assert_eq!(tracker.policy(), BudgetPolicy::Warn);
+    }
+
+    #[test]
+    fn limit_counts_every_type_on_the_heap() {
+        let mut tracker = tracker();
+        tracker.set_heap_limit(1, Some(1000));
+        tracker.record_allocate(1, 1, 400);
+        tracker.record_allocate(2, 2, 400);
+        assert_eq!(tracker.check(1, 200), BudgetCheck::Ok);
+        assert_eq!(
+            tracker.check(2, 201),
+            BudgetCheck::OverBudget { heap_index: 1, requested: 201, budget: 1000 }
+        );
+        // The other heap isn't affected.
+        assert_eq!(tracker.check(0, 4096), BudgetCheck::Ok);
+
+        tracker.record_free(1);
+        assert_eq!(tracker.check(2, 600), BudgetCheck::Ok);
+        // Unknown memory is ignored.
+        tracker.record_free(7);
+        assert_eq!(tracker.heap_allocated(1), 400);
+    }
+
+    #[test]
+    fn reported_budget_leaves_room_for_other_processes() {
+        let mut tracker = tracker();
+        tracker.record_allocate(1, 0, 100);
+        // 400 bytes in use, 100 of them ours: 300 belong to someone else.
+        tracker.set_reported_budget(&[1000, 0], &[400, 0]);
+        assert_eq!(tracker.heap_budget(0), Some(700));
+
+        // Allocations after the query are counted on top of it.
+        tracker.record_allocate(2, 0, 200);
+        assert_eq!(tracker.check(0, 400), BudgetCheck::Ok);
+        assert_eq!(
+            tracker.check(0, 401),
+            BudgetCheck::OverBudget { heap_index: 0, requested: 401, budget: 700 }
+        );
+
+        let heap = &tracker.stats(|_| 0).heaps[0];
+        assert_eq!(heap.allocated_bytes, 300);
+        assert_eq!(heap.usage, Some(600));
+        assert_eq!(heap.budget, Some(700));
+    }
+
+    #[test]
+    fn the_lower_budget_wins() {
+        let mut tracker = tracker();
+        tracker.set_reported_budget(&[1000, 5000], &[0, 0]);
+        tracker.set_heap_limit(0, Some(500));
+        tracker.set_heap_limit(1, Some(6000));
+        assert_eq!(tracker.heap_budget(0), Some(500));
+        assert_eq!(tracker.heap_budget(1), Some(5000));
+
+        tracker.set_heap_limit(0, None);
+        assert_eq!(tracker.heap_budget(0), Some(1000));
+    }
+}
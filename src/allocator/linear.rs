@@ -0,0 +1,69 @@
+use super::align_up;
+
+// Bump allocator: allocations are only released all at once, e.g. at the start of a frame.
+#[derive(Debug, Clone)]
+pub struct LinearAllocator {
+    capacity: u64,
+    head: u64,
+}
+
+impl LinearAllocator {
+    pub fn new(capacity: u64) -> Self {
+        Self { capacity, head: 0 }
+    }
+
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let offset = align_up(self.head, alignment);
+        let end = offset.checked_add(size)?;
+        if end > self.capacity {
+            return None;
+        }
+        self.head = end;
+        Some(offset)
+    }
+
+    pub fn reset(&mut self) {
+        self.head = 0;
+    }
+
+    pub fn used(&self) -> u64 {
+        self.head
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_alignment() {
+        let mut allocator = LinearAllocator::new(256);
+        assert_eq!(allocator.allocate(10, 1), Some(0));
+        assert_eq!(allocator.allocate(10, 16), Some(16));
+        assert_eq!(allocator.allocate(1, 64), Some(64));
+        assert_eq!(allocator.used(), 65);
+    }
+
+    #[test]
+    fn refuses_past_capacity() {
+        let mut allocator = LinearAllocator::new(100);
+        assert_eq!(allocator.allocate(60, 1), Some(0));
+        assert_eq!(allocator.allocate(41, 1), None);
+        assert_eq!(allocator.allocate(40, 1), Some(60));
+        assert_eq!(allocator.allocate(1, 1), None);
+        assert_eq!(allocator.allocate(u64::MAX, 1), None);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut allocator = LinearAllocator::new(100);
+        allocator.allocate(100, 1);
+        allocator.reset();
+        assert_eq!(allocator.used(), 0);
+        assert_eq!(allocator.allocate(8, 8), Some(0));
+    }
+}
@@ -0,0 +1,105 @@
+use std::ops::{Deref, DerefMut};
+
+use ash::vk;
+use bytemuck::Pod;
+use log::warn;
+
+use crate::error::Result;
+
+// Flush and invalidate ranges have to start and end on multiples of nonCoherentAtomSize,
+// unless they run to the end of the memory object.
+pub fn atom_range(offset: u64, size: u64, atom_size: u64, memory_size: u64) -> (u64, u64) {
+    let atom_size = atom_size.max(1);
+    let start = offset / atom_size * atom_size;
+    let end = (offset + size).div_ceil(atom_size) * atom_size;
+    if end >= memory_size {
+        (start, vk::WHOLE_SIZE)
+    } else {
+        (start, end - start)
+    }
+}
+
+// Describes the mapped memory a view was taken from. `range` is None for coherent memory.
+#[derive(Clone, Copy)]
+pub(crate) struct MappedRange {
+    pub memory: vk::DeviceMemory,
+    pub range: Option<(u64, u64)>,
+}
+
+impl MappedRange {
+    fn memory_range(&self) -> Option<vk::MappedMemoryRange> {
+        self.range.map(|(offset, size)| {
+            vk::MappedMemoryRange::builder()
+                .memory(self.memory)
+                .offset(offset)
+                .size(size)
+                .build()
+        })
+    }
+}
+
+// Read-only view of a persistently mapped allocation. Non-coherent memory is invalidated when
+// the view is created, so GPU writes made visible to the host before that show up.
+pub struct MappedSlice<'a, T: Pod> {
+    data: &'a [T],
+}
+
+impl<'a, T: Pod> MappedSlice<'a, T> {
+    pub(crate) fn new(device: &ash::Device, range: MappedRange, data: &'a [T]) -> Result<Self> {
+        if let Some(memory_range) = range.memory_range() {
+            unsafe { device.invalidate_mapped_memory_ranges(std::slice::from_ref(&memory_range))? };
+        }
+        Ok(Self { data })
+    }
+}
+
+impl<T: Pod> Deref for MappedSlice<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.data
+    }
+}
+
+// Writable view of a persistently mapped allocation. Non-coherent memory is flushed on drop,
+// or earlier with `flush`.
+pub struct MappedSliceMut<'a, T: Pod> {
+    device: &'a ash::Device,
+    range: MappedRange,
+    data: &'a mut [T],
+}
+
+impl<'a, T: Pod> MappedSliceMut<'a, T> {
+    pub(crate) fn new(device: &'a ash::Device, range: MappedRange, data: &'a mut [T]) -> Self {
+        Self { device, range, data }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        if let Some(memory_range) = self.range.memory_range() {
+            unsafe { self.device.flush_mapped_memory_ranges(std::slice::from_ref(&memory_range))? };
+        }
+        Ok(())
+    }
+}
+
+impl<T: Pod> Deref for MappedSliceMut<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.data
+    }
+}
+
+impl<T: Pod> DerefMut for MappedSliceMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.data
+    }
+}
+
+impl<T: Pod> Drop for MappedSliceMut<'_, T> {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            warn!("Failed to flush mapped memory: {}", error);
+        }
+    }
+}
@@ -0,0 +1,660 @@
+use std::collections::BTreeMap;
+
+use ash::vk;
+
+use crate::error::{Result, VkQueueError};
+
+mod aliasing;
+mod budget;
+mod linear;
+mod mapped;
+mod pool;
+mod ring;
+
+pub use aliasing::{plan_aliasing, AliasingPlan, TransientResource};
+pub use budget::{BudgetCheck, BudgetPolicy, HeapStats, MemoryStats, MemoryTracker, MemoryTypeStats};
+pub use linear::LinearAllocator;
+pub use mapped::{atom_range, MappedSlice, MappedSliceMut};
+pub(crate) use mapped::MappedRange;
+pub use pool::PoolAllocator;
+pub use ring::RingAllocator;
+
+const LARGE_HEAP_SIZE: u64 = 1024 * 1024 * 1024;
+const DEFAULT_BLOCK_SIZE: u64 = 256 * 1024 * 1024;
+
+// bufferImageGranularity only applies between linear and optimal resources sharing a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationKind {
+    // Buffers and images with linear tiling.
+    Linear,
+    // Images with optimal tiling.
+    Optimal,
+}
+
+// `M` is the memory handle, `vk::DeviceMemory` on a real device. Anything Copy works, so the
+// allocator can be driven with fake handles and simulated memory properties.
+//
+// An allocation owns its range: it can't be copied or built outside the crate, and freeing it
+// consumes it, so a `&mut Allocation` is the only way to write to the range.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Allocation<M = vk::DeviceMemory> {
+    pub(crate) memory: M,
+    pub(crate) memory_type_index: u32,
+    pub(crate) block: usize,
+    pub(crate) offset: vk::DeviceSize,
+    pub(crate) size: vk::DeviceSize,
+    pub(crate) kind: AllocationKind,
+    // Sole occupant of its memory object.
+    pub(crate) dedicated: bool,
+}
+
+impl<M: Copy> Allocation<M> {
+    pub fn memory(&self) -> M {
+        self.memory
+    }
+
+    pub fn memory_type_index(&self) -> u32 {
+        self.memory_type_index
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn kind(&self) -> AllocationKind {
+        self.kind
+    }
+
+    pub fn is_dedicated(&self) -> bool {
+        self.dedicated
+    }
+
+    pub fn range(&self) -> AllocationRange<M> {
+        AllocationRange {
+            memory: self.memory,
+            memory_type_index: self.memory_type_index,
+            offset: self.offset,
+            size: self.size,
+        }
+    }
+}
+
+// Where a live allocation sits, without owning it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationRange<M = vk::DeviceMemory> {
+    pub memory: M,
+    pub memory_type_index: u32,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+}
+
+// Copy `src` to `dst`, then point the resource at `dst` and free the allocation it had. The
+// source range stays reserved until then.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DefragmentationMove<M = vk::DeviceMemory> {
+    pub src: AllocationRange<M>,
+    pub dst: Allocation<M>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    size: u64,
+    alignment: u64,
+    kind: AllocationKind,
+}
+
+#[derive(Debug)]
+struct Block<M> {
+    memory: M,
+    size: u64,
+    // Holds a single allocation and is released together with it.
+    dedicated: bool,
+    ranges: BTreeMap<u64, Range>,
+}
+
+pub fn align_up(value: u64, alignment: u64) -> u64 {
+    if alignment <= 1 {
+        value
+    } else {
+        value.div_ceil(alignment) * alignment
+    }
+}
+
+// Whether the last byte of one resource and the first byte of the next share a granularity page.
+pub fn on_same_page(end_offset: u64, next_offset: u64, granularity: u64) -> bool {
+    granularity > 1 && (end_offset - 1) / granularity == next_offset / granularity
+}
+
+impl<M: Copy> Block<M> {
+    fn new(memory: M, size: u64, dedicated: bool) -> Self {
+        Self {
+            memory,
+            size,
+            dedicated,
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    // First fit over the gaps between live allocations.
+    fn find(&self, size: u64, alignment: u64, kind: AllocationKind, granularity: u64) -> Option<u64> {
+        let mut cursor = 0;
+        let mut previous: Option<AllocationKind> = None;
+        let next_ranges = self
+            .ranges
+            .iter()
+            .map(|(&offset, range)| (offset, Some(*range)))
+            .chain(std::iter::once((self.size, None)));
+
+        for (gap_end, next) in next_ranges {
+            let mut offset = align_up(cursor, alignment);
+            if previous.is_some_and(|previous| previous != kind) && on_same_page(cursor, offset, granularity) {
+                offset = align_up(align_up(offset, granularity), alignment);
+            }
+
+            let end = offset + size;
+            let conflicts_with_next =
+                next.is_some_and(|next| next.kind != kind && on_same_page(end, gap_end, granularity));
+            if end <= gap_end && !conflicts_with_next {
+                return Some(offset);
+            }
+
+            if let Some(next) = next {
+                cursor = gap_end + next.size;
+                previous = Some(next.kind);
+            }
+        }
+        None
+    }
+
+    fn used(&self) -> u64 {
+        self.ranges.values().map(|range| range.size).sum()
+    }
+}
+
+pub struct BlockAllocator<M = vk::DeviceMemory> {
+    granularity: u64,
+    block_sizes: Vec<u64>,
+    // Defaults to half the block size of the memory type.
+    dedicated_threshold: Option<u64>,
+    pools: Vec<Vec<Option<Block<M>>>>,
+}
+
+impl<M: Copy + PartialEq> BlockAllocator<M> {
+    pub fn new(memory_properties: &vk::PhysicalDeviceMemoryProperties, buffer_image_granularity: u64) -> Self {
+        let block_sizes = memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+            .iter()
+            .map(|memory_type| {
+                let heap_size = memory_properties.memory_heaps[memory_type.heap_index as usize].size;
+                preferred_block_size(heap_size)
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            granularity: buffer_image_granularity.max(1),
+            dedicated_threshold: None,
+            pools: block_sizes.iter().map(|_| Vec::new()).collect(),
+            block_sizes,
+        }
+    }
+
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        for size in &mut self.block_sizes {
+            *size = block_size;
+        }
+        self
+    }
+
+    pub fn block_size(&self, memory_type_index: u32) -> u64 {
+        self.block_sizes[memory_type_index as usize]
+    }
+
+    pub fn set_dedicated_threshold(&mut self, threshold: Option<u64>) {
+        self.dedicated_threshold = threshold;
+    }
+
+    // Whether a resource should get a memory object of its own rather than a slice of a block.
+    pub fn wants_dedicated(&self, memory_type_index: u32, size: u64, driver_prefers_dedicated: bool) -> bool {
+        let block_size = self.block_sizes[memory_type_index as usize];
+        driver_prefers_dedicated || size > self.dedicated_threshold.unwrap_or(block_size / 2)
+    }
+
+    // `create_block` is called with the block size whenever no existing block has room.
+    pub fn allocate(
+        &mut self,
+        memory_type_index: u32,
+        requirements: &vk::MemoryRequirements,
+        kind: AllocationKind,
+        create_block: impl FnOnce(u64) -> Result<M>,
+    ) -> Result<Allocation<M>> {
+        self.allocate_in(memory_type_index, requirements, kind, false, create_block)
+    }
+
+    // `create_memory` is called with the exact size of the allocation.
+    pub fn allocate_dedicated(
+        &mut self,
+        memory_type_index: u32,
+        requirements: &vk::MemoryRequirements,
+        kind: AllocationKind,
+        create_memory: impl FnOnce(u64) -> Result<M>,
+    ) -> Result<Allocation<M>> {
+        self.allocate_in(memory_type_index, requirements, kind, true, create_memory)
+    }
+
+    fn allocate_in(
+        &mut self,
+        memory_type_index: u32,
+        requirements: &vk::MemoryRequirements,
+        kind: AllocationKind,
+        dedicated: bool,
+        create_block: impl FnOnce(u64) -> Result<M>,
+    ) -> Result<Allocation<M>> {
+        let type_index = memory_type_index as usize;
+        if type_index >= self.pools.len() {
+            return Err(VkQueueError::InvalidParameter("memory type index out of range"));
+        }
+        if requirements.size == 0 {
+            return Err(VkQueueError::InvalidParameter("allocation size must be non-zero"));
+        }
+
+        let size = requirements.size;
+        let alignment = requirements.alignment.max(1);
+        let block_size = self.block_sizes[type_index];
+        let pool = &mut self.pools[type_index];
+
+        if !dedicated && size <= block_size {
+            for (index, block) in pool.iter_mut().enumerate() {
+                let block = match block {
+                    Some(block) if !block.dedicated => block,
+                    _ => continue,
+                };
+                if let Some(offset) = block.find(size, alignment, kind, self.granularity) {
+                    block.ranges.insert(offset, Range { size, alignment, kind });
+                    return Ok(Allocation {
+                        memory: block.memory,
+                        memory_type_index,
+                        block: index,
+                        offset,
+                        size,
+                        kind,
+                        dedicated: false,
+                    });
+                }
+            }
+        }
+
+        let dedicated = dedicated || size > block_size;
+        let new_block_size = if dedicated { size } else { block_size };
+        let memory = create_block(new_block_size)?;
+        let mut block = Block::new(memory, new_block_size, dedicated);
+        block.ranges.insert(0, Range { size, alignment, kind });
+
+        let index = match pool.iter().position(Option::is_none) {
+            Some(index) => {
+                pool[index] = Some(block);
+                index
+            }
+            None => {
+                pool.push(Some(block));
+                pool.len() - 1
+            }
+        };
+
+        Ok(Allocation {
+            memory,
+            memory_type_index,
+            block: index,
+            offset: 0,
+            size,
+            kind,
+            dedicated,
+        })
+    }
+
+    // Returns the block's memory when it should be released. One empty block per memory type
+    // is kept around so allocating and freeing in a loop doesn't hit the driver every time.
+    // Allocations that didn't come from this allocator are ignored.
+    pub fn free(&mut self, allocation: Allocation<M>) -> Option<M> {
+        let pool = self.pools.get_mut(allocation.memory_type_index as usize)?;
+        let block = pool.get_mut(allocation.block)?.as_mut()?;
+        let owned = block.memory == allocation.memory
+            && block.ranges.get(&allocation.offset).is_some_and(|range| range.size == allocation.size);
+        if !owned {
+            return None;
+        }
+        block.ranges.remove(&allocation.offset);
+
+        if !block.ranges.is_empty() {
+            return None;
+        }
+
+        let dedicated = block.dedicated;
+        let empty_blocks = pool
+            .iter()
+            .flatten()
+            .filter(|block| block.ranges.is_empty() && !block.dedicated)
+            .count();
+        if dedicated || empty_blocks > 1 {
+            pool[allocation.block].take().map(|block| block.memory)
+        } else {
+            None
+        }
+    }
+
+    // Moves allocations out of the emptiest blocks into fuller ones so whole blocks can be released.
+    // Destination ranges are reserved right away; at most `max_bytes` are moved.
+    pub fn plan_defragmentation(
+        &mut self,
+        max_bytes: u64,
+        movable: impl Fn(&AllocationRange<M>) -> bool,
+    ) -> Vec<DefragmentationMove<M>> {
+        let mut moves = Vec::new();
+        let mut budget = max_bytes;
+
+        for (type_index, pool) in self.pools.iter_mut().enumerate() {
+            let mut order: Vec<usize> = (0..pool.len())
+                .filter(|&index| pool[index].as_ref().is_some_and(|block| !block.dedicated && !block.ranges.is_empty()))
+                .collect();
+            order.sort_by_key(|&index| std::cmp::Reverse(pool[index].as_ref().map_or(0, Block::used)));
+
+            let mut destinations = vec![false; pool.len()];
+            for source_position in (1..order.len()).rev() {
+                let source = order[source_position];
+                if destinations[source] {
+                    continue;
+                }
+
+                let candidates: Vec<_> = pool[source]
+                    .as_ref()
+                    .map(|block| {
+                        block
+                            .ranges
+                            .iter()
+                            .map(|(&offset, range)| {
+                                let src = AllocationRange {
+                                    memory: block.memory,
+                                    memory_type_index: type_index as u32,
+                                    offset,
+                                    size: range.size,
+                                };
+                                (src, *range)
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                for (src, range) in candidates {
+                    if src.size > budget || !movable(&src) {
+                        continue;
+                    }
+                    for &destination in &order[..source_position] {
+                        let block = match pool[destination].as_mut() {
+                            Some(block) => block,
+                            None => continue,
+                        };
+                        if let Some(offset) = block.find(range.size, range.alignment, range.kind, self.granularity) {
+                            block.ranges.insert(offset, range);
+                            destinations[destination] = true;
+                            budget -= src.size;
+                            moves.push(DefragmentationMove {
+                                src,
+                                dst: Allocation {
+                                    memory: block.memory,
+                                    memory_type_index: src.memory_type_index,
+                                    block: destination,
+                                    offset,
+                                    size: range.size,
+                                    kind: range.kind,
+                                    dedicated: false,
+                                },
+                            });
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        moves
+    }
+
+    pub fn block_count(&self, memory_type_index: u32) -> usize {
+        self.pools[memory_type_index as usize].iter().flatten().count()
+    }
+
+    // Bytes handed out and bytes reserved in blocks for one memory type.
+    pub fn usage(&self, memory_type_index: u32) -> (u64, u64) {
+        self.pools[memory_type_index as usize]
+            .iter()
+            .flatten()
+            .fold((0, 0), |(used, reserved), block| (used + block.used(), reserved + block.size))
+    }
+
+    pub fn live_allocations(&self) -> Vec<AllocationRange<M>> {
+        let mut allocations = Vec::new();
+        for (type_index, pool) in self.pools.iter().enumerate() {
+            for block in pool.iter().flatten() {
+                allocations.extend(block.ranges.iter().map(|(&offset, range)| AllocationRange {
+                    memory: block.memory,
+                    memory_type_index: type_index as u32,
+                    offset,
+                    size: range.size,
+                }));
+            }
+        }
+        allocations
+    }
+
+    // Forgets every block and returns their memory for release.
+    pub fn drain_blocks(&mut self) -> Vec<M> {
+        self.pools
+            .iter_mut()
+            .flat_map(|pool| pool.drain(..).flatten().map(|block| block.memory))
+            .collect()
+    }
+}
+
+// Small heaps (integrated GPUs, the 256 MiB BAR heap) get an eighth of the heap per block.
+pub fn preferred_block_size(heap_size: u64) -> u64 {
+    if heap_size <= LARGE_HEAP_SIZE {
+        (heap_size / 8).max(1)
+    } else {
+        DEFAULT_BLOCK_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One memory type on a 2 GiB heap, with fake memory handles numbered in creation order.
+    fn allocator(block_size: u64, granularity: u64) -> BlockAllocator<u32> {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 1,
+            memory_heap_count: 1,
+            ..Default::default()
+        };
+        memory_properties.memory_heaps[0].size = 2 * LARGE_HEAP_SIZE;
+        BlockAllocator::new(&memory_properties, granularity).with_block_size(block_size)
+    }
+
+    fn requirements(size: u64, alignment: u64) -> vk::MemoryRequirements {
+        vk::MemoryRequirements {
+            size,
+            alignment,
+            memory_type_bits: 1,
+        }
+    }
+
+    struct FakeDevice {
+        next_memory: u32,
+    }
+
+    impl FakeDevice {
+        fn new() -> Self {
+            Self { next_memory: 0 }
+        }
+
+        fn allocate(
+            &mut self,
+            allocator: &mut BlockAllocator<u32>,
+            size: u64,
+            alignment: u64,
+            kind: AllocationKind,
+        ) -> Allocation<u32> {
+            allocator
+                .allocate(0, &requirements(size, alignment), kind, |_| {
+                    self.next_memory += 1;
+                    Ok(self.next_memory)
+                })
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn aligns_offsets() {
+        let mut allocator = allocator(1024, 1);
+        let mut device = FakeDevice::new();
+        let first = device.allocate(&mut allocator, 10, 1, AllocationKind::Linear);
+        let second = device.allocate(&mut allocator, 16, 256, AllocationKind::Linear);
+        let third = device.allocate(&mut allocator, 4, 4, AllocationKind::Linear);
+        assert_eq!((first.offset, second.offset, third.offset), (0, 256, 12));
+        assert_eq!(allocator.block_count(0), 1);
+        assert_eq!(allocator.usage(0), (30, 1024));
+    }
+
+    #[test]
+    fn separates_linear_and_optimal_pages() {
+        let mut allocator = allocator(1024, 128);
+        let mut device = FakeDevice::new();
+        let buffer = device.allocate(&mut allocator, 10, 1, AllocationKind::Linear);
+        let image = device.allocate(&mut allocator, 10, 1, AllocationKind::Optimal);
+        assert_eq!(buffer.offset, 0);
+        assert_eq!(image.offset, 128, "an optimal image must not share the buffer's page");
+
+        // Same kind as its neighbour before it, and the image after it is on another page.
+        let second_buffer = device.allocate(&mut allocator, 10, 1, AllocationKind::Linear);
+        assert_eq!(second_buffer.offset, 10);
+
+        // Too big for the gap before the image, and right after it would share the image's page.
+        let third_buffer = device.allocate(&mut allocator, 120, 1, AllocationKind::Linear);
+        assert_eq!(third_buffer.offset, 256);
+    }
+
+    #[test]
+    fn granularity_is_ignored_within_one_kind() {
+        let mut allocator = allocator(1024, 128);
+        let mut device = FakeDevice::new();
+        device.allocate(&mut allocator, 10, 1, AllocationKind::Optimal);
+        let image = device.allocate(&mut allocator, 10, 1, AllocationKind::Optimal);
+        assert_eq!(image.offset, 10);
+    }
+
+    #[test]
+    fn keeps_one_empty_block() {
+        let mut allocator = allocator(1024, 1);
+        let mut device = FakeDevice::new();
+        let first = device.allocate(&mut allocator, 1024, 1, AllocationKind::Linear);
+        let second = device.allocate(&mut allocator, 1024, 1, AllocationKind::Linear);
+        assert_ne!(first.memory, second.memory);
+        assert_eq!(allocator.block_count(0), 2);
+
+        let (first_memory, second_memory) = (first.memory, second.memory);
+        assert_eq!(allocator.free(first), None, "the first empty block is kept");
+        assert_eq!(allocator.free(second), Some(second_memory));
+        assert_eq!(allocator.block_count(0), 1);
+
+        // The kept block is reused rather than a new one created.
+        let third = device.allocate(&mut allocator, 512, 1, AllocationKind::Linear);
+        assert_eq!(third.memory, first_memory);
+        assert_eq!(allocator.free(third), None);
+    }
+
+    #[test]
+    fn dedicated_blocks_are_released_with_their_allocation() {
+        let mut allocator = allocator(1024, 1);
+        let oversized = FakeDevice::new().allocate(&mut allocator, 4096, 1, AllocationKind::Linear);
+        assert!(oversized.dedicated);
+
+        let dedicated = allocator
+            .allocate_dedicated(0, &requirements(64, 1), AllocationKind::Optimal, |size| {
+                assert_eq!(size, 64);
+                Ok(7)
+            })
+            .unwrap();
+        assert!(dedicated.dedicated);
+        assert_eq!(allocator.free(dedicated), Some(7));
+        let oversized_memory = oversized.memory;
+        assert_eq!(allocator.free(oversized), Some(oversized_memory));
+        assert_eq!(allocator.block_count(0), 0);
+    }
+
+    #[test]
+    fn wants_dedicated_threshold() {
+        let mut allocator = allocator(1024, 1);
+        assert!(!allocator.wants_dedicated(0, 512, false));
+        assert!(allocator.wants_dedicated(0, 513, false));
+        assert!(allocator.wants_dedicated(0, 1, true));
+
+        allocator.set_dedicated_threshold(Some(100));
+        assert!(!allocator.wants_dedicated(0, 100, false));
+        assert!(allocator.wants_dedicated(0, 101, false));
+    }
+
+    // A full first block with its second half freed, and a quarter-full second block.
+    fn fragmented(device: &mut FakeDevice) -> (BlockAllocator<u32>, Allocation<u32>, Allocation<u32>) {
+        let mut allocator = allocator(1024, 1);
+        let kept = device.allocate(&mut allocator, 512, 1, AllocationKind::Linear);
+        let freed = device.allocate(&mut allocator, 512, 1, AllocationKind::Linear);
+        let stray = device.allocate(&mut allocator, 256, 256, AllocationKind::Linear);
+        assert_eq!(allocator.block_count(0), 2);
+        allocator.free(freed);
+        (allocator, kept, stray)
+    }
+
+    #[test]
+    fn defragmentation_moves_out_of_the_emptiest_block() {
+        let mut device = FakeDevice::new();
+        let (mut allocator, kept, stray) = fragmented(&mut device);
+
+        let moves = allocator.plan_defragmentation(u64::MAX, |_| true);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].src, stray.range());
+        assert_eq!((moves[0].dst.memory, moves[0].dst.offset, moves[0].dst.size), (kept.memory, 512, 256));
+        // The destination is reserved right away, the source stays until it is freed.
+        assert_eq!(allocator.usage(0), (1024, 2048));
+
+        allocator.free(stray);
+        assert_eq!(allocator.usage(0), (768, 2048));
+        assert_eq!(allocator.live_allocations().len(), 2);
+    }
+
+    #[test]
+    fn defragmentation_respects_budget_and_filter() {
+        let mut device = FakeDevice::new();
+        let (mut allocator, _, stray) = fragmented(&mut device);
+        assert!(allocator.plan_defragmentation(255, |_| true).is_empty());
+        assert!(allocator.plan_defragmentation(u64::MAX, |range| *range != stray.range()).is_empty());
+        assert_eq!(allocator.usage(0), (768, 2048));
+    }
+
+    #[test]
+    fn defragmentation_leaves_dedicated_blocks_alone() {
+        let mut allocator = allocator(1024, 1);
+        let mut device = FakeDevice::new();
+        device.allocate(&mut allocator, 256, 1, AllocationKind::Linear);
+        let oversized = device.allocate(&mut allocator, 2048, 1, AllocationKind::Linear);
+        assert!(oversized.dedicated);
+        assert!(allocator.plan_defragmentation(u64::MAX, |_| true).is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let mut allocator = allocator(1024, 1);
+        let fail = |_| -> Result<u32> { panic!("no block should be created") };
+        assert!(allocator.allocate(1, &requirements(16, 1), AllocationKind::Linear, fail).is_err());
+        assert!(allocator.allocate(0, &requirements(0, 1), AllocationKind::Linear, fail).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use super::align_up;
+
+// Fixed-size slots; the stride is the slot size rounded up to the alignment.
+#[derive(Debug, Clone)]
+pub struct PoolAllocator {
+    stride: u64,
+    slot_count: u32,
+    free: Vec<u32>,
+    allocated: Vec<bool>,
+}
+
+impl PoolAllocator {
+    pub fn new(slot_size: u64, alignment: u64, slot_count: u32) -> Self {
+        Self {
+            stride: align_up(slot_size.max(1), alignment),
+            slot_count,
+            // Reversed so slots are handed out from the lowest offset.
+            free: (0..slot_count).rev().collect(),
+            allocated: vec![false; slot_count as usize],
+        }
+    }
+
+    pub fn required_size(slot_size: u64, alignment: u64, slot_count: u32) -> u64 {
+        align_up(slot_size.max(1), alignment) * slot_count as u64
+    }
+
+    pub fn allocate(&mut self) -> Option<u64> {
+        let slot = self.free.pop()?;
+        self.allocated[slot as usize] = true;
+        Some(slot as u64 * self.stride)
+    }
+
+    // Returns false for offsets that don't belong to a live slot.
+    pub fn free(&mut self, offset: u64) -> bool {
+        if !offset.is_multiple_of(self.stride) {
+            return false;
+        }
+        let slot = offset / self.stride;
+        if slot >= self.slot_count as u64 || !self.allocated[slot as usize] {
+            return false;
+        }
+        self.allocated[slot as usize] = false;
+        self.free.push(slot as u32);
+        true
+    }
+
+    pub fn stride(&self) -> u64 {
+        self.stride
+    }
+
+    pub fn available(&self) -> u32 {
+        self.free.len() as u32
+    }
+
+    pub fn slot_count(&self) -> u32 {
+        self.slot_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stride_includes_alignment() {
+        let pool = PoolAllocator::new(24, 16, 4);
+        assert_eq!(pool.stride(), 32);
+        assert_eq!(PoolAllocator::required_size(24, 16, 4), 128);
+    }
+
+    #[test]
+    fn runs_out_of_slots() {
+        let mut pool = PoolAllocator::new(16, 16, 3);
+        assert_eq!(pool.allocate(), Some(0));
+        assert_eq!(pool.allocate(), Some(16));
+        assert_eq!(pool.allocate(), Some(32));
+        assert_eq!(pool.available(), 0);
+        assert_eq!(pool.allocate(), None);
+    }
+
+    #[test]
+    fn reuses_freed_slots() {
+        let mut pool = PoolAllocator::new(16, 16, 3);
+        pool.allocate();
+        let middle = pool.allocate().unwrap();
+        pool.allocate();
+
+        assert!(pool.free(middle));
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.allocate(), Some(middle));
+    }
+
+    #[test]
+    fn rejects_foreign_offsets() {
+        let mut pool = PoolAllocator::new(16, 16, 2);
+        let slot = pool.allocate().unwrap();
+        assert!(!pool.free(8), "not on a slot boundary");
+        assert!(!pool.free(16), "never handed out");
+        assert!(!pool.free(64), "past the last slot");
+        assert!(pool.free(slot));
+        assert!(!pool.free(slot), "already freed");
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+
+use ash::vk;
+
+use super::align_up;
+
+// Allocations are handed out in order and released in order once the fence of the submission
+// that used them has signaled. `F` is the fence type so the arithmetic can be driven without a device.
+#[derive(Debug, Clone)]
+pub struct RingAllocator<F = vk::Fence> {
+    capacity: u64,
+    head: u64,
+    tail: u64,
+    // Number of times `head` and `tail` wrapped around; they differ while the live range wraps.
+    head_lap: u64,
+    tail_lap: u64,
+    in_flight: VecDeque<(F, u64, u64)>,
+}
+
+impl<F: Copy> RingAllocator<F> {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            head: 0,
+            tail: 0,
+            head_lap: 0,
+            tail_lap: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    // Allocations never straddle the end; the bytes skipped when wrapping are released with the tail.
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let offset = align_up(self.head, alignment);
+        let end = offset.checked_add(size)?;
+
+        if self.wrapped() {
+            if end > self.tail {
+                return None;
+            }
+        } else if end > self.capacity {
+            if size > self.tail {
+                return None;
+            }
+            self.head_lap = self.head_lap.wrapping_add(1);
+            self.head = size;
+            return Some(0);
+        }
+
+        self.head = end;
+        Some(offset)
+    }
+
+    // Everything allocated since the previous call is released once `fence` signals.
+    pub fn submit(&mut self, fence: F) {
+        self.in_flight.push_back((fence, self.head, self.head_lap));
+    }
+
+    pub fn reclaim(&mut self, mut is_signaled: impl FnMut(F) -> bool) {
+        while let Some(&(fence, end, lap)) = self.in_flight.front() {
+            if !is_signaled(fence) {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.tail = end;
+            self.tail_lap = lap;
+        }
+
+        if self.in_flight.is_empty() && !self.wrapped() && self.head == self.tail {
+            self.head = 0;
+            self.tail = 0;
+        }
+    }
+
+    fn wrapped(&self) -> bool {
+        self.head_lap != self.tail_lap
+    }
+
+    pub fn used(&self) -> u64 {
+        if self.wrapped() {
+            self.capacity - self.tail + self.head
+        } else {
+            self.head - self.tail
+        }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_alignment() {
+        let mut ring = RingAllocator::<u32>::new(256);
+        assert_eq!(ring.allocate(10, 1), Some(0));
+        assert_eq!(ring.allocate(10, 64), Some(64));
+        assert_eq!(ring.used(), 74);
+    }
+
+    #[test]
+    fn wraps_while_the_tail_is_in_use() {
+        let mut ring = RingAllocator::<u32>::new(100);
+        assert_eq!(ring.allocate(40, 1), Some(0));
+        ring.submit(1);
+        assert_eq!(ring.allocate(40, 1), Some(40));
+        ring.submit(2);
+
+        ring.reclaim(|fence| fence == 1);
+        assert_eq!(ring.used(), 40);
+
+        // Doesn't fit in the 20 bytes at the end, so it starts over at 0 in front of the tail.
+        assert_eq!(ring.allocate(30, 1), Some(0));
+        assert_eq!(ring.used(), 90);
+    }
+
+    #[test]
+    fn refuses_when_full() {
+        let mut ring = RingAllocator::<u32>::new(100);
+        assert_eq!(ring.allocate(60, 1), Some(0));
+        ring.submit(1);
+        assert_eq!(ring.allocate(50, 1), None, "nothing reclaimed yet");
+
+        assert_eq!(ring.allocate(30, 1), Some(60));
+        ring.submit(2);
+        ring.reclaim(|fence| fence == 1);
+
+        // Wrapped: only the 60 bytes in front of the tail are free.
+        assert_eq!(ring.allocate(50, 1), Some(0));
+        assert_eq!(ring.allocate(20, 1), None);
+        assert_eq!(ring.allocate(10, 1), Some(50));
+        assert_eq!(ring.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn reclaims_in_submission_order() {
+        let mut ring = RingAllocator::<u32>::new(100);
+        ring.allocate(10, 1);
+        ring.submit(1);
+        ring.allocate(10, 1);
+        ring.submit(2);
+
+        // A later fence signalling first doesn't release anything ahead of the earlier one.
+        ring.reclaim(|fence| fence == 2);
+        assert_eq!((ring.in_flight(), ring.used()), (2, 20));
+
+        ring.reclaim(|fence| fence == 1);
+        assert_eq!((ring.in_flight(), ring.used()), (1, 10));
+    }
+
+    #[test]
+    fn resets_to_zero_when_drained() {
+        let mut ring = RingAllocator::<u32>::new(100);
+        ring.allocate(70, 1);
+        ring.submit(1);
+        ring.reclaim(|_| true);
+        assert_eq!((ring.in_flight(), ring.used()), (0, 0));
+
+        // Starts from the beginning again instead of wrapping around at 70.
+        assert_eq!(ring.allocate(80, 1), Some(0));
+    }
+}
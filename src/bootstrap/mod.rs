@@ -0,0 +1,480 @@
+use std::ffi::{CStr, CString};
+use std::sync::Arc;
+
+use ash::{extensions::ext, extensions::khr, vk};
+
+use crate::builder::VulkanQueueBuilder;
+use crate::capabilities::{DeviceCapabilities, DeviceFeatures, DeviceRequirements};
+use crate::error::{Result, VkQueueError};
+
+const VALIDATION_LAYER: &CStr = c"VK_LAYER_KHRONOS_validation";
+
+pub struct InstanceBootstrap {
+    app_name: CString,
+    api_version: u32,
+    layers: Vec<CString>,
+    extensions: Vec<CString>,
+    validation: bool,
+}
+
+impl Default for InstanceBootstrap {
+    fn default() -> Self {
+        Self {
+            app_name: CString::from(c"vulkan-asyncqueue"),
+            api_version: vk::API_VERSION_1_2,
+            layers: Vec::new(),
+            extensions: Vec::new(),
+            validation: false,
+        }
+    }
+}
+
+impl InstanceBootstrap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn app_name(mut self, app_name: &CStr) -> Self {
+        self.app_name = app_name.to_owned();
+        self
+    }
+
+    pub fn api_version(mut self, api_version: u32) -> Self {
+        self.api_version = api_version;
+        self
+    }
+
+    pub fn layer(mut self, layer: &CStr) -> Self {
+        self.layers.push(layer.to_owned());
+        self
+    }
+
+    // Surface extensions are window-system specific, so they have to be passed in here.
+    pub fn extension(mut self, extension: &CStr) -> Self {
+        self.extensions.push(extension.to_owned());
+        self
+    }
+
+    // Enables the Khronos validation layer together with VK_EXT_debug_utils.
+    pub fn enable_validation(mut self, enable: bool) -> Self {
+        self.validation = enable;
+        self
+    }
+
+    pub fn build(mut self) -> Result<BootstrapInstance> {
+        let entry = unsafe { ash::Entry::load()? };
+
+        if self.validation {
+            self.layers.push(VALIDATION_LAYER.to_owned());
+            self.extensions.push(ext::DebugUtils::name().to_owned());
+        }
+        dedup_names(&mut self.layers);
+        dedup_names(&mut self.extensions);
+
+        let available_layers = entry.enumerate_instance_layer_properties()?;
+        for layer in &self.layers {
+            let found = available_layers
+                .iter()
+                .any(|properties| unsafe { CStr::from_ptr(properties.layer_name.as_ptr()) } == layer.as_c_str());
+            if !found {
+                return Err(VkQueueError::MissingLayer(layer.to_string_lossy().into_owned()));
+            }
+        }
+
+        let available_extensions = entry.enumerate_instance_extension_properties(None)?;
+        for extension in &self.extensions {
+            let found = available_extensions
+                .iter()
+                .any(|properties| unsafe { CStr::from_ptr(properties.extension_name.as_ptr()) } == extension.as_c_str());
+            if !found {
+                return Err(VkQueueError::MissingExtension(extension.to_string_lossy().into_owned()));
+            }
+        }
+
+        let layer_names: Vec<_> = self.layers.iter().map(|layer| layer.as_ptr()).collect();
+        let extension_names: Vec<_> = self.extensions.iter().map(|extension| extension.as_ptr()).collect();
+
+        let app_info = vk::ApplicationInfo::builder()
+            .application_name(&self.app_name)
+            .engine_name(&self.app_name)
+            .api_version(self.api_version);
+
+        let create_info = vk::InstanceCreateInfo::builder()
+            .application_info(&app_info)
+            .enabled_layer_names(&layer_names)
+            .enabled_extension_names(&extension_names);
+
+        let instance = unsafe { entry.create_instance(&create_info, None)? };
+
+        Ok(BootstrapInstance {
+            entry,
+            instance,
+            api_version: self.api_version,
+            extensions: self.extensions.clone(),
+            validation: self.validation,
+        })
+    }
+}
+
+pub struct BootstrapInstance {
+    pub entry: ash::Entry,
+    pub instance: ash::Instance,
+    api_version: u32,
+    extensions: Vec<CString>,
+    validation: bool,
+}
+
+impl BootstrapInstance {
+    pub fn surface_loader(&self) -> khr::Surface {
+        khr::Surface::new(&self.entry, &self.instance)
+    }
+
+    pub fn select_device(&self) -> DeviceSelector<'_> {
+        DeviceSelector {
+            instance: self,
+            surface: None,
+            required_extensions: Vec::new(),
+            required_features: vk::PhysicalDeviceFeatures::default(),
+            requirements: DeviceRequirements::default(),
+            queue_flags: vk::QueueFlags::GRAPHICS,
+        }
+    }
+
+    fn has_extension(&self, name: &CStr) -> bool {
+        self.extensions.iter().any(|extension| extension.as_c_str() == name)
+    }
+
+    /// # Safety
+    /// Everything created from this instance, including the device, has to be destroyed first.
+    pub unsafe fn destroy(&self) {
+        self.instance.destroy_instance(None);
+    }
+}
+
+pub struct DeviceSelector<'a> {
+    instance: &'a BootstrapInstance,
+    surface: Option<(vk::SurfaceKHR, &'a khr::Surface)>,
+    required_extensions: Vec<CString>,
+    required_features: vk::PhysicalDeviceFeatures,
+    requirements: DeviceRequirements,
+    queue_flags: vk::QueueFlags,
+}
+
+impl<'a> DeviceSelector<'a> {
+    // Devices must be able to present to `surface`; VK_KHR_swapchain is enabled automatically.
+    pub fn surface(mut self, surface: vk::SurfaceKHR, surface_loader: &'a khr::Surface) -> Self {
+        self.surface = Some((surface, surface_loader));
+        self
+    }
+
+    pub fn require_extension(mut self, extension: &CStr) -> Self {
+        self.required_extensions.push(extension.to_owned());
+        self
+    }
+
+    pub fn require_features(mut self, features: vk::PhysicalDeviceFeatures) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    // Gates on capabilities such as timeline semaphores or ray tracing. The required features,
+    // and the extensions behind them, are enabled on the created device.
+    pub fn requirements(mut self, requirements: DeviceRequirements) -> Self {
+        self.requirements = requirements;
+        self
+    }
+
+    pub fn queue_flags(mut self, queue_flags: vk::QueueFlags) -> Self {
+        self.queue_flags = queue_flags;
+        self
+    }
+
+    fn extensions(&self) -> Vec<CString> {
+        let mut extensions = self.required_extensions.clone();
+        if self.surface.is_some() {
+            extensions.push(khr::Swapchain::name().to_owned());
+        }
+        let features = &self.requirements.features;
+        if features.acceleration_structure {
+            extensions.push(khr::AccelerationStructure::name().to_owned());
+            extensions.push(khr::DeferredHostOperations::name().to_owned());
+        }
+        if features.ray_tracing_pipeline {
+            extensions.push(khr::RayTracingPipeline::name().to_owned());
+        }
+        if features.ray_query {
+            extensions.push(vk::KhrRayQueryFn::name().to_owned());
+        }
+        if features.memory_budget {
+            extensions.push(vk::ExtMemoryBudgetFn::name().to_owned());
+        }
+        dedup_names(&mut extensions);
+        extensions
+    }
+
+    pub fn select(&self) -> Result<SelectedDevice> {
+        let instance = &self.instance.instance;
+        let extensions = self.extensions();
+
+        let mut requirements = self.requirements.clone();
+        requirements
+            .extensions
+            .extend(extensions.iter().map(|extension| extension.to_string_lossy().into_owned()));
+        requirements.queue_flags |= self.queue_flags.as_raw();
+
+        let mut best: Option<SelectedDevice> = None;
+        for physical_device in unsafe { instance.enumerate_physical_devices()? } {
+            let capabilities = DeviceCapabilities::from_physical_device(instance, self.instance.api_version, physical_device)?;
+            let features = unsafe { instance.get_physical_device_features(physical_device) };
+
+            if !capabilities.meets(&requirements) || !supports_features(&features, &self.required_features) {
+                continue;
+            }
+
+            let mut queue_family_index = None;
+            for (index, family) in capabilities.queue_families.iter().enumerate() {
+                if !vk::QueueFlags::from_raw(family.queue_flags).contains(self.queue_flags) {
+                    continue;
+                }
+                if let Some((surface, surface_loader)) = self.surface {
+                    let can_present = unsafe {
+                        surface_loader.get_physical_device_surface_support(physical_device, index as u32, surface)?
+                    };
+                    if !can_present {
+                        continue;
+                    }
+                }
+                queue_family_index = Some(index as u32);
+                break;
+            }
+            let queue_family_index = match queue_family_index {
+                Some(index) => index,
+                None => continue,
+            };
+            let transfer_queue_family_index = dedicated_transfer_family(&capabilities);
+
+            let score = capabilities.score();
+            if best.as_ref().is_none_or(|best| score > best.score) {
+                best = Some(SelectedDevice {
+                    physical_device,
+                    queue_family_index,
+                    transfer_queue_family_index,
+                    score,
+                    api_version: capabilities.api_version.min(self.instance.api_version),
+                    capabilities,
+                    extensions: extensions.clone(),
+                    features: self.required_features,
+                    required_features: self.requirements.features,
+                });
+            }
+        }
+
+        best.ok_or(VkQueueError::NoSuitableDevice)
+    }
+
+    pub fn create_device(&self) -> Result<BootstrapDevice> {
+        self.select()?.create_device(self.instance)
+    }
+}
+
+// A family that can only transfer, which on discrete GPUs is usually a DMA engine that runs
+// alongside graphics work.
+fn dedicated_transfer_family(capabilities: &DeviceCapabilities) -> Option<u32> {
+    capabilities
+        .queue_families
+        .iter()
+        .position(|family| {
+            let flags = vk::QueueFlags::from_raw(family.queue_flags);
+            flags.contains(vk::QueueFlags::TRANSFER)
+                && !flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        })
+        .map(|index| index as u32)
+}
+
+// The core features among `required`, on top of the ones asked for with `require_features`.
+fn core_features(required: &DeviceFeatures, mut features: vk::PhysicalDeviceFeatures) -> vk::PhysicalDeviceFeatures {
+    let enable = |feature: &mut vk::Bool32, required: bool| {
+        if required {
+            *feature = vk::TRUE;
+        }
+    };
+    enable(&mut features.geometry_shader, required.geometry_shader);
+    enable(&mut features.tessellation_shader, required.tessellation_shader);
+    enable(&mut features.sampler_anisotropy, required.sampler_anisotropy);
+    enable(&mut features.multi_draw_indirect, required.multi_draw_indirect);
+    enable(&mut features.fill_mode_non_solid, required.fill_mode_non_solid);
+    enable(&mut features.wide_lines, required.wide_lines);
+    enable(&mut features.shader_int64, required.shader_int64);
+    enable(&mut features.shader_float64, required.shader_float64);
+    enable(&mut features.texture_compression_bc, required.texture_compression_bc);
+    enable(&mut features.texture_compression_astc_ldr, required.texture_compression_astc_ldr);
+    features
+}
+
+fn dedup_names(names: &mut Vec<CString>) {
+    let mut seen = std::collections::HashSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+}
+
+// VkPhysicalDeviceFeatures is nothing but a sequence of VkBool32s.
+pub fn supports_features(available: &vk::PhysicalDeviceFeatures, required: &vk::PhysicalDeviceFeatures) -> bool {
+    let count = std::mem::size_of::<vk::PhysicalDeviceFeatures>() / std::mem::size_of::<vk::Bool32>();
+    let available = unsafe { std::slice::from_raw_parts(available as *const _ as *const vk::Bool32, count) };
+    let required = unsafe { std::slice::from_raw_parts(required as *const _ as *const vk::Bool32, count) };
+    available
+        .iter()
+        .zip(required)
+        .all(|(&available, &required)| required == vk::FALSE || available == vk::TRUE)
+}
+
+pub struct SelectedDevice {
+    pub physical_device: vk::PhysicalDevice,
+    pub queue_family_index: u32,
+    // A transfer-only family, if the device has one.
+    pub transfer_queue_family_index: Option<u32>,
+    pub score: u32,
+    pub capabilities: DeviceCapabilities,
+    // The lower of the instance's and the device's API version.
+    api_version: u32,
+    extensions: Vec<CString>,
+    features: vk::PhysicalDeviceFeatures,
+    required_features: DeviceFeatures,
+}
+
+impl SelectedDevice {
+    pub fn name(&self) -> &str {
+        &self.capabilities.name
+    }
+
+    pub fn create_device(&self, instance: &BootstrapInstance) -> Result<BootstrapDevice> {
+        let queue_priorities = vec![1.0];
+        let mut queue_create_infos = vec![vk::DeviceQueueCreateInfo::builder()
+            .queue_family_index(self.queue_family_index)
+            .queue_priorities(&queue_priorities)
+            .build()];
+        if let Some(family_index) = self.transfer_queue_family_index {
+            queue_create_infos.push(
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(family_index)
+                    .queue_priorities(&queue_priorities)
+                    .build(),
+            );
+        }
+
+        let required = &self.required_features;
+        let features = core_features(required, self.features);
+        let mut vulkan12 = vk::PhysicalDeviceVulkan12Features::builder()
+            .timeline_semaphore(required.timeline_semaphore)
+            .descriptor_indexing(required.descriptor_indexing)
+            .buffer_device_address(required.buffer_device_address)
+            .draw_indirect_count(required.draw_indirect_count);
+        let mut vulkan13 = vk::PhysicalDeviceVulkan13Features::builder()
+            .synchronization2(required.synchronization2)
+            .dynamic_rendering(required.dynamic_rendering);
+        let mut acceleration_structure =
+            vk::PhysicalDeviceAccelerationStructureFeaturesKHR::builder().acceleration_structure(true);
+        let mut ray_tracing_pipeline =
+            vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::builder().ray_tracing_pipeline(true);
+        let mut ray_query = vk::PhysicalDeviceRayQueryFeaturesKHR::builder().ray_query(true);
+
+        let extension_names: Vec<_> = self.extensions.iter().map(|extension| extension.as_ptr()).collect();
+        let mut create_info = vk::DeviceCreateInfo::builder()
+            .queue_create_infos(&queue_create_infos)
+            .enabled_extension_names(&extension_names)
+            .enabled_features(&features);
+        // The device only reported these features if both it and the instance are new enough,
+        // so requiring one implies the version.
+        if vk::api_version_minor(self.api_version) >= 2 {
+            create_info = create_info.push_next(&mut vulkan12);
+        }
+        if vk::api_version_minor(self.api_version) >= 3 {
+            create_info = create_info.push_next(&mut vulkan13);
+        }
+        if required.acceleration_structure {
+            create_info = create_info.push_next(&mut acceleration_structure);
+        }
+        if required.ray_tracing_pipeline {
+            create_info = create_info.push_next(&mut ray_tracing_pipeline);
+        }
+        if required.ray_query {
+            create_info = create_info.push_next(&mut ray_query);
+        }
+
+        let device = unsafe {
+            instance
+                .instance
+                .create_device(self.physical_device, &create_info, None)?
+        };
+
+        Ok(BootstrapDevice {
+            device: Arc::new(device),
+            physical_device: self.physical_device,
+            queue_family_index: self.queue_family_index,
+            transfer_queue_family_index: self.transfer_queue_family_index,
+            api_version: self.api_version,
+            extensions: self.extensions.clone(),
+            features,
+            queue_priorities,
+            validation: instance.validation,
+        })
+    }
+}
+
+pub struct BootstrapDevice {
+    pub device: Arc<ash::Device>,
+    pub physical_device: vk::PhysicalDevice,
+    pub queue_family_index: u32,
+    pub transfer_queue_family_index: Option<u32>,
+    api_version: u32,
+    extensions: Vec<CString>,
+    // The core features the device was created with.
+    features: vk::PhysicalDeviceFeatures,
+    queue_priorities: Vec<f32>,
+    validation: bool,
+}
+
+impl BootstrapDevice {
+    // The surface and window size still have to be set on the returned builder.
+    pub fn builder<'a>(&'a self, instance: &'a BootstrapInstance) -> VulkanQueueBuilder<'a> {
+        let queue_create_info = |family_index| {
+            vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(family_index)
+                .queue_priorities(&self.queue_priorities)
+                .build()
+        };
+        let mut queue_create_infos = vec![queue_create_info(self.queue_family_index)];
+        queue_create_infos.extend(self.transfer_queue_family_index.map(queue_create_info));
+
+        let builder = VulkanQueueBuilder::new()
+            .entry(&instance.entry)
+            .instance(&instance.instance)
+            .device(self.device.clone())
+            .physical_device(self.physical_device)
+            .queue(self.queue_family_index, 0)
+            .queue_create_infos(queue_create_infos)
+            .api_version(self.api_version)
+            .enable_dedicated_allocation(
+                self.has_extension(vk::KhrDedicatedAllocationFn::name())
+                    && self.has_extension(vk::KhrGetMemoryRequirements2Fn::name()),
+            )
+            .enable_image_format_list(self.has_extension(vk::KhrImageFormatListFn::name()))
+            .enable_memory_budget(self.has_extension(vk::ExtMemoryBudgetFn::name()))
+            .enable_physical_device_properties2(instance.has_extension(vk::KhrGetPhysicalDeviceProperties2Fn::name()))
+            .enable_sampler_anisotropy(self.features.sampler_anisotropy == vk::TRUE)
+            .enable_debug_utils(self.validation);
+        match self.transfer_queue_family_index {
+            Some(family_index) => builder.transfer_queue(family_index, 0),
+            None => builder,
+        }
+    }
+
+    fn has_extension(&self, name: &CStr) -> bool {
+        self.extensions.iter().any(|extension| extension.as_c_str() == name)
+    }
+
+    /// # Safety
+    /// Every VulkanQueue built from this device has to be dropped first.
+    pub unsafe fn destroy(&self) {
+        self.device.destroy_device(None);
+    }
+}
@@ -0,0 +1,451 @@
+use std::sync::{Arc, Mutex};
+
+use ash::{extensions::khr, vk};
+
+use crate::{
+    error::{Result, VkQueueError},
+    pipeline::PipelineManager,
+    raytracing::RTPipelineManager,
+    recorder::SubmissionRecorder,
+    taskmanager::TaskManager,
+    utils::DebugUtils,
+    vk_cmdbuffermgr::CommandBufferManager,
+    vk_deskmgr::DescriptorManager,
+    vk_framemgr::FrameManager,
+    vk_memorymgr::MemoryManager,
+    vk_offscreen::OffscreenTarget,
+    vk_ownershipmgr::QueueOwnershipManager,
+    vk_pipelinemgr::ComputePipelineManager,
+    vk_renderpassmgr::RenderPassManager,
+    vk_resmgr::ResourceManager,
+    vk_samplermgr::SamplerManager,
+    vk_shadermgr::ShaderManager,
+    vk_subpassmgr::SubpassManager,
+    vk_swapchain::Swapchain,
+    vk_syncmgr::SynchronizationManager,
+    vk_textmgr::TextureManager,
+    vk_uploadmgr::UploadManager,
+    VulkanQueue,
+};
+
+pub struct VulkanQueueBuilder<'a> {
+    entry: Option<&'a ash::Entry>,
+    instance: Option<&'a ash::Instance>,
+    device: Option<Arc<ash::Device>>,
+    physical_device: Option<vk::PhysicalDevice>,
+    queue_create_infos: Vec<vk::DeviceQueueCreateInfo>,
+    surface: Option<vk::SurfaceKHR>,
+    surface_loader: Option<&'a khr::Surface>,
+    window_size: Option<(u32, u32)>,
+    queue_family_index: Option<u32>,
+    queue_index: u32,
+    transfer_queue: Option<(u32, u32)>,
+    staging_size: vk::DeviceSize,
+    task_threads: usize,
+    present_modes: Vec<vk::PresentModeKHR>,
+    surface_formats: Vec<vk::SurfaceFormatKHR>,
+    debug_utils: bool,
+    ray_tracing: bool,
+    memory_budget: bool,
+    physical_device_properties2: bool,
+    api_version: u32,
+    dedicated_allocation: bool,
+    image_format_list: bool,
+    sampler_anisotropy: bool,
+    headless: bool,
+    offscreen_format: vk::Format,
+}
+
+impl<'a> Default for VulkanQueueBuilder<'a> {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            instance: None,
+            device: None,
+            physical_device: None,
+            queue_create_infos: Vec::new(),
+            surface: None,
+            surface_loader: None,
+            window_size: None,
+            queue_family_index: None,
+            queue_index: 0,
+            transfer_queue: None,
+            staging_size: 32 * 1024 * 1024,
+            task_threads: 4,
+            present_modes: vec![vk::PresentModeKHR::MAILBOX],
+            surface_formats: vec![vk::SurfaceFormatKHR {
+                format: vk::Format::B8G8R8A8_SRGB,
+                color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            }],
+            debug_utils: false,
+            ray_tracing: false,
+            memory_budget: false,
+            physical_device_properties2: false,
+            api_version: vk::API_VERSION_1_0,
+            dedicated_allocation: false,
+            image_format_list: false,
+            sampler_anisotropy: false,
+            headless: false,
+            offscreen_format: vk::Format::R8G8B8A8_UNORM,
+        }
+    }
+}
+
+impl<'a> VulkanQueueBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entry(mut self, entry: &'a ash::Entry) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    pub fn instance(mut self, instance: &'a ash::Instance) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    pub fn device(mut self, device: Arc<ash::Device>) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn physical_device(mut self, physical_device: vk::PhysicalDevice) -> Self {
+        self.physical_device = Some(physical_device);
+        self
+    }
+
+    // Only needed by the ray tracing manager.
+    pub fn queue_create_infos(mut self, queue_create_infos: Vec<vk::DeviceQueueCreateInfo>) -> Self {
+        self.queue_create_infos = queue_create_infos;
+        self
+    }
+
+    pub fn surface(mut self, surface: vk::SurfaceKHR, surface_loader: &'a khr::Surface) -> Self {
+        self.surface = Some(surface);
+        self.surface_loader = Some(surface_loader);
+        self
+    }
+
+    pub fn window_size(mut self, width: u32, height: u32) -> Self {
+        self.window_size = Some((width, height));
+        self
+    }
+
+    pub fn queue(mut self, queue_family_index: u32, queue_index: u32) -> Self {
+        self.queue_family_index = Some(queue_family_index);
+        self.queue_index = queue_index;
+        self
+    }
+
+    // A queue of a transfer-capable family for uploads, e.g. a dedicated DMA family. Without it
+    // uploads go through the graphics queue.
+    pub fn transfer_queue(mut self, queue_family_index: u32, queue_index: u32) -> Self {
+        self.transfer_queue = Some((queue_family_index, queue_index));
+        self
+    }
+
+    // Size of the upload manager's staging ring, 32 MiB by default.
+    pub fn staging_size(mut self, staging_size: vk::DeviceSize) -> Self {
+        self.staging_size = staging_size;
+        self
+    }
+
+    pub fn task_threads(mut self, task_threads: usize) -> Self {
+        self.task_threads = task_threads;
+        self
+    }
+
+    // Tried in order; FIFO is used when none of them is supported.
+    pub fn present_modes(mut self, present_modes: &[vk::PresentModeKHR]) -> Self {
+        self.present_modes = present_modes.to_vec();
+        self
+    }
+
+    // Tried in order; the first format reported by the surface is used when none of them is supported.
+    pub fn surface_formats(mut self, surface_formats: &[vk::SurfaceFormatKHR]) -> Self {
+        self.surface_formats = surface_formats.to_vec();
+        self
+    }
+
+    // Requires VK_EXT_debug_utils to be enabled on the instance.
+    pub fn enable_debug_utils(mut self, enable: bool) -> Self {
+        self.debug_utils = enable;
+        self
+    }
+
+    pub fn enable_ray_tracing(mut self, enable: bool) -> Self {
+        self.ray_tracing = enable;
+        self
+    }
+
+    // Requires VK_EXT_memory_budget to be enabled on the device. Before Vulkan 1.1 the budget is
+    // only read with `.enable_physical_device_properties2(true)`.
+    pub fn enable_memory_budget(mut self, enable: bool) -> Self {
+        self.memory_budget = enable;
+        self
+    }
+
+    // Requires VK_KHR_get_physical_device_properties2 to be enabled on the instance.
+    pub fn enable_physical_device_properties2(mut self, enable: bool) -> Self {
+        self.physical_device_properties2 = enable;
+        self
+    }
+
+    // The version the device is used with, the lower of the instance's and the device's. Core
+    // features of newer versions are only used when it is high enough. 1.0 by default.
+    pub fn api_version(mut self, api_version: u32) -> Self {
+        self.api_version = api_version;
+        self
+    }
+
+    // Lets the driver ask for dedicated memory before Vulkan 1.1. Requires VK_KHR_dedicated_allocation
+    // and VK_KHR_get_memory_requirements2 to be enabled on the device.
+    pub fn enable_dedicated_allocation(mut self, enable: bool) -> Self {
+        self.dedicated_allocation = enable;
+        self
+    }
+
+    // Passes the view formats of an `ImageDesc` on before Vulkan 1.2. Requires VK_KHR_image_format_list
+    // to be enabled on the device.
+    pub fn enable_image_format_list(mut self, enable: bool) -> Self {
+        self.image_format_list = enable;
+        self
+    }
+
+    // Requires the samplerAnisotropy feature to be enabled on the device. Without it, anisotropy
+    // requested by a `SamplerDesc` is ignored.
+    pub fn enable_sampler_anisotropy(mut self, enable: bool) -> Self {
+        self.sampler_anisotropy = enable;
+        self
+    }
+
+    // Skips the swapchain and frame manager, so no surface is needed. If a window size is given,
+    // an offscreen render target of that size is created instead.
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    pub fn offscreen_format(mut self, format: vk::Format) -> Self {
+        self.offscreen_format = format;
+        self
+    }
+
+    pub fn build(self) -> Result<VulkanQueue> {
+        let entry = self.entry.ok_or(VkQueueError::MissingParameter("entry"))?;
+        let instance = self.instance.ok_or(VkQueueError::MissingParameter("instance"))?;
+        let device = self.device.ok_or(VkQueueError::MissingParameter("device"))?;
+        let physical_device = self
+            .physical_device
+            .ok_or(VkQueueError::MissingParameter("physical_device"))?;
+        let presentation = if self.headless {
+            None
+        } else {
+            let surface = self.surface.ok_or(VkQueueError::MissingParameter("surface"))?;
+            let surface_loader = self
+                .surface_loader
+                .ok_or(VkQueueError::MissingParameter("surface_loader"))?;
+            let window_size = self
+                .window_size
+                .ok_or(VkQueueError::MissingParameter("window_size"))?;
+            Some((surface, surface_loader, window_size))
+        };
+        let queue_family_index = self
+            .queue_family_index
+            .ok_or(VkQueueError::MissingParameter("queue"))?;
+
+        if self.task_threads == 0 {
+            return Err(VkQueueError::InvalidParameter("task_threads must be at least 1"));
+        }
+        if matches!(self.window_size, Some((0, _)) | Some((_, 0))) {
+            return Err(VkQueueError::InvalidParameter("window_size must be non-zero"));
+        }
+
+        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+
+        // Everything after this point is either torn down by its own Drop or by `command_pool`'s
+        // guard when a later step fails.
+        let command_pool = CommandPoolGuard {
+            device: device.clone(),
+            command_pool: unsafe { device.create_command_pool(&command_pool_create_info, None)? },
+        };
+
+        let graphics_queue = Arc::new(Mutex::new(unsafe {
+            device.get_device_queue(queue_family_index, self.queue_index)
+        }));
+        let (transfer_queue, transfer_queue_family_index) = match self.transfer_queue {
+            Some((family_index, index)) => (
+                Arc::new(Mutex::new(unsafe { device.get_device_queue(family_index, index) })),
+                family_index,
+            ),
+            None => (graphics_queue.clone(), queue_family_index),
+        };
+
+        let swapchain = match presentation {
+            Some((surface, surface_loader, (window_width, window_height))) => Some(Arc::new(Swapchain::new(
+                instance,
+                &device,
+                physical_device,
+                surface,
+                window_width,
+                window_height,
+                surface_loader,
+                &self.surface_formats,
+                &self.present_modes,
+            )?)),
+            None => None,
+        };
+
+        let memory_properties = unsafe {
+            instance.get_physical_device_memory_properties(physical_device)
+        };
+        let limits = unsafe { instance.get_physical_device_properties(physical_device).limits };
+        let mut memory_manager = MemoryManager::new((*device).clone(), memory_properties, &limits)
+            .with_dedicated_allocation(instance, self.api_version, self.dedicated_allocation);
+        if self.memory_budget {
+            memory_manager = memory_manager.with_budget_query(
+                entry,
+                instance,
+                physical_device,
+                self.api_version,
+                self.physical_device_properties2,
+            );
+        }
+        let resource_manager = ResourceManager::new((*device).clone(), memory_manager.clone())
+            .with_format_query(instance.clone(), physical_device)
+            .with_image_format_list(self.api_version, self.image_format_list);
+
+        let sync_manager = SynchronizationManager::new((*device).clone());
+
+        let descriptor_manager = DescriptorManager::new((*device).clone());
+
+        let pipeline_manager = PipelineManager::new((*device).clone());
+
+        let subpass_manager = SubpassManager::new();
+
+        let render_pass_manager = RenderPassManager::new((*device).clone());
+
+        let command_buffer_manager = CommandBufferManager::new((*device).clone(), command_pool.command_pool);
+
+        let frame_manager = swapchain.as_ref().map(|swapchain| {
+            FrameManager::new(
+                (*device).clone(),
+                khr::Swapchain::new(instance, &device),
+                swapchain.handle,
+                swapchain.present_mode,
+                swapchain.surface_format,
+                swapchain.extent,
+                swapchain.clone(),
+                resource_manager.shared_deletion_queue(),
+            )
+        });
+
+        let ownership_manager = QueueOwnershipManager::new((*device).clone());
+        let shader_manager = ShaderManager::new((*device).clone());
+        let texture_manager = TextureManager::new(
+            (*device).clone(),
+            memory_manager.clone(),
+            graphics_queue.clone(),
+            queue_family_index,
+            transfer_queue.clone(),
+            transfer_queue_family_index,
+        )?
+        .with_format_query(instance.clone(), physical_device);
+        let upload_manager = UploadManager::new(
+            (*device).clone(),
+            memory_manager.clone(),
+            transfer_queue,
+            transfer_queue_family_index,
+            queue_family_index,
+            self.staging_size,
+        )?;
+        let sampler_manager = SamplerManager::new(
+            (*device).clone(),
+            self.sampler_anisotropy.then_some(limits.max_sampler_anisotropy),
+        );
+
+        let debug_utils = if self.debug_utils {
+            Some(DebugUtils::new(entry, instance)?)
+        } else {
+            None
+        };
+
+        // Last, as it has no Drop of its own.
+        let offscreen_target = match (&swapchain, self.window_size) {
+            (None, Some((width, height))) => Some(OffscreenTarget::new(
+                &device,
+                &resource_manager,
+                vk::Extent2D { width, height },
+                self.offscreen_format,
+            )?),
+            _ => None,
+        };
+
+        let compute_pipeline_manager = ComputePipelineManager::new((*device).clone());
+
+        let task_manager = TaskManager::new(self.task_threads);
+
+        let raytracing = if self.ray_tracing {
+            Some(RTPipelineManager::new(
+                entry,
+                instance,
+                device.clone(),
+                physical_device,
+                self.queue_create_infos,
+            ))
+        } else {
+            None
+        };
+
+        Ok(VulkanQueue {
+            device,
+            command_pool: Arc::new(Mutex::new(command_pool.into_inner())),
+            queue_family_index,
+            graphics_queue,
+            offscreen_target,
+            resource_manager,
+            sync_manager,
+            descriptor_manager,
+            pipeline_manager,
+            subpass_manager,
+            render_pass_manager,
+            command_buffer_manager,
+            frame_manager,
+            memory_manager,
+            ownership_manager,
+            shader_manager,
+            texture_manager,
+            sampler_manager,
+            upload_manager,
+            debug_utils,
+            compute_pipeline_manager,
+            task_manager,
+            raytracing,
+            recorder: Arc::new(SubmissionRecorder::new()),
+        })
+    }
+}
+
+// Destroys the command pool unless `build` gets as far as handing it to the queue.
+struct CommandPoolGuard {
+    device: Arc<ash::Device>,
+    command_pool: vk::CommandPool,
+}
+
+impl CommandPoolGuard {
+    fn into_inner(self) -> vk::CommandPool {
+        let command_pool = self.command_pool;
+        std::mem::forget(self);
+        command_pool
+    }
+}
+
+impl Drop for CommandPoolGuard {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_command_pool(self.command_pool, None) };
+    }
+}
@@ -0,0 +1,522 @@
+use std::ffi::CStr;
+
+use ash::extensions::khr;
+use ash::vk;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceType {
+    Discrete,
+    Integrated,
+    Virtual,
+    Cpu,
+    #[default]
+    Other,
+}
+
+impl From<vk::PhysicalDeviceType> for DeviceType {
+    fn from(device_type: vk::PhysicalDeviceType) -> Self {
+        match device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => DeviceType::Discrete,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => DeviceType::Integrated,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => DeviceType::Virtual,
+            vk::PhysicalDeviceType::CPU => DeviceType::Cpu,
+            _ => DeviceType::Other,
+        }
+    }
+}
+
+macro_rules! device_features {
+    ($($name:ident),* $(,)?) => {
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(default)]
+        pub struct DeviceFeatures {
+            $(pub $name: bool,)*
+        }
+
+        impl DeviceFeatures {
+            // Names of the features set in `required` that are not available here.
+            pub fn missing(&self, required: &DeviceFeatures) -> Vec<&'static str> {
+                let mut missing = Vec::new();
+                $(
+                    if required.$name && !self.$name {
+                        missing.push(stringify!($name));
+                    }
+                )*
+                missing
+            }
+        }
+    };
+}
+
+device_features!(
+    geometry_shader,
+    tessellation_shader,
+    sampler_anisotropy,
+    multi_draw_indirect,
+    fill_mode_non_solid,
+    wide_lines,
+    shader_int64,
+    shader_float64,
+    texture_compression_bc,
+    texture_compression_astc_ldr,
+    timeline_semaphore,
+    descriptor_indexing,
+    buffer_device_address,
+    draw_indirect_count,
+    synchronization2,
+    dynamic_rendering,
+    acceleration_structure,
+    ray_tracing_pipeline,
+    ray_query,
+    memory_budget,
+);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceLimits {
+    pub max_image_dimension_1d: u32,
+    pub max_image_dimension_2d: u32,
+    pub max_image_dimension_3d: u32,
+    pub max_image_dimension_cube: u32,
+    pub max_image_array_layers: u32,
+    pub max_memory_allocation_count: u32,
+    pub max_sampler_allocation_count: u32,
+    pub max_bound_descriptor_sets: u32,
+    pub max_push_constants_size: u32,
+    pub max_sampler_anisotropy: f32,
+    pub max_compute_work_group_count: [u32; 3],
+    pub max_compute_work_group_size: [u32; 3],
+    pub max_compute_work_group_invocations: u32,
+    pub buffer_image_granularity: u64,
+    pub non_coherent_atom_size: u64,
+    pub min_uniform_buffer_offset_alignment: u64,
+    pub min_storage_buffer_offset_alignment: u64,
+    pub min_texel_buffer_offset_alignment: u64,
+    pub optimal_buffer_copy_offset_alignment: u64,
+    pub timestamp_period: f32,
+}
+
+impl From<&vk::PhysicalDeviceLimits> for DeviceLimits {
+    fn from(limits: &vk::PhysicalDeviceLimits) -> Self {
+        Self {
+            max_image_dimension_1d: limits.max_image_dimension1_d,
+            max_image_dimension_2d: limits.max_image_dimension2_d,
+            max_image_dimension_3d: limits.max_image_dimension3_d,
+            max_image_dimension_cube: limits.max_image_dimension_cube,
+            max_image_array_layers: limits.max_image_array_layers,
+            max_memory_allocation_count: limits.max_memory_allocation_count,
+            max_sampler_allocation_count: limits.max_sampler_allocation_count,
+            max_bound_descriptor_sets: limits.max_bound_descriptor_sets,
+            max_push_constants_size: limits.max_push_constants_size,
+            max_sampler_anisotropy: limits.max_sampler_anisotropy,
+            max_compute_work_group_count: limits.max_compute_work_group_count,
+            max_compute_work_group_size: limits.max_compute_work_group_size,
+            max_compute_work_group_invocations: limits.max_compute_work_group_invocations,
+            buffer_image_granularity: limits.buffer_image_granularity,
+            non_coherent_atom_size: limits.non_coherent_atom_size,
+            min_uniform_buffer_offset_alignment: limits.min_uniform_buffer_offset_alignment,
+            min_storage_buffer_offset_alignment: limits.min_storage_buffer_offset_alignment,
+            min_texel_buffer_offset_alignment: limits.min_texel_buffer_offset_alignment,
+            optimal_buffer_copy_offset_alignment: limits.optimal_buffer_copy_offset_alignment,
+            timestamp_period: limits.timestamp_period,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryHeapInfo {
+    pub size: u64,
+    pub device_local: bool,
+}
+
+// Flags are stored as raw `VkMemoryPropertyFlags`/`VkQueueFlags` bits to keep the JSON stable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryTypeInfo {
+    pub heap_index: u32,
+    pub property_flags: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueFamilyInfo {
+    pub queue_flags: u32,
+    pub queue_count: u32,
+    pub timestamp_valid_bits: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceCapabilities {
+    pub name: String,
+    pub device_type: DeviceType,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub api_version: u32,
+    pub driver_version: u32,
+    pub limits: DeviceLimits,
+    pub features: DeviceFeatures,
+    pub memory_heaps: Vec<MemoryHeapInfo>,
+    pub memory_types: Vec<MemoryTypeInfo>,
+    pub queue_families: Vec<QueueFamilyInfo>,
+    pub extensions: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceRequirements {
+    pub min_api_version: u32,
+    pub extensions: Vec<String>,
+    pub features: DeviceFeatures,
+    pub queue_flags: u32,
+    pub min_device_local_memory: u64,
+}
+
+impl DeviceCapabilities {
+    // `instance_api_version` is the version the instance was created with. Features of newer core
+    // versions are only reported if both the instance and the device support them.
+    pub fn from_physical_device(
+        instance: &ash::Instance,
+        instance_api_version: u32,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<Self> {
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let extensions: Vec<String> = unsafe { instance.enumerate_device_extension_properties(physical_device)? }
+            .iter()
+            .map(|properties| {
+                unsafe { CStr::from_ptr(properties.extension_name.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        let has_extension = |name: &CStr| extensions.iter().any(|extension| extension.as_bytes() == name.to_bytes());
+
+        let api_version = properties.api_version;
+        let features = unsafe { instance.get_physical_device_features(physical_device) };
+        let mut device_features = DeviceFeatures {
+            geometry_shader: features.geometry_shader == vk::TRUE,
+            tessellation_shader: features.tessellation_shader == vk::TRUE,
+            sampler_anisotropy: features.sampler_anisotropy == vk::TRUE,
+            multi_draw_indirect: features.multi_draw_indirect == vk::TRUE,
+            fill_mode_non_solid: features.fill_mode_non_solid == vk::TRUE,
+            wide_lines: features.wide_lines == vk::TRUE,
+            shader_int64: features.shader_int64 == vk::TRUE,
+            shader_float64: features.shader_float64 == vk::TRUE,
+            texture_compression_bc: features.texture_compression_bc == vk::TRUE,
+            texture_compression_astc_ldr: features.texture_compression_astc_ldr == vk::TRUE,
+            memory_budget: has_extension(vk::ExtMemoryBudgetFn::name()),
+            ..Default::default()
+        };
+
+        // The feature chain needs vkGetPhysicalDeviceFeatures2, i.e. Vulkan 1.1.
+        let usable_version = api_version.min(instance_api_version);
+        if usable_version >= vk::API_VERSION_1_1 {
+            let has_vulkan12 = usable_version >= vk::API_VERSION_1_2;
+            let has_vulkan13 = usable_version >= vk::API_VERSION_1_3;
+            let has_acceleration_structure = has_extension(khr::AccelerationStructure::name());
+            let has_ray_tracing_pipeline = has_extension(khr::RayTracingPipeline::name());
+            let has_ray_query = has_extension(vk::KhrRayQueryFn::name());
+
+            let mut vulkan12 = vk::PhysicalDeviceVulkan12Features::default();
+            let mut vulkan13 = vk::PhysicalDeviceVulkan13Features::default();
+            let mut acceleration_structure = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+            let mut ray_tracing_pipeline = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+            let mut ray_query = vk::PhysicalDeviceRayQueryFeaturesKHR::default();
+
+            let mut features2 = vk::PhysicalDeviceFeatures2::builder();
+            if has_vulkan12 {
+                features2 = features2.push_next(&mut vulkan12);
+            }
+            if has_vulkan13 {
+                features2 = features2.push_next(&mut vulkan13);
+            }
+            if has_acceleration_structure {
+                features2 = features2.push_next(&mut acceleration_structure);
+            }
+            if has_ray_tracing_pipeline {
+                features2 = features2.push_next(&mut ray_tracing_pipeline);
+            }
+            if has_ray_query {
+                features2 = features2.push_next(&mut ray_query);
+            }
+            let mut features2 = features2.build();
+            unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+
+            device_features.timeline_semaphore = vulkan12.timeline_semaphore == vk::TRUE;
+            device_features.descriptor_indexing = vulkan12.descriptor_indexing == vk::TRUE;
+            device_features.buffer_device_address = vulkan12.buffer_device_address == vk::TRUE;
+            device_features.draw_indirect_count = vulkan12.draw_indirect_count == vk::TRUE;
+            device_features.synchronization2 = vulkan13.synchronization2 == vk::TRUE;
+            device_features.dynamic_rendering = vulkan13.dynamic_rendering == vk::TRUE;
+            device_features.acceleration_structure = acceleration_structure.acceleration_structure == vk::TRUE;
+            device_features.ray_tracing_pipeline = ray_tracing_pipeline.ray_tracing_pipeline == vk::TRUE;
+            device_features.ray_query = ray_query.ray_query == vk::TRUE;
+        }
+
+        let memory_heaps = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+            .iter()
+            .map(|heap| MemoryHeapInfo {
+                size: heap.size,
+                device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+            })
+            .collect();
+        let memory_types = memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+            .iter()
+            .map(|memory_type| MemoryTypeInfo {
+                heap_index: memory_type.heap_index,
+                property_flags: memory_type.property_flags.as_raw(),
+            })
+            .collect();
+        let queue_families = queue_families
+            .iter()
+            .map(|family| QueueFamilyInfo {
+                queue_flags: family.queue_flags.as_raw(),
+                queue_count: family.queue_count,
+                timestamp_valid_bits: family.timestamp_valid_bits,
+            })
+            .collect();
+
+        Ok(Self {
+            name: unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+            device_type: properties.device_type.into(),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            api_version,
+            driver_version: properties.driver_version,
+            limits: DeviceLimits::from(&properties.limits),
+            features: device_features,
+            memory_heaps,
+            memory_types,
+            queue_families,
+            extensions,
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|extension| extension == name)
+    }
+
+    pub fn supports_ray_tracing(&self) -> bool {
+        self.features.acceleration_structure && self.features.ray_tracing_pipeline
+    }
+
+    pub fn device_local_memory(&self) -> u64 {
+        self.memory_heaps
+            .iter()
+            .filter(|heap| heap.device_local)
+            .map(|heap| heap.size)
+            .sum()
+    }
+
+    pub fn queue_family(&self, queue_flags: vk::QueueFlags) -> Option<u32> {
+        self.queue_families
+            .iter()
+            .position(|family| vk::QueueFlags::from_raw(family.queue_flags).contains(queue_flags))
+            .map(|index| index as u32)
+    }
+
+    // Human-readable list of every requirement this device fails; empty when it qualifies.
+    pub fn missing_requirements(&self, requirements: &DeviceRequirements) -> Vec<String> {
+        let mut missing = Vec::new();
+
+        if self.api_version < requirements.min_api_version {
+            missing.push(format!(
+                "api version {}.{} < {}.{}",
+                vk::api_version_major(self.api_version),
+                vk::api_version_minor(self.api_version),
+                vk::api_version_major(requirements.min_api_version),
+                vk::api_version_minor(requirements.min_api_version)
+            ));
+        }
+        for extension in &requirements.extensions {
+            if !self.supports_extension(extension) {
+                missing.push(format!("extension {}", extension));
+            }
+        }
+        for feature in self.features.missing(&requirements.features) {
+            missing.push(format!("feature {}", feature));
+        }
+        if requirements.queue_flags != 0
+            && self
+                .queue_family(vk::QueueFlags::from_raw(requirements.queue_flags))
+                .is_none()
+        {
+            missing.push(format!(
+                "queue family with {:?}",
+                vk::QueueFlags::from_raw(requirements.queue_flags)
+            ));
+        }
+        if self.device_local_memory() < requirements.min_device_local_memory {
+            missing.push(format!(
+                "device local memory {} < {}",
+                self.device_local_memory(),
+                requirements.min_device_local_memory
+            ));
+        }
+
+        missing
+    }
+
+    pub fn meets(&self, requirements: &DeviceRequirements) -> bool {
+        self.missing_requirements(requirements).is_empty()
+    }
+
+    // Discrete GPUs win over integrated ones, which win over virtual ones, which win over
+    // everything else; ties are broken by the maximum 2D image size. The size is clamped below
+    // the gap between two device types, so it can never outrank the type.
+    pub fn score(&self) -> u32 {
+        let type_rank = match self.device_type {
+            DeviceType::Discrete => 3,
+            DeviceType::Integrated => 2,
+            DeviceType::Virtual => 1,
+            _ => 0,
+        };
+        type_rank * 100_000 + self.limits.max_image_dimension_2d.min(99_999)
+    }
+}
+
+// Picks the highest scoring profile that meets `requirements`, as the bootstrap does for real devices.
+pub fn select_best<'a>(
+    candidates: &'a [DeviceCapabilities],
+    requirements: &DeviceRequirements,
+) -> Option<(usize, &'a DeviceCapabilities)> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, capabilities)| capabilities.meets(requirements))
+        .max_by_key(|(index, capabilities)| (capabilities.score(), std::cmp::Reverse(*index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(json: &str) -> DeviceCapabilities {
+        DeviceCapabilities::from_json(json).unwrap()
+    }
+
+    fn discrete() -> DeviceCapabilities {
+        profile(include_str!("profiles/discrete.json"))
+    }
+
+    fn integrated() -> DeviceCapabilities {
+        profile(include_str!("profiles/integrated.json"))
+    }
+
+    fn virtual_gpu() -> DeviceCapabilities {
+        profile(include_str!("profiles/virtual.json"))
+    }
+
+    fn legacy() -> DeviceCapabilities {
+        profile(include_str!("profiles/legacy.json"))
+    }
+
+    fn ray_tracing() -> DeviceRequirements {
+        DeviceRequirements {
+            min_api_version: vk::API_VERSION_1_2,
+            extensions: vec!["VK_KHR_ray_tracing_pipeline".to_owned()],
+            features: DeviceFeatures {
+                timeline_semaphore: true,
+                acceleration_structure: true,
+                ray_tracing_pipeline: true,
+                ..Default::default()
+            },
+            queue_flags: vk::QueueFlags::GRAPHICS.as_raw(),
+            min_device_local_memory: 4 << 30,
+        }
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        for capabilities in [discrete(), integrated(), virtual_gpu(), legacy()] {
+            assert_eq!(DeviceCapabilities::from_json(&capabilities.to_json().unwrap()).unwrap(), capabilities);
+        }
+    }
+
+    #[test]
+    fn meets() {
+        assert!(discrete().meets(&ray_tracing()));
+        assert!(!integrated().meets(&ray_tracing()));
+        assert!(legacy().meets(&DeviceRequirements::default()));
+
+        let timeline = DeviceRequirements {
+            features: DeviceFeatures {
+                timeline_semaphore: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(integrated().meets(&timeline));
+        assert!(!legacy().meets(&timeline));
+    }
+
+    #[test]
+    fn missing_requirements() {
+        assert!(discrete().missing_requirements(&ray_tracing()).is_empty());
+        assert_eq!(
+            integrated().missing_requirements(&ray_tracing()),
+            [
+                "extension VK_KHR_ray_tracing_pipeline",
+                "feature acceleration_structure",
+                "feature ray_tracing_pipeline",
+                "device local memory 2147483648 < 4294967296",
+            ]
+        );
+
+        let requirements = DeviceRequirements {
+            queue_flags: vk::QueueFlags::SPARSE_BINDING.as_raw(),
+            ..ray_tracing()
+        };
+        let missing = legacy().missing_requirements(&requirements);
+        assert_eq!(missing[0], "api version 1.0 < 1.2");
+        assert!(missing.iter().any(|entry| entry.starts_with("queue family with")));
+        assert!(missing.contains(&"feature timeline_semaphore".to_owned()));
+    }
+
+    #[test]
+    fn select_best() {
+        let candidates = [legacy(), virtual_gpu(), integrated(), discrete()];
+        // Both discrete, the larger maximum image size wins.
+        let (index, best) = super::select_best(&candidates, &DeviceRequirements::default()).unwrap();
+        assert_eq!((index, best.name.as_str()), (3, "Discrete 1.3"));
+
+        let timeline = DeviceRequirements {
+            features: DeviceFeatures {
+                timeline_semaphore: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (index, _) = super::select_best(&candidates[..3], &timeline).unwrap();
+        assert_eq!(index, 2);
+
+        assert!(super::select_best(&candidates[..3], &ray_tracing()).is_none());
+
+        let (index, _) = super::select_best(&[discrete(), discrete()], &DeviceRequirements::default()).unwrap();
+        assert_eq!(index, 0, "ties go to the first candidate");
+    }
+
+    #[test]
+    fn device_type_outranks_image_size() {
+        // The virtual GPU supports larger images but must still lose to the integrated one.
+        assert!(virtual_gpu().limits.max_image_dimension_2d > integrated().limits.max_image_dimension_2d);
+        assert!(integrated().score() > virtual_gpu().score());
+
+        let candidates = [virtual_gpu(), integrated()];
+        let (index, _) = super::select_best(&candidates, &DeviceRequirements::default()).unwrap();
+        assert_eq!(index, 1);
+    }
+}
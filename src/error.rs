@@ -0,0 +1,129 @@
+use std::fmt;
+
+use ash::vk;
+
+#[derive(Debug)]
+pub enum VkQueueError {
+    Vulkan(vk::Result),
+    Io(std::io::Error),
+    Image(image::ImageError),
+    SpirV(std::io::Error),
+    Json(serde_json::Error),
+    Task(tokio::task::JoinError),
+    NoSuitableMemoryType {
+        memory_type_bits: u32,
+        properties: vk::MemoryPropertyFlags,
+    },
+    NoSurfaceFormat,
+    UnknownResourceOwner,
+    MissingParameter(&'static str),
+    InvalidParameter(&'static str),
+    Loading(ash::LoadingError),
+    MissingLayer(String),
+    MissingExtension(String),
+    NoSuitableDevice,
+    BudgetExceeded {
+        heap_index: u32,
+        requested: u64,
+        budget: u64,
+    },
+    UnsupportedFormat {
+        format: vk::Format,
+        features: vk::FormatFeatureFlags,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, VkQueueError>;
+
+impl fmt::Display for VkQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VkQueueError::Vulkan(result) => write!(f, "Vulkan call failed: {}", result),
+            VkQueueError::Io(error) => write!(f, "I/O error: {}", error),
+            VkQueueError::Image(error) => write!(f, "Failed to decode image: {}", error),
+            VkQueueError::SpirV(error) => write!(f, "Failed to parse SPIR-V: {}", error),
+            VkQueueError::Json(error) => write!(f, "JSON error: {}", error),
+            VkQueueError::Task(error) => write!(f, "Submission task failed: {}", error),
+            VkQueueError::NoSuitableMemoryType {
+                memory_type_bits,
+                properties,
+            } => write!(
+                f,
+                "No memory type in {:#b} supports {:?}",
+                memory_type_bits, properties
+            ),
+            VkQueueError::NoSurfaceFormat => write!(f, "Surface reports no supported formats"),
+            VkQueueError::UnknownResourceOwner => {
+                write!(f, "Resource has no registered queue family owner")
+            }
+            VkQueueError::MissingParameter(name) => write!(f, "Missing required parameter `{}`", name),
+            VkQueueError::InvalidParameter(message) => write!(f, "Invalid parameter: {}", message),
+            VkQueueError::Loading(error) => write!(f, "Failed to load the Vulkan library: {}", error),
+            VkQueueError::MissingLayer(name) => write!(f, "Layer {} is not available", name),
+            VkQueueError::MissingExtension(name) => write!(f, "Extension {} is not available", name),
+            VkQueueError::NoSuitableDevice => write!(f, "No physical device meets the requirements"),
+            VkQueueError::BudgetExceeded {
+                heap_index,
+                requested,
+                budget,
+            } => write!(
+                f,
+                "Allocating {} bytes would exceed the {} byte budget of heap {}",
+                requested, budget, heap_index
+            ),
+            VkQueueError::UnsupportedFormat { format, features } => {
+                write!(f, "Format {:?} doesn't support {:?}", format, features)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VkQueueError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VkQueueError::Vulkan(result) => Some(result),
+            VkQueueError::Io(error) | VkQueueError::SpirV(error) => Some(error),
+            VkQueueError::Image(error) => Some(error),
+            VkQueueError::Json(error) => Some(error),
+            VkQueueError::Task(error) => Some(error),
+            VkQueueError::Loading(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for VkQueueError {
+    fn from(result: vk::Result) -> Self {
+        VkQueueError::Vulkan(result)
+    }
+}
+
+impl From<std::io::Error> for VkQueueError {
+    fn from(error: std::io::Error) -> Self {
+        VkQueueError::Io(error)
+    }
+}
+
+impl From<image::ImageError> for VkQueueError {
+    fn from(error: image::ImageError) -> Self {
+        VkQueueError::Image(error)
+    }
+}
+
+impl From<serde_json::Error> for VkQueueError {
+    fn from(error: serde_json::Error) -> Self {
+        VkQueueError::Json(error)
+    }
+}
+
+impl From<tokio::task::JoinError> for VkQueueError {
+    fn from(error: tokio::task::JoinError) -> Self {
+        VkQueueError::Task(error)
+    }
+}
+
+impl From<ash::LoadingError> for VkQueueError {
+    fn from(error: ash::LoadingError) -> Self {
+        VkQueueError::Loading(error)
+    }
+}
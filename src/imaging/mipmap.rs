@@ -0,0 +1,234 @@
+use std::f32::consts::PI;
+
+use crate::error::{Result, VkQueueError};
+
+// CPU downsampling for formats the device can't blit with linear filtering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MipFilter {
+    // Average of the covered texels. Cheap, slightly blurry.
+    #[default]
+    Box,
+    // Kaiser-windowed sinc. Sharper, at the cost of mild ringing.
+    Kaiser,
+}
+
+const KAISER_RADIUS: f32 = 3.0;
+const KAISER_ALPHA: f32 = 4.0;
+
+// Levels 1.. of the chain for tightly packed RGBA8 `level0`, down to 1x1. Colour channels are
+// filtered in linear space when `srgb` is set; alpha always is linear.
+pub fn generate_mip_chain(level0: &[u8], width: u32, height: u32, filter: MipFilter, srgb: bool) -> Result<Vec<Vec<u8>>> {
+    if width == 0 || height == 0 {
+        return Err(VkQueueError::InvalidParameter("mip chain needs a non-empty level 0"));
+    }
+    let expected = (width as usize).checked_mul(height as usize).and_then(|texels| texels.checked_mul(4));
+    if expected != Some(level0.len()) {
+        return Err(VkQueueError::InvalidParameter("level 0 must be tightly packed RGBA8"));
+    }
+
+    let mut levels = Vec::new();
+    let mut current = to_linear(level0, srgb);
+    let (mut current_width, mut current_height) = (width, height);
+    while current_width > 1 || current_height > 1 {
+        let next_width = (current_width / 2).max(1);
+        let next_height = (current_height / 2).max(1);
+        current = downsample(&current, (current_width, current_height), (next_width, next_height), filter);
+        levels.push(to_rgba8(&current, srgb));
+        current_width = next_width;
+        current_height = next_height;
+    }
+    Ok(levels)
+}
+
+// One step down of linear RGBA data, horizontally then vertically.
+fn downsample(src: &[[f32; 4]], src_size: (u32, u32), dst_size: (u32, u32), filter: MipFilter) -> Vec<[f32; 4]> {
+    let (src_width, src_height) = (src_size.0 as usize, src_size.1 as usize);
+    let (dst_width, dst_height) = (dst_size.0 as usize, dst_size.1 as usize);
+
+    let columns = weights(src_width, dst_width, filter);
+    let mut horizontal = vec![[0.0; 4]; dst_width * src_height];
+    for y in 0..src_height {
+        for (x, taps) in columns.iter().enumerate() {
+            horizontal[y * dst_width + x] = accumulate(taps.iter().map(|&(i, w)| (src[y * src_width + i], w)));
+        }
+    }
+
+    let rows = weights(src_height, dst_height, filter);
+    let mut dst = vec![[0.0; 4]; dst_width * dst_height];
+    for (y, taps) in rows.iter().enumerate() {
+        for x in 0..dst_width {
+            dst[y * dst_width + x] = accumulate(taps.iter().map(|&(i, w)| (horizontal[i * dst_width + x], w)));
+        }
+    }
+    dst
+}
+
+fn accumulate(taps: impl Iterator<Item = ([f32; 4], f32)>) -> [f32; 4] {
+    let mut sum = [0.0; 4];
+    for (texel, weight) in taps {
+        for (channel, value) in sum.iter_mut().zip(texel) {
+            *channel += value * weight;
+        }
+    }
+    sum
+}
+
+// Normalized source taps for every destination texel along one axis. Taps past the edge are
+// clamped to it.
+fn weights(src_len: usize, dst_len: usize, filter: MipFilter) -> Vec<Vec<(usize, f32)>> {
+    let scale = src_len as f32 / dst_len as f32;
+    (0..dst_len)
+        .map(|x| {
+            let start = x as f32 * scale;
+            let end = start + scale;
+            let mut taps: Vec<(usize, f32)> = match filter {
+                MipFilter::Box => (start.floor() as usize..end.ceil() as usize)
+                    .map(|i| {
+                        let overlap = end.min(i as f32 + 1.0) - start.max(i as f32);
+                        (i.min(src_len - 1), overlap)
+                    })
+                    .collect(),
+                MipFilter::Kaiser => {
+                    let center = (start + end) / 2.0;
+                    let support = KAISER_RADIUS * scale;
+                    let first = (center - support).floor() as i64;
+                    let last = (center + support).ceil() as i64;
+                    (first..=last)
+                        .map(|i| {
+                            let distance = (i as f32 + 0.5 - center) / scale;
+                            (i.clamp(0, src_len as i64 - 1) as usize, kaiser(distance))
+                        })
+                        .collect()
+                }
+            };
+            taps.retain(|&(_, weight)| weight != 0.0);
+            let total: f32 = taps.iter().map(|&(_, weight)| weight).sum();
+            for (_, weight) in &mut taps {
+                *weight /= total;
+            }
+            taps
+        })
+        .collect()
+}
+
+fn kaiser(distance: f32) -> f32 {
+    let ratio = distance / KAISER_RADIUS;
+    if ratio.abs() >= 1.0 {
+        return 0.0;
+    }
+    let window = bessel_i0(KAISER_ALPHA * (1.0 - ratio * ratio).sqrt()) / bessel_i0(KAISER_ALPHA);
+    sinc(distance) * window
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Zeroth-order modified Bessel function of the first kind, by its power series.
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..32 {
+        term *= (half / k as f32) * (half / k as f32);
+        sum += term;
+        if term < sum * 1e-7 {
+            break;
+        }
+    }
+    sum
+}
+
+fn to_linear(rgba: &[u8], srgb: bool) -> Vec<[f32; 4]> {
+    rgba.chunks_exact(4)
+        .map(|texel| {
+            let colour = |value: u8| {
+                let value = value as f32 / 255.0;
+                if srgb {
+                    srgb_to_linear(value)
+                } else {
+                    value
+                }
+            };
+            [colour(texel[0]), colour(texel[1]), colour(texel[2]), texel[3] as f32 / 255.0]
+        })
+        .collect()
+}
+
+fn to_rgba8(linear: &[[f32; 4]], srgb: bool) -> Vec<u8> {
+    let quantize = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    linear
+        .iter()
+        .flat_map(|texel| {
+            let colour = |value: f32| quantize(if srgb { linear_to_srgb(value) } else { value });
+            [colour(texel[0]), colour(texel[1]), colour(texel[2]), quantize(texel[3])]
+        })
+        .collect()
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(levels: &[Vec<u8>]) -> Vec<usize> {
+        levels.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn level_sizes() {
+        let levels = generate_mip_chain(&[0; 5 * 3 * 4], 5, 3, MipFilter::Box, false).unwrap();
+        assert_eq!(sizes(&levels), [2 * 4, 4]);
+
+        let levels = generate_mip_chain(&[0; 7 * 4], 1, 7, MipFilter::Kaiser, false).unwrap();
+        assert_eq!(sizes(&levels), [3 * 4, 4]);
+
+        assert!(generate_mip_chain(&[0; 4], 1, 1, MipFilter::Box, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(generate_mip_chain(&[0; 15], 2, 2, MipFilter::Box, false).is_err());
+        assert!(generate_mip_chain(&[], 0, 4, MipFilter::Box, false).is_err());
+    }
+
+    #[test]
+    fn constant_colour_is_preserved() {
+        let texel = [200, 100, 30, 128];
+        let level0 = texel.repeat(9 * 6);
+        for filter in [MipFilter::Box, MipFilter::Kaiser] {
+            for srgb in [false, true] {
+                for level in generate_mip_chain(&level0, 9, 6, filter, srgb).unwrap() {
+                    assert!(level.chunks_exact(4).all(|value| value == texel), "{:?}, srgb {}", filter, srgb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        let values: Vec<u8> = (0..=255).collect();
+        assert_eq!(to_rgba8(&to_linear(&values, true), true), values);
+        assert_eq!(to_rgba8(&to_linear(&values, false), false), values);
+    }
+}
@@ -0,0 +1,480 @@
+use ash::vk;
+
+use crate::error::{Result, VkQueueError};
+
+mod mipmap;
+
+pub use mipmap::{generate_mip_chain, MipFilter};
+
+// Everything `ResourceManager::create_image_with` needs. Start from one of the constructors and
+// chain the rest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageDesc {
+    pub image_type: vk::ImageType,
+    pub format: vk::Format,
+    pub extent: vk::Extent3D,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    pub samples: vk::SampleCountFlags,
+    pub tiling: vk::ImageTiling,
+    pub usage: vk::ImageUsageFlags,
+    pub flags: vk::ImageCreateFlags,
+    // Other formats views of the image may use. Implies MUTABLE_FORMAT.
+    pub view_formats: Vec<vk::Format>,
+    pub queue_family_indices: Vec<u32>,
+}
+
+impl ImageDesc {
+    pub fn new_1d(width: u32, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self::new(vk::ImageType::TYPE_1D, vk::Extent3D { width, height: 1, depth: 1 }, format, usage)
+    }
+
+    pub fn new_2d(width: u32, height: u32, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self::new(vk::ImageType::TYPE_2D, vk::Extent3D { width, height, depth: 1 }, format, usage)
+    }
+
+    pub fn new_3d(width: u32, height: u32, depth: u32, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self::new(vk::ImageType::TYPE_3D, vk::Extent3D { width, height, depth }, format, usage)
+    }
+
+    // Six square layers that can be viewed as a cube.
+    pub fn new_cube(size: u32, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self::new_2d(size, size, format, usage).array_layers(6).cube_compatible()
+    }
+
+    fn new(image_type: vk::ImageType, extent: vk::Extent3D, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self {
+            image_type,
+            format,
+            extent,
+            mip_levels: 1,
+            array_layers: 1,
+            samples: vk::SampleCountFlags::TYPE_1,
+            tiling: vk::ImageTiling::OPTIMAL,
+            usage,
+            flags: vk::ImageCreateFlags::empty(),
+            view_formats: Vec::new(),
+            queue_family_indices: Vec::new(),
+        }
+    }
+
+    pub fn mip_levels(mut self, mip_levels: u32) -> Self {
+        self.mip_levels = mip_levels;
+        self
+    }
+
+    // Mips all the way down to 1x1x1.
+    pub fn full_mip_chain(mut self) -> Self {
+        self.mip_levels = mip_level_count(self.extent);
+        self
+    }
+
+    pub fn array_layers(mut self, array_layers: u32) -> Self {
+        self.array_layers = array_layers;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn tiling(mut self, tiling: vk::ImageTiling) -> Self {
+        self.tiling = tiling;
+        self
+    }
+
+    pub fn flags(mut self, flags: vk::ImageCreateFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    pub fn cube_compatible(self) -> Self {
+        self.flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+    }
+
+    // Passed on in VkImageFormatListCreateInfo when the device supports it (Vulkan 1.2 or
+    // VK_KHR_image_format_list).
+    pub fn view_formats(mut self, view_formats: &[vk::Format]) -> Self {
+        self.view_formats = view_formats.to_vec();
+        self
+    }
+
+    pub fn queue_family_indices(mut self, queue_family_indices: &[u32]) -> Self {
+        self.queue_family_indices = queue_family_indices.to_vec();
+        self
+    }
+
+    pub fn is_cube(&self) -> bool {
+        self.flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+    }
+
+    // Catches the combinations the spec forbids before the driver (or the validation layer) does.
+    pub fn validate(&self) -> Result<()> {
+        let extent = self.extent;
+        if extent.width == 0 || extent.height == 0 || extent.depth == 0 {
+            return Err(VkQueueError::InvalidParameter("image extent must not be zero"));
+        }
+        if self.array_layers == 0 {
+            return Err(VkQueueError::InvalidParameter("image needs at least one array layer"));
+        }
+        if self.mip_levels == 0 || self.mip_levels > mip_level_count(extent) {
+            return Err(VkQueueError::InvalidParameter("mip level count out of range for the extent"));
+        }
+        match self.image_type {
+            vk::ImageType::TYPE_1D if extent.height != 1 || extent.depth != 1 => {
+                return Err(VkQueueError::InvalidParameter("1D images have a height and depth of 1"));
+            }
+            vk::ImageType::TYPE_2D if extent.depth != 1 => {
+                return Err(VkQueueError::InvalidParameter("2D images have a depth of 1"));
+            }
+            vk::ImageType::TYPE_3D if self.array_layers != 1 => {
+                return Err(VkQueueError::InvalidParameter("3D images can't have array layers"));
+            }
+            _ => {}
+        }
+        if self.is_cube()
+            && (self.image_type != vk::ImageType::TYPE_2D
+                || extent.width != extent.height
+                || !self.array_layers.is_multiple_of(6))
+        {
+            return Err(VkQueueError::InvalidParameter(
+                "cube-compatible images are square 2D images with a multiple of 6 layers",
+            ));
+        }
+        if self.samples != vk::SampleCountFlags::TYPE_1
+            && (self.image_type != vk::ImageType::TYPE_2D
+                || self.mip_levels != 1
+                || self.tiling != vk::ImageTiling::OPTIMAL
+                || self.is_cube())
+        {
+            return Err(VkQueueError::InvalidParameter(
+                "multisampled images are optimally tiled 2D images with a single mip level",
+            ));
+        }
+        Ok(())
+    }
+
+    // MUTABLE_FORMAT is added when views may use other formats.
+    pub fn create_flags(&self) -> vk::ImageCreateFlags {
+        let other_formats = self.view_formats.iter().any(|&format| format != self.format);
+        if other_formats {
+            self.flags | vk::ImageCreateFlags::MUTABLE_FORMAT
+        } else {
+            self.flags
+        }
+    }
+
+    // The view covering the whole image, as an array view when there are several layers.
+    pub fn default_view_type(&self) -> vk::ImageViewType {
+        match self.image_type {
+            vk::ImageType::TYPE_1D if self.array_layers > 1 => vk::ImageViewType::TYPE_1D_ARRAY,
+            vk::ImageType::TYPE_1D => vk::ImageViewType::TYPE_1D,
+            vk::ImageType::TYPE_3D => vk::ImageViewType::TYPE_3D,
+            _ if self.is_cube() && self.array_layers > 6 => vk::ImageViewType::CUBE_ARRAY,
+            _ if self.is_cube() => vk::ImageViewType::CUBE,
+            _ if self.array_layers > 1 => vk::ImageViewType::TYPE_2D_ARRAY,
+            _ => vk::ImageViewType::TYPE_2D,
+        }
+    }
+}
+
+// Which part of the image a view sees. `None` fields fall back to the whole image, its format and
+// the aspect implied by that format (depth only for depth-stencil formats).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImageViewDesc {
+    pub view_type: Option<vk::ImageViewType>,
+    pub format: Option<vk::Format>,
+    pub aspect_mask: Option<vk::ImageAspectFlags>,
+    pub base_mip_level: u32,
+    pub level_count: Option<u32>,
+    pub base_array_layer: u32,
+    pub layer_count: Option<u32>,
+    pub components: vk::ComponentMapping,
+}
+
+impl ImageViewDesc {
+    pub fn view_type(mut self, view_type: vk::ImageViewType) -> Self {
+        self.view_type = Some(view_type);
+        self
+    }
+
+    pub fn format(mut self, format: vk::Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn aspect_mask(mut self, aspect_mask: vk::ImageAspectFlags) -> Self {
+        self.aspect_mask = Some(aspect_mask);
+        self
+    }
+
+    pub fn mip_levels(mut self, base_mip_level: u32, level_count: u32) -> Self {
+        self.base_mip_level = base_mip_level;
+        self.level_count = Some(level_count);
+        self
+    }
+
+    pub fn array_layers(mut self, base_array_layer: u32, layer_count: u32) -> Self {
+        self.base_array_layer = base_array_layer;
+        self.layer_count = Some(layer_count);
+        self
+    }
+
+    pub fn components(mut self, components: vk::ComponentMapping) -> Self {
+        self.components = components;
+        self
+    }
+
+    // e.g. a single layer of a shadow cascade array.
+    pub fn layer(base_array_layer: u32) -> Self {
+        Self::default()
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .array_layers(base_array_layer, 1)
+    }
+
+    // The mips and layers of `image` the view covers. Counts left out (or vk::REMAINING_*) run to
+    // the end of the image; empty ranges and ranges past the end are rejected.
+    pub fn subresource_range(&self, image: &ImageDesc, aspect_mask: vk::ImageAspectFlags) -> Result<vk::ImageSubresourceRange> {
+        let level_count = view_count(self.base_mip_level, self.level_count, vk::REMAINING_MIP_LEVELS, image.mip_levels)
+            .ok_or(VkQueueError::InvalidParameter("view mip levels must lie within the image"))?;
+        let layer_count = view_count(self.base_array_layer, self.layer_count, vk::REMAINING_ARRAY_LAYERS, image.array_layers)
+            .ok_or(VkQueueError::InvalidParameter("view array layers must lie within the image"))?;
+        Ok(vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: self.base_mip_level,
+            level_count,
+            base_array_layer: self.base_array_layer,
+            layer_count,
+        })
+    }
+}
+
+fn view_count(base: u32, count: Option<u32>, remaining: u32, total: u32) -> Option<u32> {
+    let count = match count {
+        None => total.checked_sub(base)?,
+        Some(count) if count == remaining => total.checked_sub(base)?,
+        Some(count) => count,
+    };
+    (count > 0 && base.checked_add(count)? <= total).then_some(count)
+}
+
+// Number of levels down to 1x1x1: floor(log2(largest dimension)) + 1.
+pub fn mip_level_count(extent: vk::Extent3D) -> u32 {
+    let largest = extent.width.max(extent.height).max(extent.depth).max(1);
+    u32::BITS - largest.leading_zeros()
+}
+
+// Size of `level`, never below 1 in any dimension.
+pub fn mip_extent(extent: vk::Extent3D, level: u32) -> vk::Extent3D {
+    vk::Extent3D {
+        width: (extent.width >> level).max(1),
+        height: (extent.height >> level).max(1),
+        depth: (extent.depth >> level).max(1),
+    }
+}
+
+// Every aspect of the format, as barriers and subresource ranges over the whole image need.
+pub fn format_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT => {
+            vk::ImageAspectFlags::DEPTH
+        }
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+// A sampled view of a depth-stencil format may only have one aspect, so views default to depth.
+pub fn view_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    let aspect = format_aspect(format);
+    if aspect.contains(vk::ImageAspectFlags::DEPTH) {
+        vk::ImageAspectFlags::DEPTH
+    } else {
+        aspect
+    }
+}
+
+// vkCmdBlitImage mip generation needs the image to be both blit source and destination, with
+// linear filtering.
+pub fn supports_linear_blit(properties: &vk::FormatProperties) -> bool {
+    properties.optimal_tiling_features.contains(
+        vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+    )
+}
+
+pub fn is_srgb(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8_SRGB
+            | vk::Format::R8G8_SRGB
+            | vk::Format::R8G8B8_SRGB
+            | vk::Format::B8G8R8_SRGB
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+    )
+}
+
+// Bytes per block and the block's width and height in texels, as a buffer-to-image copy of
+// `aspect` lays them out. Depth-stencil formats are copied one aspect at a time. None for formats
+// not listed here.
+pub fn copy_block(format: vk::Format, aspect: vk::ImageAspectFlags) -> Option<(u64, u32, u32)> {
+    use vk::Format as F;
+
+    if aspect == vk::ImageAspectFlags::STENCIL {
+        return matches!(format, F::S8_UINT | F::D16_UNORM_S8_UINT | F::D24_UNORM_S8_UINT | F::D32_SFLOAT_S8_UINT)
+            .then_some((1, 1, 1));
+    }
+    let bytes = match format {
+        F::R4G4_UNORM_PACK8 | F::R8_UNORM | F::R8_SNORM | F::R8_UINT | F::R8_SINT | F::R8_SRGB => 1,
+        F::R4G4B4A4_UNORM_PACK16
+        | F::B4G4R4A4_UNORM_PACK16
+        | F::R5G6B5_UNORM_PACK16
+        | F::B5G6R5_UNORM_PACK16
+        | F::R5G5B5A1_UNORM_PACK16
+        | F::B5G5R5A1_UNORM_PACK16
+        | F::A1R5G5B5_UNORM_PACK16
+        | F::R8G8_UNORM
+        | F::R8G8_SNORM
+        | F::R8G8_UINT
+        | F::R8G8_SINT
+        | F::R8G8_SRGB
+        | F::R16_UNORM
+        | F::R16_SNORM
+        | F::R16_UINT
+        | F::R16_SINT
+        | F::R16_SFLOAT
+        | F::D16_UNORM
+        | F::D16_UNORM_S8_UINT => 2,
+        F::R8G8B8_UNORM | F::R8G8B8_SRGB | F::B8G8R8_UNORM | F::B8G8R8_SRGB => 3,
+        F::R8G8B8A8_UNORM
+        | F::R8G8B8A8_SNORM
+        | F::R8G8B8A8_UINT
+        | F::R8G8B8A8_SINT
+        | F::R8G8B8A8_SRGB
+        | F::B8G8R8A8_UNORM
+        | F::B8G8R8A8_SNORM
+        | F::B8G8R8A8_UINT
+        | F::B8G8R8A8_SINT
+        | F::B8G8R8A8_SRGB
+        | F::A8B8G8R8_UNORM_PACK32
+        | F::A8B8G8R8_SRGB_PACK32
+        | F::A2R10G10B10_UNORM_PACK32
+        | F::A2B10G10R10_UNORM_PACK32
+        | F::A2B10G10R10_UINT_PACK32
+        | F::B10G11R11_UFLOAT_PACK32
+        | F::E5B9G9R9_UFLOAT_PACK32
+        | F::R16G16_UNORM
+        | F::R16G16_SNORM
+        | F::R16G16_UINT
+        | F::R16G16_SINT
+        | F::R16G16_SFLOAT
+        | F::R32_UINT
+        | F::R32_SINT
+        | F::R32_SFLOAT
+        | F::X8_D24_UNORM_PACK32
+        | F::D24_UNORM_S8_UINT
+        | F::D32_SFLOAT
+        | F::D32_SFLOAT_S8_UINT => 4,
+        F::R16G16B16_UNORM | F::R16G16B16_SFLOAT => 6,
+        F::R16G16B16A16_UNORM
+        | F::R16G16B16A16_SNORM
+        | F::R16G16B16A16_UINT
+        | F::R16G16B16A16_SINT
+        | F::R16G16B16A16_SFLOAT
+        | F::R32G32_UINT
+        | F::R32G32_SINT
+        | F::R32G32_SFLOAT => 8,
+        F::R32G32B32_UINT | F::R32G32B32_SINT | F::R32G32B32_SFLOAT => 12,
+        F::R32G32B32A32_UINT | F::R32G32B32A32_SINT | F::R32G32B32A32_SFLOAT => 16,
+        F::BC1_RGB_UNORM_BLOCK
+        | F::BC1_RGB_SRGB_BLOCK
+        | F::BC1_RGBA_UNORM_BLOCK
+        | F::BC1_RGBA_SRGB_BLOCK
+        | F::BC4_UNORM_BLOCK
+        | F::BC4_SNORM_BLOCK
+        | F::ETC2_R8G8B8_UNORM_BLOCK
+        | F::ETC2_R8G8B8_SRGB_BLOCK
+        | F::ETC2_R8G8B8A1_UNORM_BLOCK
+        | F::ETC2_R8G8B8A1_SRGB_BLOCK
+        | F::EAC_R11_UNORM_BLOCK
+        | F::EAC_R11_SNORM_BLOCK => return Some((8, 4, 4)),
+        F::BC2_UNORM_BLOCK
+        | F::BC2_SRGB_BLOCK
+        | F::BC3_UNORM_BLOCK
+        | F::BC3_SRGB_BLOCK
+        | F::BC5_UNORM_BLOCK
+        | F::BC5_SNORM_BLOCK
+        | F::BC6H_UFLOAT_BLOCK
+        | F::BC6H_SFLOAT_BLOCK
+        | F::BC7_UNORM_BLOCK
+        | F::BC7_SRGB_BLOCK
+        | F::ETC2_R8G8B8A8_UNORM_BLOCK
+        | F::ETC2_R8G8B8A8_SRGB_BLOCK
+        | F::EAC_R11G11_UNORM_BLOCK
+        | F::EAC_R11G11_SNORM_BLOCK
+        | F::ASTC_4X4_UNORM_BLOCK
+        | F::ASTC_4X4_SRGB_BLOCK => return Some((16, 4, 4)),
+        F::ASTC_8X8_UNORM_BLOCK | F::ASTC_8X8_SRGB_BLOCK => return Some((16, 8, 8)),
+        _ => return None,
+    };
+    Some((bytes, 1, 1))
+}
+
+// Tightly packed bytes of `layer_count` layers of a level with the given extent.
+pub fn copy_size(format: vk::Format, aspect: vk::ImageAspectFlags, extent: vk::Extent3D, layer_count: u32) -> Option<u64> {
+    let (bytes, block_width, block_height) = copy_block(format, aspect)?;
+    let blocks = u64::from(extent.width.div_ceil(block_width))
+        * u64::from(extent.height.div_ceil(block_height))
+        * u64::from(extent.depth);
+    blocks.checked_mul(bytes)?.checked_mul(u64::from(layer_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn view_ranges_stay_inside_the_image() {
+        let image = ImageDesc::new_2d(256, 256, vk::Format::R8G8B8A8_UNORM, vk::ImageUsageFlags::SAMPLED)
+            .full_mip_chain()
+            .array_layers(4);
+        let range = |view: ImageViewDesc| view.subresource_range(&image, vk::ImageAspectFlags::COLOR);
+
+        let whole = range(ImageViewDesc::default()).unwrap();
+        assert_eq!((whole.level_count, whole.layer_count), (9, 4));
+        let tail = range(ImageViewDesc { base_mip_level: 3, ..Default::default() }).unwrap();
+        assert_eq!(tail.level_count, 6);
+        let remaining = range(ImageViewDesc::default().array_layers(1, vk::REMAINING_ARRAY_LAYERS)).unwrap();
+        assert_eq!(remaining.layer_count, 3);
+        assert_eq!(range(ImageViewDesc::layer(3)).unwrap().layer_count, 1);
+
+        assert!(range(ImageViewDesc { base_mip_level: 9, ..Default::default() }).is_err());
+        assert!(range(ImageViewDesc::layer(4)).is_err());
+        assert!(range(ImageViewDesc::default().mip_levels(0, 0)).is_err());
+        assert!(range(ImageViewDesc::default().mip_levels(8, 2)).is_err());
+        assert!(range(ImageViewDesc::default().array_layers(2, u32::MAX - 1)).is_err());
+    }
+
+    #[test]
+    fn copy_sizes() {
+        let extent = |width, height| vk::Extent3D { width, height, depth: 1 };
+        let color = vk::ImageAspectFlags::COLOR;
+        assert_eq!(copy_size(vk::Format::R8G8B8A8_UNORM, color, extent(3, 5), 2), Some(120));
+        // Partial blocks at the edges still take a whole block.
+        assert_eq!(copy_size(vk::Format::BC1_RGB_UNORM_BLOCK, color, extent(6, 1), 1), Some(16));
+        assert_eq!(copy_size(vk::Format::BC7_UNORM_BLOCK, color, extent(1, 1), 1), Some(16));
+
+        let depth_stencil = vk::Format::D24_UNORM_S8_UINT;
+        assert_eq!(copy_size(depth_stencil, vk::ImageAspectFlags::DEPTH, extent(2, 2), 1), Some(16));
+        assert_eq!(copy_size(depth_stencil, vk::ImageAspectFlags::STENCIL, extent(2, 2), 1), Some(4));
+        assert_eq!(copy_size(vk::Format::R8G8B8A8_UNORM, vk::ImageAspectFlags::STENCIL, extent(2, 2), 1), None);
+        assert_eq!(copy_size(vk::Format::UNDEFINED, color, extent(2, 2), 1), None);
+    }
+}
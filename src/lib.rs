@@ -1,55 +1,79 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
+#![allow(clippy::too_many_arguments)]
 // i know its not recommend but fuck off ok
+pub mod allocator;
+pub mod bootstrap;
+pub mod builder;
+pub mod capabilities;
+pub mod error;
+pub mod imaging;
 pub mod memtype;
 pub mod pipeline;
 pub mod raytracing;
+pub mod recorder;
 pub mod taskmanager;
 pub mod utils;
 
 pub mod vk_cmdbuffermgr;
+pub mod vk_deletion;
 pub mod vk_deskmgr;
 pub mod vk_framemgr;
 pub mod vk_memorymgr;
+pub mod vk_offscreen;
+pub mod vk_ownershipmgr;
 pub mod vk_pipelinemgr;
 pub mod vk_renderpassmgr;
 pub mod vk_resmgr;
+pub mod vk_samplermgr;
 pub mod vk_shadermgr;
 pub mod vk_subpassmgr;
 pub mod vk_swapchain;
 pub mod vk_syncmgr;
 pub mod vk_textmgr;
+pub mod vk_uploadmgr;
 
-use ash::{extensions::khr, vk};
-use std::sync::Arc;
+use ash::vk;
+use std::sync::{Arc, Mutex};
 
 use crate::{
+    builder::VulkanQueueBuilder,
+    error::{Result, VkQueueError},
     pipeline::PipelineManager,
     taskmanager::{TaskId, TaskManager},
     utils::DebugUtils,
     raytracing::RTPipelineManager,
+    recorder::{SubmissionLog, SubmissionRecorder},
     vk_cmdbuffermgr::CommandBufferManager,
+    vk_deletion::DeletionQueue,
     vk_deskmgr::DescriptorManager,
     vk_framemgr::FrameManager,
     vk_memorymgr::MemoryManager,
+    vk_offscreen::OffscreenTarget,
+    vk_ownershipmgr::QueueOwnershipManager,
     vk_pipelinemgr::ComputePipelineManager,
     vk_renderpassmgr::RenderPassManager,
     vk_resmgr::ResourceManager,
+    vk_samplermgr::SamplerManager,
     vk_shadermgr::ShaderManager,
     vk_subpassmgr::SubpassManager,
-    vk_swapchain::{Swapchain, SwapchainSupportDetails},
+    vk_swapchain::Swapchain,
     vk_syncmgr::SynchronizationManager,
     vk_textmgr::TextureManager,
+    vk_uploadmgr::UploadManager,
 };
 
 #[allow(dead_code)]
-pub struct VulkanQueue<'a> {
-    device: &'a ash::Device,
-    command_pool: vk::CommandPool,
-    graphics_queue: vk::Queue,
-    buffer: vk::Buffer,
-    frame_buffer: u32,
-    swapchain: std::rc::Rc<Swapchain>,
+pub struct VulkanQueue {
+    device: Arc<ash::Device>,
+    command_pool: Arc<Mutex<vk::CommandPool>>,
+    queue_family_index: u32,
+    // vkQueueSubmit requires external synchronization of the queue.
+    graphics_queue: Arc<Mutex<vk::Queue>>,
+    // Fields are dropped in declaration order, after `Drop::drop` has waited for the device,
+    // which is the only wait of the teardown. `None` in headless mode; it owns the swapchain.
+    frame_manager: Option<FrameManager>,
+    offscreen_target: Option<OffscreenTarget>,
     resource_manager: ResourceManager,
     descriptor_manager: DescriptorManager,
     sync_manager: SynchronizationManager,
@@ -57,150 +81,90 @@ pub struct VulkanQueue<'a> {
     subpass_manager: SubpassManager,
     render_pass_manager: RenderPassManager,
     command_buffer_manager: CommandBufferManager,
-    frame_manager: FrameManager,
-    memory_manager: MemoryManager,
+    ownership_manager: QueueOwnershipManager,
+    compute_pipeline_manager: ComputePipelineManager,
     shader_manager: ShaderManager,
     texture_manager: TextureManager,
-    compute_pipeline_manager: ComputePipelineManager,
-    debug_utils: DebugUtils,
+    sampler_manager: SamplerManager,
+    upload_manager: UploadManager,
+    memory_manager: MemoryManager,
     task_manager: TaskManager,
-    raytracing: RTPipelineManager,
+    raytracing: Option<RTPipelineManager>,
+    recorder: Arc<SubmissionRecorder>,
+    debug_utils: Option<DebugUtils>,
 }
 
-#[allow(unused_must_use)]
 #[allow(dead_code)]
-impl<'a> VulkanQueue<'a> {
-    pub fn new(
-        entry: &ash::Entry,
-        instance: &ash::Instance,
-        device: &'a ash::Device,
-        physical_device: vk::PhysicalDevice,
-        queue_create_infos: Vec<vk::DeviceQueueCreateInfo>,
-        surface: vk::SurfaceKHR,
-        surface_loader: &khr::Surface,
-        queue_index: u32,
-        buffer: vk::Buffer,
-        frame_buffer: u32,
-        window_width: u32,
-        window_height: u32,
-    ) -> Self {
-        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
-            .queue_family_index(queue_index)
-            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
-
-        let command_pool = unsafe {
-            device.create_command_pool(&command_pool_create_info, None).unwrap()
-        };
-
-        let graphics_queue = unsafe { device.get_device_queue(queue_index, 0) };
-
-        let swapchain = std::rc::Rc::new(Swapchain::new(
-            instance,
-            device,
-            physical_device,
-            surface,
-            window_width,
-            window_height,
-            surface_loader,
-        ));
-
-        let memory_properties = unsafe {
-            instance.get_physical_device_memory_properties(physical_device)
-        };
-        let resource_manager = ResourceManager::new(device.clone(), memory_properties);
-
-        let sync_manager = SynchronizationManager::new(device.clone());
-
-        let descriptor_manager = DescriptorManager::new(device.clone());
-
-        let pipeline_manager = PipelineManager::new(device.clone());
+impl VulkanQueue {
+    pub fn builder<'a>() -> VulkanQueueBuilder<'a> {
+        VulkanQueueBuilder::new()
+    }
 
-        let subpass_manager = SubpassManager::new();
+    pub fn is_headless(&self) -> bool {
+        self.frame_manager.is_none()
+    }
 
-        let render_pass_manager = RenderPassManager::new(device.clone());
+    // Always the current swapchain, including after `FrameManager::recreate_swapchain`.
+    pub fn swapchain(&self) -> Option<&Arc<Swapchain>> {
+        self.frame_manager.as_ref().map(FrameManager::swapchain)
+    }
 
-        let command_buffer_manager = CommandBufferManager::new(device.clone(), command_pool);
+    pub fn frame_manager(&self) -> Option<&FrameManager> {
+        self.frame_manager.as_ref()
+    }
 
-        let swapchain_support = SwapchainSupportDetails::new(instance, physical_device, surface, surface_loader);
+    pub fn frame_manager_mut(&mut self) -> Option<&mut FrameManager> {
+        self.frame_manager.as_mut()
+    }
 
-        let surface_format = swapchain_support
-            .formats
-            .iter()
-            .cloned()
-            .find(|format| {
-                format.format == vk::Format::B8G8R8A8_SRGB
-                    && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
-            })
-            .unwrap_or(swapchain_support.formats[0]);
+    pub fn offscreen_target(&self) -> Option<&OffscreenTarget> {
+        self.offscreen_target.as_ref()
+    }
 
-        let present_mode = swapchain_support
-            .present_modes
-            .iter()
-            .cloned()
-            .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
-            .unwrap_or(vk::PresentModeKHR::FIFO);
+    pub fn resource_manager(&self) -> &ResourceManager {
+        &self.resource_manager
+    }
 
-        let extent = swapchain.extent;
+    pub fn memory_manager(&self) -> &MemoryManager {
+        &self.memory_manager
+    }
 
-        let frame_manager = FrameManager::new(
-            device.clone(),
-            khr::Swapchain::new(instance, device),
-            swapchain.handle,
-            present_mode,
-            surface_format,
-            extent,
-            swapchain.clone(),
-        );
+    pub fn texture_manager(&self) -> &TextureManager {
+        &self.texture_manager
+    }
 
-        let memory_properties = unsafe {
-            instance.get_physical_device_memory_properties(physical_device)
-        };
+    pub fn sampler_manager(&self) -> &SamplerManager {
+        &self.sampler_manager
+    }
 
-        let memory_manager = MemoryManager::new(device.clone(), memory_properties);
-        let shader_manager = ShaderManager::new(device.clone());
-        let texture_manager = TextureManager::new(device.clone(), memory_manager.clone());
+    pub fn upload_manager(&self) -> &UploadManager {
+        &self.upload_manager
+    }
 
-        let debug_utils = DebugUtils::new(entry, instance);
+    pub fn deletion_queue(&self) -> &DeletionQueue {
+        self.resource_manager.deletion_queue()
+    }
 
-        let compute_pipeline_manager = ComputePipelineManager::new(device.clone());
+    pub fn create_offscreen_target(&self, extent: vk::Extent2D, format: vk::Format) -> Result<OffscreenTarget> {
+        OffscreenTarget::new(&self.device, &self.resource_manager, extent, format)
+    }
 
-        let task_manager = TaskManager::new(4);
+    pub fn destroy_offscreen_target(&self, target: OffscreenTarget) -> Result<()> {
+        unsafe { self.device.device_wait_idle()? };
+        target.destroy(&self.device);
+        Ok(())
+    }
 
-        let raytracing = RTPipelineManager::new(
-            entry,
-            instance,
-            Arc::new(device.clone()),
-            physical_device,
-            queue_create_infos,
-        );
+    pub fn start_recording(&self) {
+        self.recorder.start();
+    }
 
-        Self {
-            device,
-            command_pool,
-            graphics_queue,
-            buffer,
-            frame_buffer,
-            swapchain,
-            resource_manager,
-            sync_manager,
-            descriptor_manager,
-            pipeline_manager,
-            subpass_manager,
-            render_pass_manager,
-            command_buffer_manager,
-            frame_manager,
-            memory_manager,
-            shader_manager,
-            texture_manager,
-            debug_utils,
-            compute_pipeline_manager,
-            task_manager,
-            raytracing,
-        }
+    pub fn stop_recording(&self) -> SubmissionLog {
+        self.recorder.stop()
     }
 
     pub fn add_task<T: FnOnce() + Send + 'static>(
-        &mut self,
+        &self,
         task: T,
         priority: i32,
         dependencies: &[TaskId],
@@ -208,7 +172,7 @@ impl<'a> VulkanQueue<'a> {
         self.task_manager.add_task(task, priority, dependencies)
     }
 
-    pub fn cancel_task(&mut self, id: TaskId) -> bool {
+    pub fn cancel_task(&self, id: TaskId) -> bool {
         self.task_manager.cancel_task(id)
     }
 
@@ -216,78 +180,164 @@ impl<'a> VulkanQueue<'a> {
         self.task_manager.get_progress()
     }
 
+    // Every thread index gets a transient command pool of its own for `command_generator` to
+    // allocate from. The pools live until everything submitted by the call has completed.
     pub async fn submit_commands(
         &self,
         num_threads: usize,
-        mut command_generator: impl FnMut(usize) -> Vec<vk::CommandBuffer> + Send + 'static,
-    ) -> Result<(), vk::Result> {
-        let mut fence_vec = Vec::with_capacity(num_threads);
-        let mut semaphore_vec = Vec::with_capacity(num_threads);
-
+        mut command_generator: impl FnMut(usize, vk::CommandPool) -> Vec<vk::CommandBuffer> + Send + 'static,
+    ) -> Result<()> {
+        let mut slots = SubmitSlots::new(self.device.clone());
         for _ in 0..num_threads {
-            fence_vec.push(unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None).unwrap() });
-            semaphore_vec.push(unsafe { self.device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None).unwrap() });
+            slots.add(self.queue_family_index)?;
         }
 
         let mut futures = Vec::with_capacity(num_threads);
+        let batch = self.recorder.begin_batch();
 
         for i in 0..num_threads {
 
-            let command_buffers = command_generator(i);
+            let command_buffers = command_generator(i, slots.command_pools[i]);
             let command_buffers_len = command_buffers.len() as u32;
-            let (synced_tx, _synced_rx) = tokio::sync::oneshot::channel();
 
-            unsafe { self.device.reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::RELEASE_RESOURCES); }
-            let (tx, mut rx) = tokio::sync::mpsc::channel(command_buffers_len as usize);
+            let (tx, mut rx) = tokio::sync::mpsc::channel(command_buffers_len.max(1) as usize);
             let device = self.device.clone();
-            let graphics_queue = self.graphics_queue;
-            let fence = fence_vec[i];
-            let semaphore = semaphore_vec[i];
+            let graphics_queue = self.graphics_queue.clone();
+            let fence = slots.fences[i];
+            let semaphore = slots.semaphores[i];
+            let recorder = self.recorder.clone();
             let future = tokio::spawn(async move {
 
                 for command_buffer in &command_buffers {
-                    tx.send(command_buffer.clone()).await.unwrap();
+                    tx.send(*command_buffer).await.unwrap();
                 }
                 drop(tx);
                 let mut command_buffer_handles = Vec::new();
                 while let Some(command_buffer) = rx.recv().await {
                     command_buffer_handles.push(command_buffer);
                 }
-                let submit_infos: Vec<_> = command_buffer_handles.iter().map(|c| vk::SubmitInfo::builder().command_buffers(&[*c]).wait_semaphores(&[semaphore]).signal_semaphores(&[semaphore]).build()).collect();
-                unsafe {
-                    device.queue_submit(graphics_queue, submit_infos.as_slice(), fence);
-                }                
-                synced_tx.send(()).unwrap();
-                Ok::<(), vk::Result>(())
+                let semaphores = [semaphore];
+                let wait_dst_stage_masks = [vk::PipelineStageFlags::ALL_COMMANDS];
+                let submit_infos: Vec<_> = command_buffer_handles.iter().map(|c| vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(c)).wait_semaphores(&semaphores).wait_dst_stage_mask(&wait_dst_stage_masks).signal_semaphores(&semaphores).build()).collect();
+                {
+                    let graphics_queue = graphics_queue.lock().unwrap();
+                    recorder.record(batch, Some(i as u32), &submit_infos, fence)?;
+                    unsafe {
+                        device.queue_submit(*graphics_queue, submit_infos.as_slice(), fence)?;
+                    }
+                }
+                Ok::<(), VkQueueError>(())
             });
             
             futures.push(future);
         }
 
+        // Every task has to be done with the slots before they can be torn down.
+        let mut result = Ok(());
         for future in futures {
-            future.await.unwrap();
+            let joined = future.await.map_err(VkQueueError::from).and_then(|submitted| submitted);
+            result = result.and(joined);
         }
+        result?;
 
-        let sync_submit_info = vk::SubmitInfo::builder().wait_semaphores(&semaphore_vec).wait_dst_stage_mask(&[vk::PipelineStageFlags::ALL_COMMANDS]).build();
+        let wait_dst_stage_masks = vec![vk::PipelineStageFlags::ALL_COMMANDS; slots.semaphores.len()];
+        let sync_submit_info = vk::SubmitInfo::builder().wait_semaphores(&slots.semaphores).wait_dst_stage_mask(&wait_dst_stage_masks).build();
 
-        unsafe {
-            self.device.queue_submit(self.graphics_queue, &[sync_submit_info], vk::Fence::null());
+        // The semaphores can only be destroyed once the sync submission has consumed them.
+        let sync_fence = unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None)? };
+        slots.fences.push(sync_fence);
+
+        {
+            let graphics_queue = self.graphics_queue.lock().unwrap();
+            self.recorder.record(batch, None, &[sync_submit_info], sync_fence)?;
+            unsafe {
+                self.device.queue_submit(*graphics_queue, &[sync_submit_info], sync_fence)?;
+            }
         }
 
-        for fence in &fence_vec {
+        for fence in &slots.fences {
             let timeout = std::time::Duration::from_millis(1000);
 
-            match unsafe {
+            unsafe {
                 self.device
-                    .wait_for_fences(&[*fence], true, timeout.as_nanos() as u64)
-            } {
-                Ok(_) => {}
-                Err(error) => {
-                    return Err(error);
-                }
+                    .wait_for_fences(&[*fence], true, timeout.as_nanos() as u64)?;
             }
         }
+        slots.completed = true;
 
         Ok(())
     }    
 }
+
+// The per-call objects of `submit_commands`. Unless the call got as far as seeing every fence
+// signalled, dropping it waits for the device before destroying them.
+struct SubmitSlots {
+    device: Arc<ash::Device>,
+    command_pools: Vec<vk::CommandPool>,
+    fences: Vec<vk::Fence>,
+    semaphores: Vec<vk::Semaphore>,
+    completed: bool,
+}
+
+impl SubmitSlots {
+    fn new(device: Arc<ash::Device>) -> Self {
+        Self {
+            device,
+            command_pools: Vec::new(),
+            fences: Vec::new(),
+            semaphores: Vec::new(),
+            completed: false,
+        }
+    }
+
+    fn add(&mut self, queue_family_index: u32) -> Result<()> {
+        let command_pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        unsafe {
+            self.command_pools.push(self.device.create_command_pool(&command_pool_info, None)?);
+            self.fences.push(self.device.create_fence(&vk::FenceCreateInfo::default(), None)?);
+            self.semaphores.push(self.device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SubmitSlots {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.completed {
+                let _ = self.device.device_wait_idle();
+            }
+            for &fence in &self.fences {
+                self.device.destroy_fence(fence, None);
+            }
+            for &semaphore in &self.semaphores {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+            for &command_pool in &self.command_pools {
+                self.device.destroy_command_pool(command_pool, None);
+            }
+        }
+    }
+}
+
+impl Drop for VulkanQueue {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.device_wait_idle();
+        }
+
+        if let Some(target) = self.offscreen_target.take() {
+            target.destroy(&self.device);
+        }
+
+        let command_pool = self.command_pool.lock().unwrap();
+        unsafe { self.device.destroy_command_pool(*command_pool, None) };
+    }
+}
+
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<VulkanQueue>();
+};
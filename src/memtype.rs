@@ -1,17 +1,211 @@
 
 use ash::vk;
 
-pub fn find_memorytype_index(
-    requirements: &vk::MemoryRequirements,
+// BAR windows are 256 MiB (some drivers report a little less). Without resizable BAR,
+// that is all of device memory the host can see, and it's shared with everyone.
+const SMALL_HEAP_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryUsage {
+    // Render targets, sampled images, vertex data uploaded once.
+    GpuOnly,
+    // Written by the CPU every frame and read by the GPU, e.g. uniforms.
+    CpuToGpu,
+    // Written by the GPU and read back by the CPU.
+    GpuToCpu,
+    // Source of transfers, written once by the CPU.
+    Staging,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryRequest {
+    pub required: vk::MemoryPropertyFlags,
+    pub preferred: vk::MemoryPropertyFlags,
+    pub not_preferred: vk::MemoryPropertyFlags,
+}
+
+impl From<MemoryUsage> for MemoryRequest {
+    fn from(usage: MemoryUsage) -> Self {
+        let (required, preferred, not_preferred) = match usage {
+            MemoryUsage::GpuOnly => (
+                vk::MemoryPropertyFlags::empty(),
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                vk::MemoryPropertyFlags::HOST_VISIBLE,
+            ),
+            MemoryUsage::CpuToGpu => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_COHERENT,
+                vk::MemoryPropertyFlags::HOST_CACHED,
+            ),
+            MemoryUsage::GpuToCpu => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE,
+                vk::MemoryPropertyFlags::HOST_CACHED | vk::MemoryPropertyFlags::HOST_COHERENT,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ),
+            MemoryUsage::Staging => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE,
+                vk::MemoryPropertyFlags::HOST_COHERENT,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_CACHED,
+            ),
+        };
+        Self {
+            required,
+            preferred,
+            not_preferred,
+        }
+    }
+}
+
+// Raw flags are all required, as before.
+impl From<vk::MemoryPropertyFlags> for MemoryRequest {
+    fn from(required: vk::MemoryPropertyFlags) -> Self {
+        Self {
+            required,
+            ..Default::default()
+        }
+    }
+}
+
+impl MemoryRequest {
+    // Lower is better; None when the type lacks a required flag.
+    pub fn cost(&self, memory_properties: &vk::PhysicalDeviceMemoryProperties, memory_type_index: u32) -> Option<u32> {
+        let memory_type = memory_properties.memory_types[memory_type_index as usize];
+        let flags = memory_type.property_flags;
+        if !flags.contains(self.required) {
+            return None;
+        }
+        if flags.contains(vk::MemoryPropertyFlags::PROTECTED) && !self.required.contains(vk::MemoryPropertyFlags::PROTECTED) {
+            return None;
+        }
+
+        let mut cost = (self.preferred & !flags).as_raw().count_ones() + (self.not_preferred & flags).as_raw().count_ones();
+
+        let heap_size = memory_properties.memory_heaps[memory_type.heap_index as usize].size;
+        let host_visible_vram = vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE;
+        if flags.contains(host_visible_vram) && heap_size <= SMALL_HEAP_SIZE {
+            cost += 1;
+        }
+        Some(cost)
+    }
+}
+
+// The one place memory types are picked: cheapest type by `MemoryRequest::cost`, then the larger
+// heap, then the lower index.
+pub fn find_memory_type_index(
     memory_properties: &vk::PhysicalDeviceMemoryProperties,
-    usage: vk::MemoryPropertyFlags,
+    memory_type_bits: u32,
+    request: impl Into<MemoryRequest>,
 ) -> Option<u32> {
-    for i in 0..memory_properties.memory_type_count {
-        if requirements.memory_type_bits & (1 << i) != 0
-            && memory_properties.memory_types[i as usize].property_flags.contains(usage)
-        {
-            return Some(i);
+    let request = request.into();
+    (0..memory_properties.memory_type_count)
+        .filter(|&i| memory_type_bits & (1 << i) != 0)
+        .filter_map(|i| request.cost(memory_properties, i).map(|cost| (i, cost)))
+        .min_by_key(|&(i, cost)| {
+            let heap_index = memory_properties.memory_types[i as usize].heap_index;
+            (cost, std::cmp::Reverse(memory_properties.memory_heaps[heap_index as usize].size), i)
+        })
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+    const DL: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+    const HV: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::HOST_VISIBLE;
+    const HC: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::HOST_COHERENT;
+    const CACHED: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::HOST_CACHED;
+    const PROTECTED: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::PROTECTED;
+
+    fn properties(heaps: &[(u64, bool)], types: &[(vk::MemoryPropertyFlags, u32)]) -> vk::PhysicalDeviceMemoryProperties {
+        let mut properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: types.len() as u32,
+            memory_heap_count: heaps.len() as u32,
+            ..Default::default()
+        };
+        for (i, &(size, device_local)) in heaps.iter().enumerate() {
+            let flags = if device_local { vk::MemoryHeapFlags::DEVICE_LOCAL } else { vk::MemoryHeapFlags::empty() };
+            properties.memory_heaps[i] = vk::MemoryHeap { size, flags };
         }
+        for (i, &(property_flags, heap_index)) in types.iter().enumerate() {
+            properties.memory_types[i] = vk::MemoryType { property_flags, heap_index };
+        }
+        properties
+    }
+
+    // VRAM, system memory and the 256 MiB BAR window, plus a protected type.
+    fn discrete() -> vk::PhysicalDeviceMemoryProperties {
+        properties(
+            &[(8 * GIB, true), (16 * GIB, false), (SMALL_HEAP_SIZE, true)],
+            &[(DL, 0), (HV | HC, 1), (HV | HC | CACHED, 1), (DL | HV | HC, 2), (DL | PROTECTED, 0)],
+        )
+    }
+
+    // Resizable BAR: all of VRAM is host visible.
+    fn rebar() -> vk::PhysicalDeviceMemoryProperties {
+        properties(
+            &[(8 * GIB, true), (16 * GIB, false)],
+            &[(DL, 0), (HV | HC, 1), (HV | HC | CACHED, 1), (DL | HV | HC, 0)],
+        )
+    }
+
+    // Integrated GPU: one heap, every type device local.
+    fn uma() -> vk::PhysicalDeviceMemoryProperties {
+        properties(&[(16 * GIB, true)], &[(DL, 0), (DL | HV | HC, 0), (DL | HV | HC | CACHED, 0)])
+    }
+
+    #[test]
+    fn picks_by_intent() {
+        let cases = [
+            ("discrete", discrete(), MemoryUsage::GpuOnly, 0),
+            // The BAR heap ties with system memory and loses on size.
+            ("discrete", discrete(), MemoryUsage::CpuToGpu, 1),
+            ("discrete", discrete(), MemoryUsage::GpuToCpu, 2),
+            ("discrete", discrete(), MemoryUsage::Staging, 1),
+            ("rebar", rebar(), MemoryUsage::GpuOnly, 0),
+            ("rebar", rebar(), MemoryUsage::CpuToGpu, 3),
+            ("rebar", rebar(), MemoryUsage::GpuToCpu, 2),
+            ("rebar", rebar(), MemoryUsage::Staging, 1),
+            ("uma", uma(), MemoryUsage::GpuOnly, 0),
+            ("uma", uma(), MemoryUsage::CpuToGpu, 1),
+            ("uma", uma(), MemoryUsage::GpuToCpu, 2),
+            ("uma", uma(), MemoryUsage::Staging, 1),
+        ];
+        for (layout, properties, usage, expected) in cases {
+            assert_eq!(find_memory_type_index(&properties, !0, usage), Some(expected), "{} {:?}", layout, usage);
+        }
+    }
+
+    #[test]
+    fn small_device_local_heaps_cost_extra() {
+        let request = MemoryRequest::from(MemoryUsage::CpuToGpu);
+        assert_eq!(request.cost(&discrete(), 3), Some(1));
+        assert_eq!(request.cost(&rebar(), 3), Some(0));
+    }
+
+    #[test]
+    fn required_flags_are_hard_limits() {
+        assert_eq!(find_memory_type_index(&discrete(), !0, DL | HV), Some(3));
+        assert_eq!(find_memory_type_index(&discrete(), !0, vk::MemoryPropertyFlags::LAZILY_ALLOCATED), None);
+        // Host-visible intents never fall back to device-only memory.
+        assert_eq!(find_memory_type_index(&discrete(), 1 << 0, MemoryUsage::Staging), None);
+        // Preferred flags are not: device-only work still lands somewhere.
+        assert_eq!(find_memory_type_index(&discrete(), 1 << 2, MemoryUsage::GpuOnly), Some(2));
+    }
+
+    #[test]
+    fn protected_memory_only_when_required() {
+        assert_eq!(find_memory_type_index(&discrete(), 1 << 4, MemoryUsage::GpuOnly), None);
+        assert_eq!(find_memory_type_index(&discrete(), !0, DL | PROTECTED), Some(4));
+    }
+
+    #[test]
+    fn ties_go_to_the_larger_heap_then_the_lower_index() {
+        // Types 1, 2 and 3 all cost 2 for GpuOnly; 1 and 2 share the biggest heap.
+        assert_eq!(find_memory_type_index(&discrete(), 0b1110, MemoryUsage::GpuOnly), Some(1));
+        let duplicated = properties(&[(GIB, true)], &[(DL, 0), (DL, 0)]);
+        assert_eq!(find_memory_type_index(&duplicated, !0, MemoryUsage::GpuOnly), Some(0));
+        assert_eq!(find_memory_type_index(&duplicated, 0b10, MemoryUsage::GpuOnly), Some(1));
     }
-    None
 }
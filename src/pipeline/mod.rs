@@ -1,5 +1,7 @@
 use ash::vk;
 
+use crate::error::{Result, VkQueueError};
+
 pub struct PipelineManager {
     device: ash::Device,
 }
@@ -9,10 +11,10 @@ impl PipelineManager {
         Self { device }
     }
 
-    pub fn create_shader_module(&self, code: &[u8]) -> vk::ShaderModule {
-        let code = ash::util::read_spv(&mut std::io::Cursor::new(code)).unwrap();
+    pub fn create_shader_module(&self, code: &[u8]) -> Result<vk::ShaderModule> {
+        let code = ash::util::read_spv(&mut std::io::Cursor::new(code)).map_err(VkQueueError::SpirV)?;
         let shader_info = vk::ShaderModuleCreateInfo::builder().code(&code).build();
-        unsafe { self.device.create_shader_module(&shader_info, None).unwrap() }
+        Ok(unsafe { self.device.create_shader_module(&shader_info, None)? })
     }
 
     pub fn create_graphics_pipeline(
@@ -28,19 +30,19 @@ impl PipelineManager {
         depth_stencil_info: Option<&vk::PipelineDepthStencilStateCreateInfo>,
         layout: vk::PipelineLayout,
         render_pass: vk::RenderPass,
-    ) -> vk::Pipeline {
-        let shader_entry_name = std::ffi::CString::new("main").unwrap();
+    ) -> Result<vk::Pipeline> {
+        let shader_entry_name = c"main";
     
         let shader_stages = [
             vk::PipelineShaderStageCreateInfo::builder()
                 .stage(vk::ShaderStageFlags::VERTEX)
                 .module(vertex_shader)
-                .name(&shader_entry_name)
+                .name(shader_entry_name)
                 .build(),
             vk::PipelineShaderStageCreateInfo::builder()
                 .stage(vk::ShaderStageFlags::FRAGMENT)
                 .module(fragment_shader)
-                .name(&shader_entry_name)
+                .name(shader_entry_name)
                 .build(),
         ];
     
@@ -57,11 +59,12 @@ impl PipelineManager {
             .render_pass(render_pass)
             .build();
     
-        unsafe {
+        let pipelines = unsafe {
             self.device
                 .create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
-                .unwrap()[0]
-        }
+                .map_err(|(_, result)| result)?
+        };
+        Ok(pipelines[0])
     }
         
 }
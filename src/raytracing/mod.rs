@@ -6,16 +6,22 @@ use std::sync::Arc;
 use ash::extensions::khr::*;
 use ash::vk;
 
-use crate::memtype::find_memorytype_index;
+use crate::error::{Result, VkQueueError};
+use crate::memtype::{find_memory_type_index, MemoryUsage};
 
 pub struct RTPipelineManager {
     device: Arc<ash::Device>,
     ray_tracing_pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
     device_memory_properties: vk::PhysicalDeviceMemoryProperties,
-    queue_create_infos: Vec<vk::DeviceQueueCreateInfo>,
+    // (queue family index, priorities) copied out of the create infos so no raw pointers are kept around.
+    queue_families: Vec<(u32, Vec<f32>)>,
     physical_device: vk::PhysicalDevice,
 }
 
+// The only raw pointer held is the properties' p_next, which is cleared in `new`.
+unsafe impl Send for RTPipelineManager {}
+unsafe impl Sync for RTPipelineManager {}
+
 impl RTPipelineManager {
     pub fn new(
         entry: &ash::Entry,
@@ -24,13 +30,27 @@ impl RTPipelineManager {
         physical_device: vk::PhysicalDevice,
         queue_create_infos: Vec<vk::DeviceQueueCreateInfo>,
     ) -> Self {
-        let ray_tracing_pipeline_properties = Self::get_ray_tracing_properties(entry, instance, physical_device);
+        let mut ray_tracing_pipeline_properties = Self::get_ray_tracing_properties(entry, instance, physical_device);
+        ray_tracing_pipeline_properties.p_next = ptr::null_mut();
         let device_memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let queue_families = queue_create_infos
+            .iter()
+            .map(|info| {
+                let priorities = if info.p_queue_priorities.is_null() {
+                    vec![1.0; info.queue_count as usize]
+                } else {
+                    unsafe {
+                        std::slice::from_raw_parts(info.p_queue_priorities, info.queue_count as usize).to_vec()
+                    }
+                };
+                (info.queue_family_index, priorities)
+            })
+            .collect();
         Self {
             device,
             ray_tracing_pipeline_properties,
             device_memory_properties,
-            queue_create_infos,
+            queue_families,
             physical_device,
         }
     }
@@ -50,10 +70,10 @@ impl RTPipelineManager {
         }
     }
 
-    pub fn create_shader_module(&self, code: &[u8]) -> vk::ShaderModule {
-        let code = ash::util::read_spv(&mut std::io::Cursor::new(code)).unwrap();
+    pub fn create_shader_module(&self, code: &[u8]) -> Result<vk::ShaderModule> {
+        let code = ash::util::read_spv(&mut std::io::Cursor::new(code)).map_err(VkQueueError::SpirV)?;
         let shader_module_create_info = vk::ShaderModuleCreateInfo::builder().code(&code);
-        unsafe { self.device.create_shader_module(&shader_module_create_info, None).unwrap() }
+        Ok(unsafe { self.device.create_shader_module(&shader_module_create_info, None)? })
     }
 
     pub fn create_ray_tracing_pipeline(
@@ -63,7 +83,7 @@ impl RTPipelineManager {
         groups: &[vk::RayTracingShaderGroupCreateInfoKHR],
         max_recursion_depth: u32,
         layout: vk::PipelineLayout,
-    ) -> vk::Pipeline {
+    ) -> Result<vk::Pipeline> {
         let ray_tracing_pipeline = RayTracingPipeline::new(instance, &self.device);
     
         let pipeline_create_info = vk::RayTracingPipelineCreateInfoKHR::builder()
@@ -72,17 +92,16 @@ impl RTPipelineManager {
             .max_pipeline_ray_recursion_depth(max_recursion_depth)
             .layout(layout);
     
-        let pipeline = unsafe {
+        let pipelines = unsafe {
             ray_tracing_pipeline
                 .create_ray_tracing_pipelines(
                     vk::DeferredOperationKHR::null(),
                     vk::PipelineCache::null(),
                     &[pipeline_create_info.build()],
                     None,
-                )
-                .unwrap()[0]
+                )?
         };
-        pipeline
+        Ok(pipelines[0])
     }
 
     pub fn create_shader_binding_table(
@@ -91,7 +110,7 @@ impl RTPipelineManager {
         pipeline: vk::Pipeline,
         groups: &[vk::RayTracingShaderGroupCreateInfoKHR],
         group_handle_size: u32,
-    ) -> (vk::Buffer, vk::DeviceMemory) {
+    ) -> Result<(vk::Buffer, vk::DeviceMemory)> {
         let group_count = groups.len() as u32;
         let sbt_size = group_count * group_handle_size;
 
@@ -99,64 +118,63 @@ impl RTPipelineManager {
             .size(sbt_size as u64)
             .usage(vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS);
 
-        let buffer = unsafe { self.device.create_buffer(&buffer_create_info, None).unwrap() };
+        let buffer = unsafe { self.device.create_buffer(&buffer_create_info, None)? };
 
         let memory_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
-        
-         // Find a memory type that is host visible and coherent
-         let memory_type_index = find_memorytype_index(
-            &memory_requirements,
-            &self.device_memory_properties,
-            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-        )
-        .expect("Unable to find suitable memory type for the shader binding table.");        
 
-         // Allocate memory and bind it to the buffer
-         let allocate_info = vk::MemoryAllocateInfo::builder()
+        // Find a memory type that is host visible and coherent
+        let properties = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let memory_type_index =
+            match find_memory_type_index(&self.device_memory_properties, memory_requirements.memory_type_bits, properties) {
+                Some(memory_type_index) => memory_type_index,
+                None => {
+                    unsafe { self.device.destroy_buffer(buffer, None) };
+                    return Err(VkQueueError::NoSuitableMemoryType {
+                        memory_type_bits: memory_requirements.memory_type_bits,
+                        properties,
+                    });
+                }
+            };
+
+        // Allocate memory and bind it to the buffer
+        let allocate_info = vk::MemoryAllocateInfo::builder()
             .allocation_size(memory_requirements.size)
             .memory_type_index(memory_type_index);
 
-         let memory = unsafe {
-             self.device
-                 .allocate_memory(&allocate_info, None)
-                 .expect("Failed to allocate memory for the shader binding table.")
-         };
-         unsafe {
-             self.device
-                 .bind_buffer_memory(buffer, memory, 0)
-                 .expect("Failed to bind memory to the shader binding table buffer.");
-         }
-
-        let data = unsafe {
-            self.device
-                .map_memory(
-                    memory,
-                    0,
-                    sbt_size as u64,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .unwrap()
+        let memory = match unsafe { self.device.allocate_memory(&allocate_info, None) } {
+            Ok(memory) => memory,
+            Err(error) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                return Err(error.into());
+            }
         };
 
         let ray_tracing_pipeline = RayTracingPipeline::new(instance, &self.device);
-
-        let group_handles = unsafe {
-            ray_tracing_pipeline
-                .get_ray_tracing_shader_group_handles(
+        let written = (|| -> Result<()> {
+            unsafe {
+                self.device.bind_buffer_memory(buffer, memory, 0)?;
+                let group_handles = ray_tracing_pipeline.get_ray_tracing_shader_group_handles(
                     pipeline,
                     0,
                     group_count,
                     sbt_size as usize,
-                )
-                .unwrap()
-        };
+                )?;
+                let data = self.device.map_memory(memory, 0, sbt_size as u64, vk::MemoryMapFlags::empty())?;
+                data.copy_from_nonoverlapping(group_handles.as_ptr() as *const c_void, sbt_size as usize);
+                self.device.unmap_memory(memory);
+            }
+            Ok(())
+        })();
 
-        unsafe {
-            data.copy_from_nonoverlapping(group_handles.as_ptr() as *const c_void, sbt_size as usize);
-            self.device.unmap_memory(memory);
+        if let Err(error) = written {
+            unsafe {
+                self.device.destroy_buffer(buffer, None);
+                self.device.free_memory(memory, None);
+            }
+            return Err(error);
         }
 
-        (buffer, memory)
+        Ok((buffer, memory))
     }
     
     pub fn create_bottom_level_acceleration_structure(
@@ -164,18 +182,28 @@ impl RTPipelineManager {
         instance: &ash::Instance,
         geometries: &[vk::GeometryNV],
         flags: vk::BuildAccelerationStructureFlagsNV,
-    ) -> (vk::AccelerationStructureNV, vk::DeviceMemory) {
+    ) -> Result<(vk::AccelerationStructureNV, vk::DeviceMemory)> {
         let device_extensions = [ash::extensions::nv::RayTracing::name().as_ptr()];
     
+        let queue_create_infos: Vec<_> = self
+            .queue_families
+            .iter()
+            .map(|(queue_family_index, priorities)| {
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(*queue_family_index)
+                    .queue_priorities(priorities)
+                    .build()
+            })
+            .collect();
+
         let device_create_info = vk::DeviceCreateInfo::builder()
-            .queue_create_infos(&self.queue_create_infos)
+            .queue_create_infos(&queue_create_infos)
             .enabled_extension_names(&device_extensions)
             .build();
     
         let device = unsafe {
             instance
-                .create_device(self.physical_device, &device_create_info, None)
-                .expect("Failed to create logical device.")
+                .create_device(self.physical_device, &device_create_info, None)?
         };
     
         let ray_tracing = ash::extensions::nv::RayTracing::new(instance, &device);
@@ -201,8 +229,7 @@ impl RTPipelineManager {
                 ptr::null(),
                 acceleration_structure.as_mut_ptr(),
             )
-            .result()
-            .unwrap();
+            .result()?;
         }
         let acceleration_structure = unsafe { acceleration_structure.assume_init() };
     
@@ -219,12 +246,15 @@ impl RTPipelineManager {
         }
         let memory_requirements = unsafe { memory_requirements.assume_init() }.memory_requirements;
     
-        let memory_type_index = find_memorytype_index(
-            &memory_requirements,
+        let memory_type_index = find_memory_type_index(
             &self.device_memory_properties,
-            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            memory_requirements.memory_type_bits,
+            MemoryUsage::GpuOnly,
         )
-        .expect("Unable to find suitable memory type for the acceleration structure.");
+        .ok_or(VkQueueError::NoSuitableMemoryType {
+            memory_type_bits: memory_requirements.memory_type_bits,
+            properties: vk::MemoryPropertyFlags::empty(),
+        })?;
     
         let allocate_info = vk::MemoryAllocateInfo::builder()
             .allocation_size(memory_requirements.size)
@@ -232,8 +262,7 @@ impl RTPipelineManager {
     
         let memory = unsafe {
             device
-                .allocate_memory(&allocate_info, None)
-                .expect("Failed to allocate memory for the acceleration structure.")
+                .allocate_memory(&allocate_info, None)?
         };
     
         let bind_info = vk::BindAccelerationStructureMemoryInfoNV::builder()
@@ -247,10 +276,9 @@ impl RTPipelineManager {
                 1,
                 &bind_info,
             )
-            .result()
-            .unwrap();
+            .result()?;
         }
     
-        (acceleration_structure, memory)
+        Ok((acceleration_structure, memory))
     }            
 }
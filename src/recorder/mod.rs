@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use ash::vk;
+use ash::vk::Handle;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, VkQueueError};
+
+// Handles are replaced by symbolic ids assigned in first-seen order, so logs from two runs
+// with the same submission structure compare equal even though the raw handles differ.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubmitRecord {
+    pub command_buffers: Vec<u32>,
+    pub wait_semaphores: Vec<u32>,
+    pub wait_dst_stage_masks: Vec<u32>,
+    pub signal_semaphores: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueSubmitRecord {
+    pub batch: u32,
+    // `None` for the synchronization submit issued after all threads finished.
+    pub thread: Option<u32>,
+    pub submits: Vec<SubmitRecord>,
+    pub fence: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubmissionLog {
+    pub command_buffer_count: u32,
+    pub semaphore_count: u32,
+    pub fence_count: u32,
+    pub queue_submits: Vec<QueueSubmitRecord>,
+}
+
+impl SubmissionLog {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    // Submissions from different threads of one batch may land in any order, so records are
+    // compared after sorting by (batch, thread) and renumbering the ids in that order.
+    pub fn diff(&self, other: &SubmissionLog) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        let ours = self.canonical();
+        let theirs = other.canonical();
+
+        if ours.len() != theirs.len() {
+            differences.push(format!(
+                "queue submit count differs: {} vs {}",
+                ours.len(),
+                theirs.len()
+            ));
+        }
+
+        for (index, (a, b)) in ours.iter().zip(theirs.iter()).enumerate() {
+            if (a.batch, a.thread) != (b.batch, b.thread) {
+                differences.push(format!(
+                    "queue submit {}: origin differs: batch {} thread {:?} vs batch {} thread {:?}",
+                    index, a.batch, a.thread, b.batch, b.thread
+                ));
+                continue;
+            }
+            if a.fence != b.fence {
+                differences.push(format!(
+                    "batch {} thread {:?}: fence differs: {:?} vs {:?}",
+                    a.batch, a.thread, a.fence, b.fence
+                ));
+            }
+            if a.submits.len() != b.submits.len() {
+                differences.push(format!(
+                    "batch {} thread {:?}: submit info count differs: {} vs {}",
+                    a.batch,
+                    a.thread,
+                    a.submits.len(),
+                    b.submits.len()
+                ));
+            }
+            for (submit_index, (x, y)) in a.submits.iter().zip(b.submits.iter()).enumerate() {
+                if x.command_buffers != y.command_buffers {
+                    differences.push(format!(
+                        "batch {} thread {:?} submit {}: command buffers differ: {:?} vs {:?}",
+                        a.batch, a.thread, submit_index, x.command_buffers, y.command_buffers
+                    ));
+                }
+                if x.wait_semaphores != y.wait_semaphores {
+                    differences.push(format!(
+                        "batch {} thread {:?} submit {}: wait semaphores differ: {:?} vs {:?}",
+                        a.batch, a.thread, submit_index, x.wait_semaphores, y.wait_semaphores
+                    ));
+                }
+                if x.signal_semaphores != y.signal_semaphores {
+                    differences.push(format!(
+                        "batch {} thread {:?} submit {}: signal semaphores differ: {:?} vs {:?}",
+                        a.batch, a.thread, submit_index, x.signal_semaphores, y.signal_semaphores
+                    ));
+                }
+                if x.wait_dst_stage_masks != y.wait_dst_stage_masks {
+                    differences.push(format!(
+                        "batch {} thread {:?} submit {}: wait stage masks differ: {:?} vs {:?}",
+                        a.batch,
+                        a.thread,
+                        submit_index,
+                        stage_masks(&x.wait_dst_stage_masks),
+                        stage_masks(&y.wait_dst_stage_masks)
+                    ));
+                }
+            }
+        }
+
+        differences
+    }
+
+    // Re-issues the recorded submissions on `queue`. `command_buffers` is indexed by the symbolic
+    // command buffer id; semaphores and fences are created for the replay and destroyed afterwards.
+    pub fn replay(
+        &self,
+        device: &ash::Device,
+        queue: vk::Queue,
+        command_buffers: &[vk::CommandBuffer],
+    ) -> Result<()> {
+        let mut semaphores = Vec::with_capacity(self.semaphore_count as usize);
+        let mut fences = Vec::with_capacity(self.fence_count as usize);
+        for _ in 0..self.semaphore_count {
+            semaphores.push(unsafe { device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)? });
+        }
+        for _ in 0..self.fence_count {
+            fences.push(unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None)? });
+        }
+
+        let result = self.replay_with(command_buffers, &semaphores, &fences, |submit_infos, fence| unsafe {
+            Ok(device.queue_submit(queue, submit_infos, fence)?)
+        });
+
+        unsafe {
+            device.device_wait_idle()?;
+            for semaphore in semaphores {
+                device.destroy_semaphore(semaphore, None);
+            }
+            for fence in fences {
+                device.destroy_fence(fence, None);
+            }
+        }
+
+        result
+    }
+
+    fn replay_with(
+        &self,
+        command_buffers: &[vk::CommandBuffer],
+        semaphores: &[vk::Semaphore],
+        fences: &[vk::Fence],
+        mut queue_submit: impl FnMut(&[vk::SubmitInfo], vk::Fence) -> Result<()>,
+    ) -> Result<()> {
+        // Everything is resolved up front, so a malformed log fails before anything is submitted.
+        let mut resolved = Vec::with_capacity(self.queue_submits.len());
+        for record in &self.queue_submits {
+            let mut submits = Vec::with_capacity(record.submits.len());
+            for submit in &record.submits {
+                if submit.wait_dst_stage_masks.len() != submit.wait_semaphores.len() {
+                    return Err(VkQueueError::InvalidParameter("every wait semaphore needs a stage mask"));
+                }
+                submits.push((
+                    lookup(command_buffers, &submit.command_buffers, "command buffer id out of range")?,
+                    lookup(semaphores, &submit.wait_semaphores, "semaphore id out of range")?,
+                    stage_masks(&submit.wait_dst_stage_masks),
+                    lookup(semaphores, &submit.signal_semaphores, "semaphore id out of range")?,
+                ));
+            }
+            let fence = match record.fence {
+                Some(id) => lookup(fences, &[id], "fence id out of range")?[0],
+                None => vk::Fence::null(),
+            };
+            resolved.push((submits, fence));
+        }
+
+        for (submits, fence) in &resolved {
+            let submit_infos: Vec<_> = submits
+                .iter()
+                .map(|(command_buffers, wait_semaphores, wait_dst_stage_masks, signal_semaphores)| {
+                    vk::SubmitInfo::builder()
+                        .command_buffers(command_buffers)
+                        .wait_semaphores(wait_semaphores)
+                        .wait_dst_stage_mask(wait_dst_stage_masks)
+                        .signal_semaphores(signal_semaphores)
+                        .build()
+                })
+                .collect();
+
+            queue_submit(&submit_infos, *fence)?;
+        }
+
+        Ok(())
+    }
+
+    // Records sorted by (batch, thread), with every kind of id renumbered in first-seen order.
+    fn canonical(&self) -> Vec<QueueSubmitRecord> {
+        let mut records = self.queue_submits.clone();
+        records.sort_by_key(|record| (record.batch, record.thread.map_or(u32::MAX, |t| t)));
+
+        let mut command_buffers = HashMap::new();
+        let mut semaphores = HashMap::new();
+        let mut fences = HashMap::new();
+        let renumber = |ids: &mut HashMap<u32, u32>, id: &mut u32| {
+            let next = ids.len() as u32;
+            *id = *ids.entry(*id).or_insert(next);
+        };
+        for record in &mut records {
+            for submit in &mut record.submits {
+                for id in &mut submit.command_buffers {
+                    renumber(&mut command_buffers, id);
+                }
+                for id in submit.wait_semaphores.iter_mut().chain(submit.signal_semaphores.iter_mut()) {
+                    renumber(&mut semaphores, id);
+                }
+            }
+            if let Some(id) = &mut record.fence {
+                renumber(&mut fences, id);
+            }
+        }
+        records
+    }
+}
+
+fn lookup<T: Copy>(handles: &[T], ids: &[u32], error: &'static str) -> Result<Vec<T>> {
+    ids.iter()
+        .map(|&id| handles.get(id as usize).copied().ok_or(VkQueueError::InvalidParameter(error)))
+        .collect()
+}
+
+fn stage_masks(masks: &[u32]) -> Vec<vk::PipelineStageFlags> {
+    masks.iter().map(|&mask| vk::PipelineStageFlags::from_raw(mask)).collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum HandleKind {
+    CommandBuffer,
+    Semaphore,
+    Fence,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    log: SubmissionLog,
+    ids: HashMap<(HandleKind, u64), u32>,
+}
+
+impl RecorderState {
+    fn id(&mut self, kind: HandleKind, raw: u64) -> u32 {
+        let log = &mut self.log;
+        *self.ids.entry((kind, raw)).or_insert_with(|| {
+            let counter = match kind {
+                HandleKind::CommandBuffer => &mut log.command_buffer_count,
+                HandleKind::Semaphore => &mut log.semaphore_count,
+                HandleKind::Fence => &mut log.fence_count,
+            };
+            *counter += 1;
+            *counter - 1
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct SubmissionRecorder {
+    enabled: AtomicBool,
+    next_batch: AtomicU32,
+    state: Mutex<RecorderState>,
+}
+
+impl SubmissionRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self) {
+        *self.state.lock().unwrap() = RecorderState::default();
+        self.next_batch.store(0, Ordering::SeqCst);
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stop(&self) -> SubmissionLog {
+        self.enabled.store(false, Ordering::SeqCst);
+        std::mem::take(&mut *self.state.lock().unwrap()).log
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_batch(&self) -> u32 {
+        self.next_batch.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // The pointers inside `submit_infos` must be valid, i.e. this has to be called with the same
+    // infos right before they are handed to vkQueueSubmit.
+    pub(crate) fn record(
+        &self,
+        batch: u32,
+        thread: Option<u32>,
+        submit_infos: &[vk::SubmitInfo],
+        fence: vk::Fence,
+    ) -> Result<()> {
+        if !self.is_recording() {
+            return Ok(());
+        }
+        // A log without stage masks could never be replayed, and the submit is invalid anyway.
+        if submit_infos.iter().any(|info| info.wait_semaphore_count > 0 && info.p_wait_dst_stage_mask.is_null()) {
+            return Err(VkQueueError::InvalidParameter("every wait semaphore needs a stage mask"));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let submits = submit_infos
+            .iter()
+            .map(|info| unsafe {
+                let command_buffers = slice(info.p_command_buffers, info.command_buffer_count);
+                let wait_semaphores = slice(info.p_wait_semaphores, info.wait_semaphore_count);
+                let wait_dst_stage_masks = slice(info.p_wait_dst_stage_mask, info.wait_semaphore_count);
+                let signal_semaphores = slice(info.p_signal_semaphores, info.signal_semaphore_count);
+
+                SubmitRecord {
+                    command_buffers: command_buffers
+                        .iter()
+                        .map(|c| state.id(HandleKind::CommandBuffer, c.as_raw()))
+                        .collect(),
+                    wait_semaphores: wait_semaphores
+                        .iter()
+                        .map(|s| state.id(HandleKind::Semaphore, s.as_raw()))
+                        .collect(),
+                    wait_dst_stage_masks: wait_dst_stage_masks.iter().map(|m| m.as_raw()).collect(),
+                    signal_semaphores: signal_semaphores
+                        .iter()
+                        .map(|s| state.id(HandleKind::Semaphore, s.as_raw()))
+                        .collect(),
+                }
+            })
+            .collect();
+        let fence = (fence != vk::Fence::null()).then(|| state.id(HandleKind::Fence, fence.as_raw()));
+
+        state.log.queue_submits.push(QueueSubmitRecord {
+            batch,
+            thread,
+            submits,
+            fence,
+        });
+        Ok(())
+    }
+}
+
+unsafe fn slice<'a, T>(ptr: *const T, count: u32) -> &'a [T] {
+    if ptr.is_null() || count == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submit(command_buffer: u32, wait: &[u32], signal: &[u32]) -> SubmitRecord {
+        SubmitRecord {
+            command_buffers: vec![command_buffer],
+            wait_semaphores: wait.to_vec(),
+            wait_dst_stage_masks: vec![vk::PipelineStageFlags::ALL_COMMANDS.as_raw(); wait.len()],
+            signal_semaphores: signal.to_vec(),
+        }
+    }
+
+    fn log(records: Vec<(Option<u32>, SubmitRecord, Option<u32>)>) -> SubmissionLog {
+        SubmissionLog {
+            command_buffer_count: 2,
+            semaphore_count: 2,
+            fence_count: 2,
+            queue_submits: records
+                .into_iter()
+                .map(|(thread, submit, fence)| QueueSubmitRecord {
+                    batch: 0,
+                    thread,
+                    submits: vec![submit],
+                    fence,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn thread_order_does_not_matter() {
+        let a = log(vec![
+            (Some(0), submit(0, &[0], &[0]), Some(0)),
+            (Some(1), submit(1, &[1], &[1]), Some(1)),
+        ]);
+        // The same structure with thread 1 having submitted first.
+        let b = log(vec![
+            (Some(1), submit(0, &[0], &[0]), Some(0)),
+            (Some(0), submit(1, &[1], &[1]), Some(1)),
+        ]);
+        assert!(a.diff(&b).is_empty());
+    }
+
+    #[test]
+    fn swapped_semaphores_are_reported() {
+        let a = log(vec![
+            (Some(0), submit(0, &[0], &[0]), Some(0)),
+            (Some(1), submit(1, &[1], &[1]), Some(1)),
+        ]);
+        // Thread 1 waits on what thread 0 signals: same counts, different dependencies.
+        let b = log(vec![
+            (Some(0), submit(0, &[0], &[0]), Some(0)),
+            (Some(1), submit(1, &[0], &[1]), Some(1)),
+        ]);
+        let differences = a.diff(&b);
+        assert_eq!(differences.len(), 1);
+        assert!(differences[0].contains("wait semaphores differ"));
+    }
+
+    #[test]
+    fn recorded_submits_replay() {
+        let command_buffers = [vk::CommandBuffer::from_raw(0x10), vk::CommandBuffer::from_raw(0x11)];
+        let semaphores = [vk::Semaphore::from_raw(0x20), vk::Semaphore::from_raw(0x21)];
+        let masks = [vk::PipelineStageFlags::ALL_COMMANDS; 2];
+        let fence = vk::Fence::from_raw(0x30);
+
+        let recorder = SubmissionRecorder::new();
+        recorder.start();
+        let batch = recorder.begin_batch();
+        for i in 0..2 {
+            let info = vk::SubmitInfo::builder()
+                .command_buffers(&command_buffers[i..=i])
+                .wait_semaphores(&semaphores[i..=i])
+                .wait_dst_stage_mask(&masks[i..=i])
+                .signal_semaphores(&semaphores[i..=i])
+                .build();
+            recorder.record(batch, Some(i as u32), &[info], fence).unwrap();
+        }
+        let sync = vk::SubmitInfo::builder().wait_semaphores(&semaphores).wait_dst_stage_mask(&masks).build();
+        recorder.record(batch, None, &[sync], vk::Fence::null()).unwrap();
+        let recorded = recorder.stop();
+        assert_eq!(recorded.queue_submits[0].submits[0].wait_dst_stage_masks.len(), 1);
+
+        // Replaying onto fresh handles has to reproduce the same structure.
+        let replay_command_buffers = [vk::CommandBuffer::from_raw(0x40), vk::CommandBuffer::from_raw(0x41)];
+        let replay_semaphores = [vk::Semaphore::from_raw(0x50), vk::Semaphore::from_raw(0x51)];
+        let replay_fences = [vk::Fence::from_raw(0x60)];
+        let rerecorder = SubmissionRecorder::new();
+        rerecorder.start();
+        recorded
+            .replay_with(&replay_command_buffers, &replay_semaphores, &replay_fences, |submit_infos, fence| {
+                let record = recorded.queue_submits[rerecorder.state.lock().unwrap().log.queue_submits.len()].clone();
+                rerecorder.record(record.batch, record.thread, submit_infos, fence)
+            })
+            .unwrap();
+        let replayed = rerecorder.stop();
+        assert_eq!(recorded, replayed);
+        assert!(recorded.diff(&replayed).is_empty());
+    }
+
+    #[test]
+    fn missing_stage_masks_are_rejected() {
+        let semaphore = [vk::Semaphore::from_raw(0x20)];
+        let info = vk::SubmitInfo::builder().wait_semaphores(&semaphore).build();
+        let recorder = SubmissionRecorder::new();
+        recorder.start();
+        assert!(recorder.record(0, Some(0), &[info], vk::Fence::null()).is_err());
+        assert!(recorder.stop().queue_submits.is_empty());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let a = log(vec![(None, submit(0, &[0, 1], &[]), Some(0))]);
+        let b = SubmissionLog::from_json(&a.to_json().unwrap()).unwrap();
+        assert_eq!(a, b);
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -20,7 +21,7 @@ impl Eq for Task {}
 
 impl PartialOrd for Task {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        other.priority.partial_cmp(&self.priority)
+        Some(self.cmp(other))
     }
 }
 
@@ -31,7 +32,7 @@ impl Ord for Task {
 }
 
 pub struct TaskManager {
-    next_task_id: TaskId,
+    next_task_id: AtomicUsize,
     task_queue: Arc<Mutex<BinaryHeap<Task>>>,
     task_dependencies: Arc<Mutex<HashMap<TaskId, Vec<TaskId>>>>,
     threads: Vec<thread::JoinHandle<()>>,
@@ -77,7 +78,7 @@ impl TaskManager {
         }
 
         Self {
-            next_task_id: 0,
+            next_task_id: AtomicUsize::new(0),
             task_queue,
             task_dependencies,
             threads,
@@ -85,13 +86,12 @@ impl TaskManager {
     }
 
     pub fn add_task<T: FnOnce() + Send + 'static>(
-        &mut self,
+        &self,
         task: T,
         priority: i32,
         dependencies: &[TaskId],
     ) -> TaskId {
-        let id = self.next_task_id;
-        self.next_task_id += 1;
+        let id = self.next_task_id.fetch_add(1, Ordering::SeqCst);
         self.task_queue.lock().unwrap().push(Task {
             id,
             task: Box::new(task),
@@ -106,7 +106,7 @@ impl TaskManager {
         id
     }
 
-    pub fn cancel_task(&mut self, id: TaskId) -> bool {
+    pub fn cancel_task(&self, id: TaskId) -> bool {
         let mut queue = self.task_queue.lock().unwrap();
         if let Some(position) = queue.iter().position(|t| t.id == id) {
             queue.pop();
@@ -117,7 +117,8 @@ impl TaskManager {
     }
 
     pub fn get_progress(&self) -> (usize, usize) {
-        let completed = self.next_task_id - self.task_queue.lock().unwrap().len();
-        (completed, self.next_task_id)
+        let total = self.next_task_id.load(Ordering::SeqCst);
+        let completed = total - self.task_queue.lock().unwrap().len();
+        (completed, total)
     }
 }
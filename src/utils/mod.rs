@@ -3,6 +3,8 @@ use log::*;
 
 use std::ffi::CStr;
 
+use crate::error::Result;
+
 
 pub struct DebugUtils {
     loader: ash::extensions::ext::DebugUtils,
@@ -13,7 +15,7 @@ impl DebugUtils {
     pub fn new(
         entry: &ash::Entry,
         instance: &ash::Instance,
-    ) -> Self {
+    ) -> Result<Self> {
         let loader = ash::extensions::ext::DebugUtils::new(entry, instance);
 
         let messenger_ci = vk::DebugUtilsMessengerCreateInfoEXT::builder()
@@ -32,11 +34,10 @@ impl DebugUtils {
 
         let messenger = unsafe {
             loader
-                .create_debug_utils_messenger(&messenger_ci, None)
-                .expect("Failed to create debug utils messenger")
+                .create_debug_utils_messenger(&messenger_ci, None)?
         };
 
-        Self { loader, messenger }
+        Ok(Self { loader, messenger })
     }
 }
 
@@ -70,3 +71,16 @@ unsafe extern "system" fn vulkan_debug_callback(
 
     vk::FALSE
 }
+
+// Lists the objects a manager still owned when it was dropped. Debug builds only.
+pub(crate) fn report_leaks<T: std::fmt::Debug>(manager: &str, kind: &str, handles: &[T]) {
+    if cfg!(debug_assertions) && !handles.is_empty() {
+        warn!(
+            "{}: {} {}(s) still alive at drop, destroying: {:?}",
+            manager,
+            handles.len(),
+            kind,
+            handles
+        );
+    }
+}
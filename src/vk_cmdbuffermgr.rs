@@ -2,6 +2,8 @@ use ash::{
     vk,
 };
 
+use crate::error::Result;
+
 pub struct CommandBufferManager {
     device: ash::Device,
     command_pool: vk::CommandPool,
@@ -17,19 +19,19 @@ impl CommandBufferManager {
         }
     }
     
-    pub fn begin_command_buffer(&self, command_buffer: vk::CommandBuffer) {
+    pub fn begin_command_buffer(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
         let begin_info = vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
             .build();
 
         unsafe {
             self.device
-                .begin_command_buffer(command_buffer, &begin_info)
-                .expect("Failed to begin command buffer");
+                .begin_command_buffer(command_buffer, &begin_info)?;
         }
+        Ok(())
     }
 
-    pub fn allocate_command_buffers(&mut self, count: u32) -> &[vk::CommandBuffer] {
+    pub fn allocate_command_buffers(&mut self, count: u32) -> Result<&[vk::CommandBuffer]> {
         let allocate_info = vk::CommandBufferAllocateInfo::builder()
             .command_pool(self.command_pool)
             .level(vk::CommandBufferLevel::PRIMARY)
@@ -38,11 +40,10 @@ impl CommandBufferManager {
 
         self.command_buffers = unsafe {
             self.device
-                .allocate_command_buffers(&allocate_info)
-                .expect("Failed to allocate command buffers")
+                .allocate_command_buffers(&allocate_info)?
         };
 
-        &self.command_buffers
+        Ok(&self.command_buffers)
     }
 
     pub fn free_command_buffers(&mut self) {
@@ -53,11 +54,11 @@ impl CommandBufferManager {
         self.command_buffers.clear();
     }
 
-    pub fn end_command_buffer(&self, command_buffer: vk::CommandBuffer) {
+    pub fn end_command_buffer(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
         unsafe {
             self.device
-                .end_command_buffer(command_buffer)
-                .expect("Failed to end command buffer");
+                .end_command_buffer(command_buffer)?;
         }
+        Ok(())
     }
 }
@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use ash::vk;
+
+use crate::allocator::Allocation;
+use crate::error::Result;
+use crate::vk_memorymgr::MemoryManager;
+
+#[derive(Debug)]
+pub enum Deletion {
+    Buffer(vk::Buffer, Allocation),
+    Image(vk::Image, Allocation),
+    ImageView(vk::ImageView),
+    BufferView(vk::BufferView),
+    Pipeline(vk::Pipeline),
+    PipelineLayout(vk::PipelineLayout),
+    DescriptorPool(vk::DescriptorPool),
+    // Memory from `MemoryManager::allocate_memory`.
+    Memory(vk::DeviceMemory),
+}
+
+// The GPU work an object has to outlive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetireAfter {
+    Frame(u64),
+    Timeline(vk::Semaphore, u64),
+}
+
+// Holds on to objects that command buffers may still reference until the GPU work they were
+// retired after is known to be complete. `FrameManager::begin_frame` drives the frames; without
+// a swapchain the owner calls `begin_frame` and `collect` itself, or nothing is ever destroyed.
+pub struct DeletionQueue {
+    device: ash::Device,
+    memory_manager: MemoryManager,
+    frame: AtomicU64,
+    pending: Mutex<Vec<(RetireAfter, Deletion)>>,
+}
+
+impl DeletionQueue {
+    pub fn new(device: ash::Device, memory_manager: MemoryManager) -> Self {
+        Self {
+            device,
+            memory_manager,
+            frame: AtomicU64::new(0),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame.load(Ordering::Acquire)
+    }
+
+    // Objects retired from now on wait for `frame` to complete. Frames only move forward.
+    pub fn begin_frame(&self, frame: u64) {
+        self.frame.fetch_max(frame, Ordering::AcqRel);
+    }
+
+    // Retires after the frame currently being recorded.
+    pub fn retire(&self, deletion: Deletion) {
+        self.retire_after(RetireAfter::Frame(self.frame()), deletion);
+    }
+
+    pub fn retire_after(&self, after: RetireAfter, deletion: Deletion) {
+        self.pending.lock().unwrap().push((after, deletion));
+    }
+
+    // Destroys everything retired after frames up to and including `completed_frame`.
+    pub fn collect(&self, completed_frame: u64) {
+        self.collect_where(|after| matches!(after, RetireAfter::Frame(frame) if frame <= completed_frame));
+    }
+
+    // Destroys everything waiting on `semaphore` reaching a value up to `completed_value`.
+    pub fn collect_timeline(&self, semaphore: vk::Semaphore, completed_value: u64) {
+        self.collect_where(|after| {
+            matches!(after, RetireAfter::Timeline(waited, value) if waited == semaphore && value <= completed_value)
+        });
+    }
+
+    // Reads the counter of every timeline semaphore with pending objects. Needs the
+    // timelineSemaphore feature.
+    pub fn poll_timelines(&self) -> Result<()> {
+        let mut semaphores: Vec<_> = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(after, _)| match *after {
+                RetireAfter::Timeline(semaphore, _) => Some(semaphore),
+                RetireAfter::Frame(_) => None,
+            })
+            .collect();
+        semaphores.sort_unstable();
+        semaphores.dedup();
+
+        for semaphore in semaphores {
+            let value = unsafe { self.device.get_semaphore_counter_value(semaphore)? };
+            self.collect_timeline(semaphore, value);
+        }
+        Ok(())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    // Waits for the device and destroys everything, whatever it was waiting on.
+    pub fn flush(&self) {
+        if self.pending() == 0 {
+            return;
+        }
+        unsafe {
+            let _ = self.device.device_wait_idle();
+        }
+        self.destroy_pending();
+    }
+
+    // Destroys everything without waiting, for when the device is known to be idle.
+    pub(crate) fn destroy_pending(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (_, deletion) in pending {
+            self.destroy(deletion);
+        }
+    }
+
+    fn collect_where(&self, is_complete: impl Fn(RetireAfter) -> bool) {
+        // Destroyed outside the lock, so `destroy` can take the allocator locks.
+        let ready: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            let (ready, waiting) = std::mem::take(&mut *pending)
+                .into_iter()
+                .partition(|(after, _)| is_complete(*after));
+            *pending = waiting;
+            ready
+        };
+        for (_, deletion) in ready {
+            self.destroy(deletion);
+        }
+    }
+
+    fn destroy(&self, deletion: Deletion) {
+        unsafe {
+            match deletion {
+                Deletion::Buffer(buffer, allocation) => {
+                    self.device.destroy_buffer(buffer, None);
+                    self.memory_manager.free(allocation);
+                }
+                Deletion::Image(image, allocation) => {
+                    self.device.destroy_image(image, None);
+                    self.memory_manager.free(allocation);
+                }
+                Deletion::ImageView(view) => self.device.destroy_image_view(view, None),
+                Deletion::BufferView(view) => self.device.destroy_buffer_view(view, None),
+                Deletion::Pipeline(pipeline) => self.device.destroy_pipeline(pipeline, None),
+                Deletion::PipelineLayout(layout) => self.device.destroy_pipeline_layout(layout, None),
+                Deletion::DescriptorPool(pool) => self.device.destroy_descriptor_pool(pool, None),
+                Deletion::Memory(memory) => self.memory_manager.free_memory(memory),
+            }
+        }
+    }
+}
+
+// Like the managers, the queue expects its owner to have waited for the device.
+impl Drop for DeletionQueue {
+    fn drop(&mut self) {
+        self.destroy_pending();
+    }
+}
@@ -1,5 +1,7 @@
 use ash::vk;
 
+use crate::error::Result;
+
 pub struct DescriptorManager {
     device: ash::Device,
 }
@@ -13,37 +15,37 @@ impl DescriptorManager {
         &self,
         max_sets: u32,
         pool_sizes: &[vk::DescriptorPoolSize],
-    ) -> vk::DescriptorPool {
+    ) -> Result<vk::DescriptorPool> {
         let pool_info = vk::DescriptorPoolCreateInfo::builder()
             .pool_sizes(pool_sizes)
             .max_sets(max_sets)
             .build();
 
-        unsafe { self.device.create_descriptor_pool(&pool_info, None).unwrap() }
+        Ok(unsafe { self.device.create_descriptor_pool(&pool_info, None)? })
     }
 
     pub fn create_descriptor_set_layout(
         &self,
         bindings: &[vk::DescriptorSetLayoutBinding],
-    ) -> vk::DescriptorSetLayout {
+    ) -> Result<vk::DescriptorSetLayout> {
         let layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
             .bindings(bindings)
             .build();
 
-        unsafe { self.device.create_descriptor_set_layout(&layout_info, None).unwrap() }
+        Ok(unsafe { self.device.create_descriptor_set_layout(&layout_info, None)? })
     }
 
     pub fn create_descriptor_sets(
         &self,
         pool: vk::DescriptorPool,
         layouts: &[vk::DescriptorSetLayout],
-    ) -> Vec<vk::DescriptorSet> {
+    ) -> Result<Vec<vk::DescriptorSet>> {
         let allocate_info = vk::DescriptorSetAllocateInfo::builder()
             .descriptor_pool(pool)
             .set_layouts(layouts)
             .build();
 
-        unsafe { self.device.allocate_descriptor_sets(&allocate_info).unwrap() }
+        Ok(unsafe { self.device.allocate_descriptor_sets(&allocate_info)? })
     }
 
     pub fn update_descriptor_sets(&self, writes: &[vk::WriteDescriptorSet]) {
@@ -1,3 +1,8 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::vk_deletion::DeletionQueue;
 use crate::vk_swapchain::Swapchain;
 
 use ash::{
@@ -14,7 +19,11 @@ pub struct FrameManager {
     present_mode: vk::PresentModeKHR,
     surface_format: vk::SurfaceFormatKHR,
     extent: vk::Extent2D,
-    swapchain_module: std::rc::Rc<Swapchain>,
+    swapchain_module: Arc<Swapchain>,
+    deletion_queue: Arc<DeletionQueue>,
+    frame: u64,
+    // The last frame submitted with each fence passed to `begin_frame`.
+    frame_fences: HashMap<vk::Fence, u64>,
 }
 
 impl FrameManager {
@@ -25,8 +34,8 @@ impl FrameManager {
         present_mode: vk::PresentModeKHR,
         surface_format: vk::SurfaceFormatKHR,
         extent: vk::Extent2D,
-        swapchain_module: std::rc::Rc<Swapchain>,
-
+        swapchain_module: Arc<Swapchain>,
+        deletion_queue: Arc<DeletionQueue>,
     ) -> Self {
         Self {
             device,
@@ -36,7 +45,35 @@ impl FrameManager {
             surface_format,
             extent,
             swapchain_module,
+            deletion_queue,
+            frame: 0,
+            frame_fences: HashMap::new(),
+        }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Starts a new frame whose last submission will signal `fence`, one of the frames-in-flight
+    // fences. Waits for the frame that last used `fence` and resets it, then destroys what the
+    // deletion queue held for that frame and everything before it. Assumes the frames are
+    // submitted to one queue, so they complete in order.
+    pub fn begin_frame(&mut self, fence: vk::Fence) -> Result<u64> {
+        if let Some(&previous) = self.frame_fences.get(&fence) {
+            unsafe {
+                self.device.wait_for_fences(&[fence], true, u64::MAX)?;
+            }
+            self.deletion_queue.collect(previous);
+        }
+        unsafe {
+            self.device.reset_fences(&[fence])?;
         }
+
+        self.frame += 1;
+        self.frame_fences.insert(fence, self.frame);
+        self.deletion_queue.begin_frame(self.frame);
+        Ok(self.frame)
     }
 
     pub fn recreate_swapchain(
@@ -48,12 +85,13 @@ impl FrameManager {
         window_width: u32,
         window_height: u32,
         surface_loader: &khr::Surface,
-    ) {
+    ) -> Result<()> {
         unsafe {
-            device.device_wait_idle().unwrap();
+            device.device_wait_idle()?;
         }
 
-        self.swapchain_module = std::rc::Rc::new(Swapchain::new(
+        // The device is idle, so the retired swapchain can go as soon as nobody else holds it.
+        self.swapchain_module = Arc::new(self.swapchain_module.recreate(
             instance,
             device,
             physical_device,
@@ -61,20 +99,25 @@ impl FrameManager {
             window_width,
             window_height,
             surface_loader,
-        ));
+        )?);
 
         self.swapchain = self.swapchain_module.handle;
         self.extent = self.swapchain_module.extent;
+        Ok(())
+    }
+
+    pub fn swapchain(&self) -> &Arc<Swapchain> {
+        &self.swapchain_module
     }
 
     pub fn acquire_next_image(
         &self,
         image_available_semaphore: vk::Semaphore,
-    ) -> Result<(u32, bool), vk::Result> {
+    ) -> Result<(u32, bool)> {
         let (image_index, suboptimal) = unsafe {
             self.swapchain_loader.acquire_next_image(
                 self.swapchain,
-                std::u64::MAX,
+                u64::MAX,
                 image_available_semaphore,
                 vk::Fence::null(),
             )?
@@ -88,7 +131,7 @@ impl FrameManager {
         queue: vk::Queue,
         image_index: u32,
         wait_semaphores: &[vk::Semaphore],
-    ) -> Result<bool, vk::Result> {
+    ) -> Result<bool> {
         let present_info = vk::PresentInfoKHR::builder()
             .wait_semaphores(wait_semaphores)
             .swapchains(&[self.swapchain])
@@ -100,7 +143,7 @@ impl FrameManager {
         match result {
             Ok(is_suboptimal) => Ok(is_suboptimal),
             Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(true),
-            Err(error) => Err(error),
+            Err(error) => Err(error.into()),
         }
     }
 }
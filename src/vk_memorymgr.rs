@@ -1,94 +1,668 @@
-use ash::vk;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use ash::{extensions::khr, vk};
+use bytemuck::Pod;
+
+use crate::allocator::{
+    atom_range, Allocation, AllocationKind, AllocationRange, BlockAllocator, BudgetCheck, DefragmentationMove, BudgetPolicy,
+    LinearAllocator, MappedRange, MappedSlice, MappedSliceMut, MemoryStats, MemoryTracker, PoolAllocator, RingAllocator,
+};
+use crate::error::{Result, VkQueueError};
+use crate::memtype::{self, MemoryRequest};
+use crate::utils::report_leaks;
+use log::warn;
+
+// Host-visible memory is mapped once, right after it is allocated, and stays mapped until freed.
+struct PersistentMapping {
+    pointer: *mut u8,
+    size: vk::DeviceSize,
+    coherent: bool,
+}
+
+// The pointer is only handed out through MappedSlice views, which borrow the allocation or arena.
+unsafe impl Send for PersistentMapping {}
+unsafe impl Sync for PersistentMapping {}
+
+// Shared between all clones of a MemoryManager; the blocks are freed when the last clone goes away.
+struct MemoryBlocks {
+    device: ash::Device,
+    allocator: Mutex<BlockAllocator>,
+    tracker: Mutex<MemoryTracker>,
+    mappings: Mutex<HashMap<vk::DeviceMemory, PersistentMapping>>,
+    // Memory handed out by `allocate_memory`, the only kind `free_memory` accepts.
+    raw_memory: Mutex<HashSet<vk::DeviceMemory>>,
+}
+
+impl Drop for MemoryBlocks {
+    fn drop(&mut self) {
+        let allocator = self.allocator.get_mut().unwrap();
+        report_leaks("MemoryManager", "allocation", &allocator.live_allocations());
+        for memory in allocator.drain_blocks() {
+            unsafe { self.device.free_memory(memory, None) };
+        }
+    }
+}
+
+// A single vk::DeviceMemory handed out by one of the specialized strategies. Only the allocator
+// can be changed, so views stay inside the memory the arena owns.
+pub struct MemoryArena<A> {
+    memory: vk::DeviceMemory,
+    memory_type_index: u32,
+    size: vk::DeviceSize,
+    pub allocator: A,
+}
+
+impl<A> MemoryArena<A> {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn memory_type_index(&self) -> u32 {
+        self.memory_type_index
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+}
+
+// How requirements are queried when the driver may ask for dedicated memory: core in Vulkan 1.1,
+// through VK_KHR_get_memory_requirements2 before it.
+#[derive(Clone)]
+enum DedicatedQuery {
+    Core,
+    Khr(khr::GetMemoryRequirements2),
+}
+
+impl DedicatedQuery {
+    unsafe fn buffer_requirements(
+        &self,
+        device: &ash::Device,
+        info: &vk::BufferMemoryRequirementsInfo2,
+        requirements: &mut vk::MemoryRequirements2,
+    ) {
+        match self {
+            DedicatedQuery::Core => device.get_buffer_memory_requirements2(info, requirements),
+            DedicatedQuery::Khr(loader) => loader.get_buffer_memory_requirements2(info, requirements),
+        }
+    }
+
+    unsafe fn image_requirements(
+        &self,
+        device: &ash::Device,
+        info: &vk::ImageMemoryRequirementsInfo2,
+        requirements: &mut vk::MemoryRequirements2,
+    ) {
+        match self {
+            DedicatedQuery::Core => device.get_image_memory_requirements2(info, requirements),
+            DedicatedQuery::Khr(loader) => loader.get_image_memory_requirements2(info, requirements),
+        }
+    }
+}
+
+// How VkPhysicalDeviceMemoryBudgetPropertiesEXT is read: core in Vulkan 1.1, through
+// VK_KHR_get_physical_device_properties2 before it.
+#[derive(Clone)]
+enum BudgetQuery {
+    Core(ash::Instance),
+    Khr(khr::GetPhysicalDeviceProperties2),
+}
+
+impl BudgetQuery {
+    unsafe fn memory_properties(&self, physical_device: vk::PhysicalDevice, properties: &mut vk::PhysicalDeviceMemoryProperties2) {
+        match self {
+            BudgetQuery::Core(instance) => instance.get_physical_device_memory_properties2(physical_device, properties),
+            BudgetQuery::Khr(loader) => loader.get_physical_device_memory_properties2(physical_device, properties),
+        }
+    }
+}
 
 pub struct MemoryManager {
     device: ash::Device,
     memory_prop: vk::PhysicalDeviceMemoryProperties,
+    blocks: Arc<MemoryBlocks>,
+    buffer_image_granularity: vk::DeviceSize,
+    non_coherent_atom_size: vk::DeviceSize,
+    // Set when VK_EXT_memory_budget is enabled on the device and can be queried.
+    budget_query: Option<(BudgetQuery, vk::PhysicalDevice)>,
+    // Without it only the dedicated threshold decides.
+    dedicated_query: Option<DedicatedQuery>,
 }
 
 impl MemoryManager {
     pub fn new(
         device: ash::Device,
         memory_prop: vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
     ) -> Self {
+        let blocks = Arc::new(MemoryBlocks {
+            device: device.clone(),
+            allocator: Mutex::new(BlockAllocator::new(&memory_prop, limits.buffer_image_granularity)),
+            tracker: Mutex::new(MemoryTracker::new(&memory_prop)),
+            mappings: Mutex::new(HashMap::new()),
+            raw_memory: Mutex::new(HashSet::new()),
+        });
         Self {
             device,
             memory_prop,
+            blocks,
+            buffer_image_granularity: limits.buffer_image_granularity,
+            non_coherent_atom_size: limits.non_coherent_atom_size,
+            budget_query: None,
+            dedicated_query: None,
+        }
+    }
+
+    // Lets the driver ask for dedicated memory through VkMemoryDedicatedRequirements. That needs
+    // `api_version` (the lower of the instance's and the device's) to be at least 1.1, or
+    // VK_KHR_dedicated_allocation and VK_KHR_get_memory_requirements2 enabled on the device.
+    pub fn with_dedicated_allocation(mut self, instance: &ash::Instance, api_version: u32, extensions_enabled: bool) -> Self {
+        self.dedicated_query = if api_version >= vk::API_VERSION_1_1 {
+            Some(DedicatedQuery::Core)
+        } else if extensions_enabled {
+            Some(DedicatedQuery::Khr(khr::GetMemoryRequirements2::new(instance, &self.device)))
+        } else {
+            None
+        };
+        self
+    }
+
+    // Requires VK_EXT_memory_budget to be enabled on the device. Reading it also needs `api_version`
+    // to be at least 1.1, or VK_KHR_get_physical_device_properties2 enabled on the instance;
+    // without either the budget query stays off.
+    pub fn with_budget_query(
+        mut self,
+        entry: &ash::Entry,
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        api_version: u32,
+        properties2_enabled: bool,
+    ) -> Self {
+        let query = if api_version >= vk::API_VERSION_1_1 {
+            Some(BudgetQuery::Core(instance.clone()))
+        } else if properties2_enabled {
+            Some(BudgetQuery::Khr(khr::GetPhysicalDeviceProperties2::new(entry, instance)))
+        } else {
+            None
+        };
+        self.budget_query = query.map(|query| (query, physical_device));
+        self.update_budget();
+        self
+    }
+
+    // Limits apply to the bytes this manager allocates from the heap, on top of whatever
+    // VK_EXT_memory_budget reports.
+    pub fn set_heap_budget(&self, heap_index: u32, budget: Option<vk::DeviceSize>) {
+        self.blocks.tracker.lock().unwrap().set_heap_limit(heap_index, budget);
+    }
+
+    pub fn set_budget_policy(&self, policy: BudgetPolicy) {
+        self.blocks.tracker.lock().unwrap().set_policy(policy);
+    }
+
+    // Re-reads what the driver reports. Only `stats()` does this on its own; in between, the
+    // tracker adds this manager's allocations on top of the last report, so the checks on
+    // allocation don't need a fresh query.
+    pub fn update_budget(&self) {
+        let (query, physical_device) = match &self.budget_query {
+            Some(query) => query,
+            None => return,
+        };
+
+        let mut budget_properties = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties2::builder().push_next(&mut budget_properties);
+        unsafe { query.memory_properties(*physical_device, &mut memory_properties) };
+
+        let heap_count = self.memory_prop.memory_heap_count as usize;
+        self.blocks.tracker.lock().unwrap().set_reported_budget(
+            &budget_properties.heap_budget[..heap_count],
+            &budget_properties.heap_usage[..heap_count],
+        );
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        self.update_budget();
+        let allocator = self.blocks.allocator.lock().unwrap();
+        let tracker = self.blocks.tracker.lock().unwrap();
+        tracker.stats(|memory_type_index| allocator.usage(memory_type_index).0)
+    }
+
+    pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+        &self.memory_prop
+    }
+
+    pub fn buffer_image_granularity(&self) -> vk::DeviceSize {
+        self.buffer_image_granularity
+    }
+
+    // Carves the allocation out of a shared block instead of calling vkAllocateMemory per resource.
+    pub fn allocate(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        memory_usage: impl Into<MemoryRequest>,
+        kind: AllocationKind,
+    ) -> Result<Allocation> {
+        let memory_type_index = self.memory_type_for(requirements, memory_usage.into())?;
+
+        self.blocks.allocator.lock().unwrap().allocate(
+            memory_type_index,
+            requirements,
+            kind,
+            |block_size| self.allocate_memory_with(block_size, memory_type_index, None),
+        )
+    }
+
+    // Resources above the threshold get a memory object of their own. None restores the default
+    // of half the block size; the driver's preference for dedicated memory is always honored.
+    pub fn set_dedicated_threshold(&self, threshold: Option<vk::DeviceSize>) {
+        self.blocks.allocator.lock().unwrap().set_dedicated_threshold(threshold);
+    }
+
+    fn memory_type_for(&self, requirements: &vk::MemoryRequirements, request: MemoryRequest) -> Result<u32> {
+        self.find_memory_type_index(requirements.memory_type_bits, request)
+            .ok_or(VkQueueError::NoSuitableMemoryType {
+                memory_type_bits: requirements.memory_type_bits,
+                properties: request.required,
+            })
+    }
+
+    fn allocate_for_resource(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        dedicated_requirements: &vk::MemoryDedicatedRequirements,
+        request: MemoryRequest,
+        kind: AllocationKind,
+        dedicated_info: vk::MemoryDedicatedAllocateInfo,
+    ) -> Result<Allocation> {
+        let memory_type_index = self.memory_type_for(requirements, request)?;
+        let driver_prefers_dedicated = dedicated_requirements.prefers_dedicated_allocation == vk::TRUE
+            || dedicated_requirements.requires_dedicated_allocation == vk::TRUE;
+
+        // VkMemoryDedicatedAllocateInfo comes with the same version or extension as the query.
+        let dedicated_info = self.dedicated_query.is_some().then_some(dedicated_info);
+        let mut allocator = self.blocks.allocator.lock().unwrap();
+        if allocator.wants_dedicated(memory_type_index, requirements.size, driver_prefers_dedicated) {
+            allocator.allocate_dedicated(memory_type_index, requirements, kind, |size| {
+                self.allocate_memory_with(size, memory_type_index, dedicated_info)
+            })
+        } else {
+            allocator.allocate(memory_type_index, requirements, kind, |block_size| {
+                self.allocate_memory_with(block_size, memory_type_index, None)
+            })
+        }
+    }
+
+    // The caller must make sure the GPU is done with the range.
+    pub fn free(&self, allocation: Allocation) {
+        let released = self.blocks.allocator.lock().unwrap().free(allocation);
+        if let Some(memory) = released {
+            self.release_memory(memory);
+        }
+    }
+
+    // See BlockAllocator::plan_defragmentation. The sources are freed like any other allocation
+    // once the resources have moved.
+    pub fn plan_defragmentation(
+        &self,
+        max_bytes: vk::DeviceSize,
+        movable: impl Fn(&AllocationRange) -> bool,
+    ) -> Vec<DefragmentationMove> {
+        self.blocks.allocator.lock().unwrap().plan_defragmentation(max_bytes, movable)
+    }
+
+    pub fn allocate_for_buffer(&self, buffer: vk::Buffer, memory_usage: impl Into<MemoryRequest>) -> Result<Allocation> {
+        let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+        let requirements = match &self.dedicated_query {
+            Some(query) => {
+                let mut requirements = vk::MemoryRequirements2::builder().push_next(&mut dedicated_requirements);
+                let info = vk::BufferMemoryRequirementsInfo2::builder().buffer(buffer);
+                unsafe { query.buffer_requirements(&self.device, &info, &mut requirements) };
+                requirements.memory_requirements
+            }
+            None => unsafe { self.device.get_buffer_memory_requirements(buffer) },
+        };
+
+        let dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().buffer(buffer).build();
+        let allocation = self.allocate_for_resource(
+            &requirements,
+            &dedicated_requirements,
+            memory_usage.into(),
+            AllocationKind::Linear,
+            dedicated_info,
+        )?;
+        if let Err(error) = unsafe { self.device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) } {
+            self.free(allocation);
+            return Err(error.into());
         }
+        Ok(allocation)
     }
 
+    pub fn allocate_for_image(
+        &self,
+        image: vk::Image,
+        tiling: vk::ImageTiling,
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<Allocation> {
+        let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+        let requirements = match &self.dedicated_query {
+            Some(query) => {
+                let mut requirements = vk::MemoryRequirements2::builder().push_next(&mut dedicated_requirements);
+                let info = vk::ImageMemoryRequirementsInfo2::builder().image(image);
+                unsafe { query.image_requirements(&self.device, &info, &mut requirements) };
+                requirements.memory_requirements
+            }
+            None => unsafe { self.device.get_image_memory_requirements(image) },
+        };
+
+        let kind = if tiling == vk::ImageTiling::LINEAR {
+            AllocationKind::Linear
+        } else {
+            AllocationKind::Optimal
+        };
+        let dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image).build();
+        let allocation =
+            self.allocate_for_resource(&requirements, &dedicated_requirements, memory_usage.into(), kind, dedicated_info)?;
+        if let Err(error) = unsafe { self.device.bind_image_memory(image, allocation.memory, allocation.offset) } {
+            self.free(allocation);
+            return Err(error.into());
+        }
+        Ok(allocation)
+    }
+
+    pub fn create_linear_arena(
+        &self,
+        size: vk::DeviceSize,
+        memory_type_bits: u32,
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<MemoryArena<LinearAllocator>> {
+        self.create_arena(size, memory_type_bits, memory_usage.into(), LinearAllocator::new(size))
+    }
+
+    pub fn create_ring_arena(
+        &self,
+        size: vk::DeviceSize,
+        memory_type_bits: u32,
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<MemoryArena<RingAllocator>> {
+        self.create_arena(size, memory_type_bits, memory_usage.into(), RingAllocator::new(size))
+    }
+
+    pub fn create_pool_arena(
+        &self,
+        slot_size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        slot_count: u32,
+        memory_type_bits: u32,
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<MemoryArena<PoolAllocator>> {
+        let size = PoolAllocator::required_size(slot_size, alignment, slot_count);
+        self.create_arena(size, memory_type_bits, memory_usage.into(), PoolAllocator::new(slot_size, alignment, slot_count))
+    }
+
+    fn create_arena<A>(
+        &self,
+        size: vk::DeviceSize,
+        memory_type_bits: u32,
+        request: MemoryRequest,
+        allocator: A,
+    ) -> Result<MemoryArena<A>> {
+        if size == 0 {
+            return Err(VkQueueError::InvalidParameter("arena size must be non-zero"));
+        }
+        let memory_type_index = self
+            .find_memory_type_index(memory_type_bits, request)
+            .ok_or(VkQueueError::NoSuitableMemoryType {
+                memory_type_bits,
+                properties: request.required,
+            })?;
+
+        Ok(MemoryArena {
+            memory: self.allocate_memory_with(size, memory_type_index, None)?,
+            memory_type_index,
+            size,
+            allocator,
+        })
+    }
+
+    // Releases ring allocations whose fences have signaled.
+    pub fn reclaim_ring(&self, arena: &mut MemoryArena<RingAllocator>) {
+        arena
+            .allocator
+            .reclaim(|fence| unsafe { self.device.get_fence_status(fence) }.unwrap_or(false));
+    }
+
+    // The caller must make sure the GPU is done with everything placed in the arena.
+    pub fn destroy_arena<A>(&self, arena: MemoryArena<A>) {
+        self.release_memory(arena.memory);
+    }
+
+    // Writes `data` at `offset` bytes into the allocation.
+    pub fn copy_to_allocation<T: Pod>(&self, data: &[T], allocation: &mut Allocation, offset: vk::DeviceSize) -> Result<()> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let size = bytes.len() as vk::DeviceSize;
+        if offset.checked_add(size).is_none_or(|end| end > allocation.size) {
+            return Err(VkQueueError::InvalidParameter("data does not fit into the allocation"));
+        }
+
+        let (pointer, range) = self.mapped_range(allocation.memory, allocation.offset + offset, size)?;
+        let mut view = MappedSliceMut::new(&self.device, range, unsafe {
+            std::slice::from_raw_parts_mut(pointer, bytes.len())
+        });
+        view.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    // As many `T`s as fit into the allocation.
+    pub fn mapped_slice<'a, T: Pod>(&'a self, allocation: &'a Allocation) -> Result<MappedSlice<'a, T>> {
+        let (pointer, len, range) = self.typed_range::<T>(allocation.memory, allocation.offset, allocation.size)?;
+        MappedSlice::new(&self.device, range, unsafe { std::slice::from_raw_parts(pointer, len) })
+    }
+
+    // Takes the allocation mutably so there is only ever one writable view of it.
+    pub fn mapped_slice_mut<'a, T: Pod>(&'a self, allocation: &'a mut Allocation) -> Result<MappedSliceMut<'a, T>> {
+        let (pointer, len, range) = self.typed_range::<T>(allocation.memory, allocation.offset, allocation.size)?;
+        Ok(MappedSliceMut::new(&self.device, range, unsafe {
+            std::slice::from_raw_parts_mut(pointer, len)
+        }))
+    }
+
+    // `offset` and `len` usually come from the arena's allocator; `len` counts `T`s.
+    pub fn arena_slice_mut<'a, A, T: Pod>(
+        &'a self,
+        arena: &'a mut MemoryArena<A>,
+        offset: vk::DeviceSize,
+        len: usize,
+    ) -> Result<MappedSliceMut<'a, T>> {
+        let size = len
+            .checked_mul(std::mem::size_of::<T>())
+            .map(|size| size as vk::DeviceSize)
+            .filter(|&size| offset.checked_add(size).is_some_and(|end| end <= arena.size))
+            .ok_or(VkQueueError::InvalidParameter("range lies outside of the arena"))?;
+        let (pointer, len, range) = self.typed_range::<T>(arena.memory, offset, size)?;
+        Ok(MappedSliceMut::new(&self.device, range, unsafe {
+            std::slice::from_raw_parts_mut(pointer, len)
+        }))
+    }
+
+    fn typed_range<T: Pod>(
+        &self,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) -> Result<(*mut T, usize, MappedRange)> {
+        let element_size = std::mem::size_of::<T>().max(1) as vk::DeviceSize;
+        let len = size / element_size;
+        let (pointer, range) = self.mapped_range(memory, offset, len * element_size)?;
+        if !(pointer as usize).is_multiple_of(std::mem::align_of::<T>()) {
+            return Err(VkQueueError::InvalidParameter("mapped memory is not aligned for the element type"));
+        }
+        Ok((pointer as *mut T, len as usize, range))
+    }
+
+    fn mapped_range(
+        &self,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) -> Result<(*mut u8, MappedRange)> {
+        let mappings = self.blocks.mappings.lock().unwrap();
+        let mapping = mappings
+            .get(&memory)
+            .ok_or(VkQueueError::InvalidParameter("memory is not host visible"))?;
+        if offset.checked_add(size).is_none_or(|end| end > mapping.size) {
+            return Err(VkQueueError::InvalidParameter("range lies outside of the memory object"));
+        }
+
+        let range = (!mapping.coherent).then(|| atom_range(offset, size, self.non_coherent_atom_size, mapping.size));
+        let pointer = unsafe { mapping.pointer.add(offset as usize) };
+        Ok((pointer, MappedRange { memory, range }))
+    }
+
+    // A memory object of its own, outside the blocks. Release it with `free_memory`.
     pub fn allocate_memory(
         &self,
         allocation_size: vk::DeviceSize,
         memory_type_index: u32,
-    ) -> vk::DeviceMemory {
-        let alloc_info = vk::MemoryAllocateInfo::builder()
+    ) -> Result<vk::DeviceMemory> {
+        let memory = self.allocate_memory_with(allocation_size, memory_type_index, None)?;
+        self.blocks.raw_memory.lock().unwrap().insert(memory);
+        Ok(memory)
+    }
+
+    fn allocate_memory_with(
+        &self,
+        allocation_size: vk::DeviceSize,
+        memory_type_index: u32,
+        mut dedicated_info: Option<vk::MemoryDedicatedAllocateInfo>,
+    ) -> Result<vk::DeviceMemory> {
+        let mut tracker = self.blocks.tracker.lock().unwrap();
+        if let BudgetCheck::OverBudget {
+            heap_index,
+            requested,
+            budget,
+        } = tracker.check(memory_type_index, allocation_size)
+        {
+            if tracker.policy() == BudgetPolicy::Refuse {
+                return Err(VkQueueError::BudgetExceeded {
+                    heap_index,
+                    requested,
+                    budget,
+                });
+            }
+            warn!(
+                "Allocating {} bytes exceeds the {} byte budget of heap {}",
+                requested, budget, heap_index
+            );
+        }
+
+        let mut alloc_info = vk::MemoryAllocateInfo::builder()
             .allocation_size(allocation_size)
-            .memory_type_index(memory_type_index)
-            .build();
+            .memory_type_index(memory_type_index);
+        if let Some(dedicated_info) = dedicated_info.as_mut() {
+            alloc_info = alloc_info.push_next(dedicated_info);
+        }
 
-        unsafe {
+        let memory = unsafe {
             self.device
-                .allocate_memory(&alloc_info, None)
-                .expect("Failed to allocate memory")
+                .allocate_memory(&alloc_info, None)?
+        };
+        tracker.record_allocate(memory, memory_type_index, allocation_size);
+
+        let flags = self.memory_prop.memory_types[memory_type_index as usize].property_flags;
+        if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            let pointer = match unsafe { self.device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()) } {
+                Ok(pointer) => pointer as *mut u8,
+                Err(error) => {
+                    drop(tracker);
+                    self.release_memory(memory);
+                    return Err(error.into());
+                }
+            };
+            self.blocks.mappings.lock().unwrap().insert(
+                memory,
+                PersistentMapping {
+                    pointer,
+                    size: allocation_size,
+                    coherent: flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT),
+                },
+            );
         }
+        Ok(memory)
     }
 
+    // Only takes memory from `allocate_memory`; blocks and arenas are released through `free` and
+    // `destroy_arena`, which know whether anything still lives in them.
     pub fn free_memory(&self, memory: vk::DeviceMemory) {
+        if self.blocks.raw_memory.lock().unwrap().remove(&memory) {
+            self.release_memory(memory);
+        } else {
+            warn!("Ignoring free_memory of {:?}, which didn't come from allocate_memory", memory);
+        }
+    }
+
+    // Unchecked: the caller owns `memory` and nothing refers to it any more. Freeing implicitly
+    // unmaps a persistently mapped object.
+    pub(crate) fn release_memory(&self, memory: vk::DeviceMemory) {
+        self.blocks.mappings.lock().unwrap().remove(&memory);
+        self.blocks.tracker.lock().unwrap().record_free(memory);
         unsafe {
             self.device.free_memory(memory, None);
         }
     }
 
+    // Dereferencing the pointer is up to the caller; prefer the MappedSlice views.
     pub fn map_memory<T>(
         &self,
         memory: vk::DeviceMemory,
         offset: vk::DeviceSize,
         size: vk::DeviceSize,
-    ) -> *mut T {
-        unsafe {
-            self.device
-                .map_memory(memory, offset, size, vk::MemoryMapFlags::empty())
-                .expect("Failed to map memory") as *mut T
+    ) -> Result<*mut T> {
+        if let Some(mapping) = self.blocks.mappings.lock().unwrap().get(&memory) {
+            if offset > mapping.size {
+                return Err(VkQueueError::InvalidParameter("offset lies outside of the memory object"));
+            }
+            return Ok(unsafe { mapping.pointer.add(offset as usize) } as *mut T);
         }
+        Ok(unsafe {
+            self.device
+                .map_memory(memory, offset, size, vk::MemoryMapFlags::empty())? as *mut T
+        })
     }
 
+    // Persistently mapped memory stays mapped.
     pub fn unmap_memory(&self, memory: vk::DeviceMemory) {
+        if self.blocks.mappings.lock().unwrap().contains_key(&memory) {
+            return;
+        }
         unsafe {
             self.device.unmap_memory(memory);
         }
     }
 
-    pub fn copy_data_to_memory<T>(
+    /// # Safety
+    /// `memory` has to be host visible with `offset..offset + size_of_val(data)` inside it, and no
+    /// allocation, arena or view may cover that range. `copy_to_allocation` is the checked version.
+    pub unsafe fn copy_data_to_memory<T>(
         &self,
         data: &[T],
         memory: vk::DeviceMemory,
         offset: vk::DeviceSize,
-    ) {
-        let size = (data.len() * std::mem::size_of::<T>()) as vk::DeviceSize;
-        let mapped_memory = self.map_memory::<T>(memory, offset, size);
+    ) -> Result<()> {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        let mapped_memory = self.map_memory::<T>(memory, offset, size)?;
 
-        unsafe {
-            std::ptr::copy_nonoverlapping(data.as_ptr(), mapped_memory, data.len());
-            self.device.unmap_memory(memory);
-        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), mapped_memory, data.len());
+        self.unmap_memory(memory);
+        Ok(())
     }
 
     pub fn find_memory_type_index(
         &self,
         type_filter: u32,
-        properties: vk::MemoryPropertyFlags,
+        memory_usage: impl Into<MemoryRequest>,
     ) -> Option<u32> {
-        for i in 0..self.memory_prop.memory_type_count {
-            if (type_filter & (1 << i)) != 0
-                && self.memory_prop.memory_types[i as usize]
-                    .property_flags
-                    .contains(properties)
-            {
-                return Some(i);
-            }
-        }
-
-        None
+        memtype::find_memory_type_index(&self.memory_prop, type_filter, memory_usage)
     }
 }
 
@@ -97,6 +671,11 @@ impl Clone for MemoryManager {
         Self {
             device: self.device.clone(),
             memory_prop: self.memory_prop,
+            blocks: self.blocks.clone(),
+            buffer_image_granularity: self.buffer_image_granularity,
+            non_coherent_atom_size: self.non_coherent_atom_size,
+            budget_query: self.budget_query.clone(),
+            dedicated_query: self.dedicated_query.clone(),
         }
     }
 }
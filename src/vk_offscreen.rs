@@ -0,0 +1,48 @@
+use ash::vk;
+
+use crate::error::Result;
+use crate::memtype::MemoryUsage;
+use crate::vk_resmgr::{Image, ResourceManager};
+
+// Render target used in place of swapchain images when running without a surface.
+pub struct OffscreenTarget {
+    pub image: Image,
+    pub view: vk::ImageView,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl OffscreenTarget {
+    pub fn new(
+        device: &ash::Device,
+        resource_manager: &ResourceManager,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<Self> {
+        let image = resource_manager.create_image(
+            extent.width,
+            extent.height,
+            format,
+            vk::ImageTiling::OPTIMAL,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::SAMPLED,
+            MemoryUsage::GpuOnly,
+        )?;
+
+        let view = resource_manager.create_default_view(&image)?;
+
+        Ok(Self {
+            image,
+            view,
+            format,
+            extent,
+        })
+    }
+
+    // The caller must make sure the GPU is done with the view. The image goes through the
+    // deletion queue.
+    pub fn destroy(self, device: &ash::Device) {
+        unsafe { device.destroy_image_view(self.view, None) };
+    }
+}
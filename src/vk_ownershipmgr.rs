@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::error::{Result, VkQueueError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SharedResource {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueOwner {
+    Exclusive(u32),
+    Concurrent,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransferStages {
+    pub src_stage_mask: vk::PipelineStageFlags,
+    pub src_access_mask: vk::AccessFlags,
+    pub dst_stage_mask: vk::PipelineStageFlags,
+    pub dst_access_mask: vk::AccessFlags,
+}
+
+pub struct QueueOwnershipManager {
+    device: ash::Device,
+    owners: HashMap<SharedResource, QueueOwner>,
+}
+
+impl QueueOwnershipManager {
+    pub fn new(device: ash::Device) -> Self {
+        Self {
+            device,
+            owners: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, resource: SharedResource, queue_family_index: u32) {
+        self.owners
+            .insert(resource, QueueOwner::Exclusive(queue_family_index));
+    }
+
+    // CONCURRENT resources are visible to every family they were created with, so transfers are no-ops.
+    pub fn register_concurrent(&mut self, resource: SharedResource) {
+        self.owners.insert(resource, QueueOwner::Concurrent);
+    }
+
+    pub fn unregister(&mut self, resource: SharedResource) -> Option<QueueOwner> {
+        self.owners.remove(&resource)
+    }
+
+    pub fn owner(&self, resource: SharedResource) -> Option<QueueOwner> {
+        self.owners.get(&resource).copied()
+    }
+
+    fn begin_transfer(&self, resource: SharedResource, dst_queue_family_index: u32) -> Result<Option<u32>> {
+        match self
+            .owners
+            .get(&resource)
+            .ok_or(VkQueueError::UnknownResourceOwner)?
+        {
+            QueueOwner::Exclusive(src) if *src != dst_queue_family_index => Ok(Some(*src)),
+            _ => Ok(None),
+        }
+    }
+
+    // Records the release barrier into `release_command_buffer` (executed on the current owner's queue)
+    // and the matching acquire barrier into `acquire_command_buffer` (executed on the destination queue).
+    // Returns false when no transfer was needed.
+    pub fn transfer_buffer(
+        &mut self,
+        release_command_buffer: vk::CommandBuffer,
+        acquire_command_buffer: vk::CommandBuffer,
+        buffer: vk::Buffer,
+        dst_queue_family_index: u32,
+        stages: &TransferStages,
+    ) -> Result<bool> {
+        let resource = SharedResource::Buffer(buffer);
+        let src_queue_family_index = match self.begin_transfer(resource, dst_queue_family_index)? {
+            Some(src) => src,
+            None => return Ok(false),
+        };
+
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .src_queue_family_index(src_queue_family_index)
+            .dst_queue_family_index(dst_queue_family_index)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build();
+
+        let release = vk::BufferMemoryBarrier {
+            src_access_mask: stages.src_access_mask,
+            ..barrier
+        };
+        let acquire = vk::BufferMemoryBarrier {
+            dst_access_mask: stages.dst_access_mask,
+            ..barrier
+        };
+
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                release_command_buffer,
+                stages.src_stage_mask,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[release],
+                &[],
+            );
+            self.device.cmd_pipeline_barrier(
+                acquire_command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                stages.dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[acquire],
+                &[],
+            );
+        }
+
+        self.register(resource, dst_queue_family_index);
+        Ok(true)
+    }
+
+    // Both barriers carry the same layout transition, as required by the spec.
+    pub fn transfer_image(
+        &mut self,
+        release_command_buffer: vk::CommandBuffer,
+        acquire_command_buffer: vk::CommandBuffer,
+        image: vk::Image,
+        subresource_range: vk::ImageSubresourceRange,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        dst_queue_family_index: u32,
+        stages: &TransferStages,
+    ) -> Result<bool> {
+        let resource = SharedResource::Image(image);
+        let src_queue_family_index = match self.begin_transfer(resource, dst_queue_family_index)? {
+            Some(src) => src,
+            None => return Ok(false),
+        };
+
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .src_queue_family_index(src_queue_family_index)
+            .dst_queue_family_index(dst_queue_family_index)
+            .image(image)
+            .subresource_range(subresource_range)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .build();
+
+        let release = vk::ImageMemoryBarrier {
+            src_access_mask: stages.src_access_mask,
+            ..barrier
+        };
+        let acquire = vk::ImageMemoryBarrier {
+            dst_access_mask: stages.dst_access_mask,
+            ..barrier
+        };
+
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                release_command_buffer,
+                stages.src_stage_mask,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[release],
+            );
+            self.device.cmd_pipeline_barrier(
+                acquire_command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                stages.dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[acquire],
+            );
+        }
+
+        self.register(resource, dst_queue_family_index);
+        Ok(true)
+    }
+}
@@ -1,5 +1,7 @@
 use ash::vk;
 
+use crate::error::Result;
+
 pub struct ComputePipelineManager {
     device: ash::Device,
     descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
@@ -21,14 +23,13 @@ impl ComputePipelineManager {
         &mut self,
         shader_module: vk::ShaderModule,
         descriptor_set_layout_bindings: &[vk::DescriptorSetLayoutBinding],
-    ) -> vk::Pipeline {
+    ) -> Result<vk::Pipeline> {
         let descriptor_set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder()
             .bindings(descriptor_set_layout_bindings);
     
         let descriptor_set_layout = unsafe {
             self.device
-                .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)
-                .unwrap()
+                .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
         };
         let binding = [descriptor_set_layout];
         let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder()
@@ -36,14 +37,13 @@ impl ComputePipelineManager {
     
         let pipeline_layout = unsafe {
             self.device
-                .create_pipeline_layout(&pipeline_layout_create_info, None)
-                .unwrap()
+                .create_pipeline_layout(&pipeline_layout_create_info, None)?
         };
     
         let shader_stage_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::COMPUTE)
             .module(shader_module)
-            .name(std::ffi::CStr::from_bytes_with_nul(b"main\0").unwrap());
+            .name(c"main");
     
         let compute_pipeline_create_info = vk::ComputePipelineCreateInfo::builder()
             .stage(shader_stage_create_info.build())
@@ -56,13 +56,31 @@ impl ComputePipelineManager {
                     &[compute_pipeline_create_info.build()],
                     None,
                 )
-                .unwrap()[0]
+                .map_err(|(_, result)| result)?[0]
         };
     
         self.descriptor_set_layouts.push(descriptor_set_layout);
         self.pipeline_layouts.push(pipeline_layout);
         self.pipelines.push(pipeline);
     
-        pipeline
+        Ok(pipeline)
     }    
 }
+
+// Expects the device to be idle, as the pipelines may still be bound in pending work. The manager
+// owns its pipelines, so destroying them here is the normal teardown rather than a leak.
+impl Drop for ComputePipelineManager {
+    fn drop(&mut self) {
+        unsafe {
+            for pipeline in self.pipelines.drain(..) {
+                self.device.destroy_pipeline(pipeline, None);
+            }
+            for pipeline_layout in self.pipeline_layouts.drain(..) {
+                self.device.destroy_pipeline_layout(pipeline_layout, None);
+            }
+            for descriptor_set_layout in self.descriptor_set_layouts.drain(..) {
+                self.device.destroy_descriptor_set_layout(descriptor_set_layout, None);
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use ash::vk;
 
+use crate::error::Result;
+
 pub struct RenderPassManager {
     device: ash::Device,
 }
@@ -14,14 +16,14 @@ impl RenderPassManager {
         attachments: &[vk::AttachmentDescription],
         subpasses: &[vk::SubpassDescription],
         dependencies: &[vk::SubpassDependency],
-    ) -> vk::RenderPass {
+    ) -> Result<vk::RenderPass> {
         let render_pass_info = vk::RenderPassCreateInfo::builder()
             .attachments(attachments)
             .subpasses(subpasses)
             .dependencies(dependencies)
             .build();
 
-        unsafe { self.device.create_render_pass(&render_pass_info, None).unwrap() }
+        Ok(unsafe { self.device.create_render_pass(&render_pass_info, None)? })
     }
 
     pub fn create_framebuffer(
@@ -31,7 +33,7 @@ impl RenderPassManager {
         width: u32,
         height: u32,
         layers: u32,
-    ) -> vk::Framebuffer {
+    ) -> Result<vk::Framebuffer> {
         let framebuffer_info = vk::FramebufferCreateInfo::builder()
             .render_pass(render_pass)
             .attachments(attachments)
@@ -40,6 +42,6 @@ impl RenderPassManager {
             .layers(layers)
             .build();
 
-        unsafe { self.device.create_framebuffer(&framebuffer_info, None).unwrap() }
+        Ok(unsafe { self.device.create_framebuffer(&framebuffer_info, None)? })
     }
 }
@@ -1,44 +1,805 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
 use ash::vk;
+use bytemuck::Pod;
+
+use crate::allocator::{
+    align_up, plan_aliasing, Allocation, AllocationKind, AllocationRange, MappedSlice, MappedSliceMut, TransientResource,
+};
+use crate::error::{Result, VkQueueError};
+use crate::imaging::{copy_block, view_aspect, ImageDesc, ImageViewDesc};
+use crate::memtype::MemoryRequest;
+use crate::utils::report_leaks;
+use crate::vk_deletion::{Deletion, DeletionQueue};
+use crate::vk_memorymgr::MemoryManager;
+use crate::vk_ownershipmgr::SharedResource;
+
+// What is needed to create an identical buffer somewhere else during defragmentation. The
+// allocation itself belongs to the `Buffer`; only its location is kept here.
+#[derive(Debug)]
+struct TrackedBuffer {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    queue_family_indices: Vec<u32>,
+    // Retired together with the buffer. Buffers with views are left out of defragmentation.
+    views: Vec<vk::BufferView>,
+    // The copy made by a defragmentation pass, until the `Buffer` switches over to it.
+    pending_move: Option<PendingMove>,
+}
+
+#[derive(Debug)]
+struct PendingMove {
+    new_buffer: vk::Buffer,
+    allocation: Allocation,
+    // Set by `finish_defragmentation` once the copy has completed.
+    finished: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferMove {
+    pub old_buffer: vk::Buffer,
+    pub new_buffer: vk::Buffer,
+}
+
+// Buffers being moved by a defragmentation pass. Both the old and the new buffers stay valid
+// until the `Buffer` handles follow with `apply_moves`.
+#[derive(Debug)]
+pub struct Defragmentation {
+    pub moves: Vec<BufferMove>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TransientDesc {
+    Buffer {
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    },
+    // Optimal tiling, one mip level and layer.
+    Image {
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        samples: vk::SampleCountFlags,
+    },
+}
+
+// `first_use` and `last_use` are pass indices, both inclusive. Resources whose ranges don't
+// overlap may share memory.
+#[derive(Debug, Clone, Copy)]
+pub struct TransientResourceDesc {
+    pub resource: TransientDesc,
+    pub first_use: u32,
+    pub last_use: u32,
+}
+
+// Resources bound into one shared allocation, in the order they were described. Contents are
+// undefined at each resource's first use, so images have to start from UNDEFINED layout.
+#[derive(Debug)]
+pub struct AliasGroup {
+    pub resources: Vec<SharedResource>,
+    pub offsets: Vec<vk::DeviceSize>,
+    pub allocation: Allocation,
+}
+
+// Shared with the handles, so dropping one can retire it without a reference to the manager.
+struct Tracker {
+    buffers: Mutex<HashMap<vk::Buffer, TrackedBuffer>>,
+    images: Mutex<HashSet<vk::Image>>,
+    deletion_queue: Arc<DeletionQueue>,
+}
+
+impl Tracker {
+    // Handles that outlive the manager find nothing left to retire; their memory goes with the blocks.
+    fn retire_buffer(&self, buffer: vk::Buffer, allocation: Allocation) {
+        if let Some(tracked) = self.buffers.lock().unwrap().remove(&buffer) {
+            for view in tracked.views {
+                self.deletion_queue.retire(Deletion::BufferView(view));
+            }
+            if let Some(pending) = tracked.pending_move {
+                self.deletion_queue.retire(Deletion::Buffer(pending.new_buffer, pending.allocation));
+            }
+            self.deletion_queue.retire(Deletion::Buffer(buffer, allocation));
+        }
+    }
+
+    fn retire_image(&self, image: vk::Image, allocation: Allocation) {
+        if self.images.lock().unwrap().remove(&image) {
+            self.deletion_queue.retire(Deletion::Image(image, allocation));
+        }
+    }
+
+    // Hands a finished move over to the buffer, which is tracked under its new handle from now on.
+    fn take_finished_move(&self, buffer: vk::Buffer) -> Option<(vk::Buffer, Allocation)> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let finished = buffers.get(&buffer)?.pending_move.as_ref().is_some_and(|pending| pending.finished);
+        if !finished {
+            return None;
+        }
+
+        let mut tracked = buffers.remove(&buffer)?;
+        let pending = tracked.pending_move.take()?;
+        tracked.memory = pending.allocation.memory();
+        tracked.offset = pending.allocation.offset();
+        buffers.insert(pending.new_buffer, tracked);
+        Some((pending.new_buffer, pending.allocation))
+    }
+}
+
+// Owned buffer. Dropping it hands the buffer and its memory to the deletion queue, which destroys
+// them once the frame it was dropped in has completed. `FrameManager::begin_frame` moves the queue
+// along; without a swapchain the caller has to call `begin_frame` and `collect` on it instead.
+pub struct Buffer {
+    raw: vk::Buffer,
+    // Only taken when the buffer is dropped.
+    allocation: Option<Allocation>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    tracker: Arc<Tracker>,
+}
+
+impl Buffer {
+    pub fn raw(&self) -> vk::Buffer {
+        self.raw
+    }
+
+    pub fn allocation(&self) -> &Allocation {
+        self.allocation.as_ref().expect("buffer allocation taken before drop")
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn usage(&self) -> vk::BufferUsageFlags {
+        self.usage
+    }
+
+    // Views borrow the buffer, so it can't be dropped or moved by defragmentation under them.
+    pub fn mapped_slice<'a, T: Pod>(&'a self, memory_manager: &'a MemoryManager) -> Result<MappedSlice<'a, T>> {
+        memory_manager.mapped_slice(self.allocation())
+    }
+
+    pub fn mapped_slice_mut<'a, T: Pod>(&'a mut self, memory_manager: &'a MemoryManager) -> Result<MappedSliceMut<'a, T>> {
+        memory_manager.mapped_slice_mut(self.allocation.as_mut().expect("buffer allocation taken before drop"))
+    }
+
+    // Writes `data` at `offset` bytes into host-visible memory.
+    pub fn write<T: Pod>(&mut self, memory_manager: &MemoryManager, offset: vk::DeviceSize, data: &[T]) -> Result<()> {
+        if offset.checked_add(std::mem::size_of_val(data) as vk::DeviceSize).is_none_or(|end| end > self.size) {
+            return Err(VkQueueError::InvalidParameter("write exceeds the buffer"));
+        }
+        memory_manager.copy_to_allocation(data, self.allocation.as_mut().expect("buffer allocation taken before drop"), offset)
+    }
+
+    // Follows the buffer to its new place once `finish_defragmentation` has returned the moves.
+    // The old buffer and its memory are retired after the current frame. Returns whether it moved.
+    pub fn apply_moves(&mut self, moves: &[BufferMove]) -> bool {
+        if !moves.iter().any(|defrag_move| defrag_move.old_buffer == self.raw) {
+            return false;
+        }
+        let (new_buffer, allocation) = match self.tracker.take_finished_move(self.raw) {
+            Some(moved) => moved,
+            None => return false,
+        };
+
+        let old_allocation = self.allocation.replace(allocation).expect("buffer allocation taken before drop");
+        let old_buffer = std::mem::replace(&mut self.raw, new_buffer);
+        self.tracker.deletion_queue.retire(Deletion::Buffer(old_buffer, old_allocation));
+        true
+    }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buffer")
+            .field("raw", &self.raw)
+            .field("allocation", &self.allocation)
+            .field("size", &self.size)
+            .field("usage", &self.usage)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            self.tracker.retire_buffer(self.raw, allocation);
+        }
+    }
+}
+
+// Owned image, retired through the deletion queue like `Buffer`. Views have to be destroyed first.
+pub struct Image {
+    raw: vk::Image,
+    // Only taken when the image is dropped.
+    allocation: Option<Allocation>,
+    desc: ImageDesc,
+    tracker: Arc<Tracker>,
+}
+
+impl Image {
+    pub fn raw(&self) -> vk::Image {
+        self.raw
+    }
+
+    pub fn allocation(&self) -> &Allocation {
+        self.allocation.as_ref().expect("image allocation taken before drop")
+    }
+
+    pub fn desc(&self) -> &ImageDesc {
+        &self.desc
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.desc.format
+    }
+
+    pub fn extent(&self) -> vk::Extent3D {
+        self.desc.extent
+    }
+
+    pub fn usage(&self) -> vk::ImageUsageFlags {
+        self.desc.usage
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.desc.mip_levels
+    }
+
+    pub fn array_layers(&self) -> u32 {
+        self.desc.array_layers
+    }
+}
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Image")
+            .field("raw", &self.raw)
+            .field("allocation", &self.allocation)
+            .field("desc", &self.desc)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            self.tracker.retire_image(self.raw, allocation);
+        }
+    }
+}
 
 pub struct ResourceManager {
     device: ash::Device,
-    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    memory_manager: MemoryManager,
+    tracker: Arc<Tracker>,
+    // The handles of each live group, keyed by where its allocation sits.
+    alias_groups: Mutex<Vec<(AllocationRange, Vec<SharedResource>)>>,
+    format_query: Option<(ash::Instance, vk::PhysicalDevice)>,
+    format_properties: Mutex<HashMap<vk::Format, vk::FormatProperties>>,
+    min_texel_buffer_offset_alignment: vk::DeviceSize,
+    max_texel_buffer_elements: u32,
+    // Whether VkImageFormatListCreateInfo may be chained.
+    image_format_list: bool,
 }
 
 impl ResourceManager {
-    pub fn new(device: ash::Device, memory_properties: vk::PhysicalDeviceMemoryProperties) -> Self {
+    pub fn new(device: ash::Device, memory_manager: MemoryManager) -> Self {
+        let tracker = Arc::new(Tracker {
+            buffers: Mutex::new(HashMap::new()),
+            images: Mutex::new(HashSet::new()),
+            deletion_queue: Arc::new(DeletionQueue::new(device.clone(), memory_manager.clone())),
+        });
         Self {
             device,
-            memory_properties,
+            memory_manager,
+            tracker,
+            alias_groups: Mutex::new(Vec::new()),
+            format_query: None,
+            format_properties: Mutex::new(HashMap::new()),
+            min_texel_buffer_offset_alignment: 1,
+            max_texel_buffer_elements: u32::MAX,
+            image_format_list: false,
+        }
+    }
+
+    // `api_version` is the lower of the instance's and the device's. Before Vulkan 1.2 the view
+    // formats of an `ImageDesc` are only passed on with VK_KHR_image_format_list enabled.
+    pub fn with_image_format_list(mut self, api_version: u32, extension_enabled: bool) -> Self {
+        self.image_format_list = api_version >= vk::API_VERSION_1_2 || extension_enabled;
+        self
+    }
+
+    // Lets buffer views be checked against the format's bufferFeatures and the device limits.
+    pub fn with_format_query(mut self, instance: ash::Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let limits = unsafe { instance.get_physical_device_properties(physical_device).limits };
+        self.min_texel_buffer_offset_alignment = limits.min_texel_buffer_offset_alignment.max(1);
+        self.max_texel_buffer_elements = limits.max_texel_buffer_elements;
+        self.format_query = Some((instance, physical_device));
+        self
+    }
+
+    pub fn format_properties(&self, format: vk::Format) -> Option<vk::FormatProperties> {
+        let (instance, physical_device) = self.format_query.as_ref()?;
+        let mut cache = self.format_properties.lock().unwrap();
+        let properties = cache.entry(format).or_insert_with(|| unsafe {
+            instance.get_physical_device_format_properties(*physical_device, format)
+        });
+        Some(*properties)
+    }
+
+    // A texel view of `range` bytes from `offset`, or of the rest of the buffer with
+    // vk::WHOLE_SIZE. The view belongs to the buffer and is retired with it.
+    pub fn create_buffer_view(
+        &self,
+        buffer: &Buffer,
+        format: vk::Format,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    ) -> Result<vk::BufferView> {
+        let required = texel_buffer_features(buffer.usage);
+        if required.is_empty() {
+            return Err(VkQueueError::InvalidParameter(
+                "buffer views need UNIFORM_TEXEL_BUFFER or STORAGE_TEXEL_BUFFER usage",
+            ));
+        }
+        if !offset.is_multiple_of(self.min_texel_buffer_offset_alignment) {
+            return Err(VkQueueError::InvalidParameter(
+                "buffer view offset must be a multiple of minTexelBufferOffsetAlignment",
+            ));
+        }
+        if offset >= buffer.size
+            || (range != vk::WHOLE_SIZE && (range == 0 || offset.checked_add(range).is_none_or(|end| end > buffer.size)))
+        {
+            return Err(VkQueueError::InvalidParameter("buffer view range exceeds the buffer"));
+        }
+        // Texel sizes of formats `copy_block` doesn't know are left to the format query.
+        if let Some((texel_size, 1, 1)) = copy_block(format, vk::ImageAspectFlags::COLOR) {
+            // vk::WHOLE_SIZE covers the whole texels left in the buffer.
+            let bytes = if range == vk::WHOLE_SIZE {
+                buffer.size - offset
+            } else if range.is_multiple_of(texel_size) {
+                range
+            } else {
+                return Err(VkQueueError::InvalidParameter(
+                    "buffer view range must be a multiple of the format's texel size",
+                ));
+            };
+            if bytes / texel_size > u64::from(self.max_texel_buffer_elements) {
+                return Err(VkQueueError::InvalidParameter(
+                    "buffer view has more texels than maxTexelBufferElements",
+                ));
+            }
+        }
+        if let Some(properties) = self.format_properties(format) {
+            if !properties.buffer_features.contains(required) {
+                return Err(VkQueueError::UnsupportedFormat {
+                    format,
+                    features: required,
+                });
+            }
         }
+
+        let mut buffers = self.tracker.buffers.lock().unwrap();
+        let tracked = buffers
+            .get_mut(&buffer.raw)
+            .ok_or(VkQueueError::InvalidParameter("buffer is not tracked by this manager"))?;
+
+        let view_info = vk::BufferViewCreateInfo::builder()
+            .buffer(buffer.raw)
+            .format(format)
+            .offset(offset)
+            .range(range);
+        let view = unsafe { self.device.create_buffer_view(&view_info, None)? };
+        tracked.views.push(view);
+        Ok(view)
+    }
+
+    pub fn deletion_queue(&self) -> &DeletionQueue {
+        &self.tracker.deletion_queue
+    }
+
+    // For `FrameManager`, which moves the queue from frame to frame.
+    pub(crate) fn shared_deletion_queue(&self) -> Arc<DeletionQueue> {
+        self.tracker.deletion_queue.clone()
     }
 
     pub fn create_buffer(
         &self,
         size: vk::DeviceSize,
         usage: vk::BufferUsageFlags,
-        properties: vk::MemoryPropertyFlags,
-    ) -> (vk::Buffer, vk::DeviceMemory) {
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<Buffer> {
+        self.create_buffer_shared(size, usage, memory_usage.into(), &[])
+    }
+
+    pub fn create_buffer_concurrent(
+        &self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        memory_usage: impl Into<MemoryRequest>,
+        queue_family_indices: &[u32],
+    ) -> Result<Buffer> {
+        self.create_buffer_shared(size, usage, memory_usage.into(), queue_family_indices)
+    }
+
+    fn create_buffer_shared(
+        &self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        memory_usage: MemoryRequest,
+        queue_family_indices: &[u32],
+    ) -> Result<Buffer> {
+        let (sharing_mode, queue_family_indices) = sharing(queue_family_indices);
         let buffer_info = vk::BufferCreateInfo::builder()
             .size(size)
             .usage(usage)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices)
             .build();
 
-        let buffer = unsafe { self.device.create_buffer(&buffer_info, None).unwrap() };
+        let buffer = unsafe { self.device.create_buffer(&buffer_info, None)? };
+
+        let allocation = match self.memory_manager.allocate_for_buffer(buffer, memory_usage) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                return Err(error);
+            }
+        };
+
+        self.tracker.buffers.lock().unwrap().insert(
+            buffer,
+            TrackedBuffer {
+                memory: allocation.memory(),
+                offset: allocation.offset(),
+                size,
+                usage,
+                queue_family_indices: queue_family_indices.to_vec(),
+                views: Vec::new(),
+                pending_move: None,
+            },
+        );
+        Ok(Buffer {
+            raw: buffer,
+            allocation: Some(allocation),
+            size,
+            usage,
+            tracker: self.tracker.clone(),
+        })
+    }
+
+    pub fn create_alias_group(
+        &self,
+        descs: &[TransientResourceDesc],
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<AliasGroup> {
+        if descs.is_empty() {
+            return Err(VkQueueError::InvalidParameter("alias group needs at least one resource"));
+        }
+
+        let mut resources = Vec::with_capacity(descs.len());
+        for desc in descs {
+            match self.create_transient(&desc.resource) {
+                Ok(resource) => resources.push(resource),
+                Err(error) => {
+                    self.destroy_handles(&resources);
+                    return Err(error);
+                }
+            }
+        }
+
+        let mut memory_type_bits = !0;
+        let transients: Vec<_> = resources
+            .iter()
+            .zip(descs)
+            .map(|(resource, desc)| {
+                let (requirements, kind) = match *resource {
+                    SharedResource::Buffer(buffer) => (
+                        unsafe { self.device.get_buffer_memory_requirements(buffer) },
+                        AllocationKind::Linear,
+                    ),
+                    SharedResource::Image(image) => (
+                        unsafe { self.device.get_image_memory_requirements(image) },
+                        AllocationKind::Optimal,
+                    ),
+                };
+                memory_type_bits &= requirements.memory_type_bits;
+                TransientResource {
+                    size: requirements.size,
+                    alignment: requirements.alignment,
+                    kind,
+                    first_use: desc.first_use,
+                    last_use: desc.last_use,
+                }
+            })
+            .collect();
+
+        let request = memory_usage.into();
+        if memory_type_bits == 0 {
+            self.destroy_handles(&resources);
+            return Err(VkQueueError::NoSuitableMemoryType {
+                memory_type_bits,
+                properties: request.required,
+            });
+        }
+
+        let granularity = self.memory_manager.buffer_image_granularity();
+        let plan = match plan_aliasing(&transients, granularity) {
+            Ok(plan) => plan,
+            Err(error) => {
+                self.destroy_handles(&resources);
+                return Err(error);
+            }
+        };
+        let mixed = transients.iter().any(|transient| transient.kind != transients[0].kind);
+        // A group holding both kinds is padded to whole pages so no neighbour shares one with it.
+        let requirements = if mixed {
+            vk::MemoryRequirements {
+                size: align_up(plan.size, granularity),
+                alignment: plan.alignment.max(granularity),
+                memory_type_bits,
+            }
+        } else {
+            vk::MemoryRequirements {
+                size: plan.size,
+                alignment: plan.alignment,
+                memory_type_bits,
+            }
+        };
+        let kind = if mixed { AllocationKind::Optimal } else { transients[0].kind };
+
+        let allocation = match self.memory_manager.allocate(&requirements, request, kind) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                self.destroy_handles(&resources);
+                return Err(error);
+            }
+        };
+
+        for (resource, &offset) in resources.iter().zip(&plan.offsets) {
+            let result = match *resource {
+                SharedResource::Buffer(buffer) => unsafe {
+                    self.device
+                        .bind_buffer_memory(buffer, allocation.memory(), allocation.offset() + offset)
+                },
+                SharedResource::Image(image) => unsafe {
+                    self.device
+                        .bind_image_memory(image, allocation.memory(), allocation.offset() + offset)
+                },
+            };
+            if let Err(error) = result {
+                self.destroy_handles(&resources);
+                self.memory_manager.free(allocation);
+                return Err(error.into());
+            }
+        }
+
+        self.alias_groups.lock().unwrap().push((allocation.range(), resources.clone()));
+        Ok(AliasGroup {
+            resources,
+            offsets: plan.offsets,
+            allocation,
+        })
+    }
+
+    // The caller must make sure the GPU is done with every resource of the group.
+    pub fn destroy_alias_group(&self, group: AliasGroup) {
+        let mut alias_groups = self.alias_groups.lock().unwrap();
+        let range = group.allocation.range();
+        if let Some(index) = alias_groups.iter().position(|(tracked, _)| *tracked == range) {
+            let (_, resources) = alias_groups.swap_remove(index);
+            self.destroy_handles(&resources);
+            self.memory_manager.free(group.allocation);
+        }
+    }
+
+    fn create_transient(&self, desc: &TransientDesc) -> Result<SharedResource> {
+        match *desc {
+            TransientDesc::Buffer { size, usage } => {
+                let buffer_info = vk::BufferCreateInfo::builder()
+                    .size(size)
+                    .usage(usage)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE);
+                Ok(SharedResource::Buffer(unsafe { self.device.create_buffer(&buffer_info, None)? }))
+            }
+            TransientDesc::Image {
+                width,
+                height,
+                format,
+                usage,
+                samples,
+            } => {
+                let image_info = vk::ImageCreateInfo::builder()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .extent(vk::Extent3D {
+                        width,
+                        height,
+                        depth: 1,
+                    })
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .format(format)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .usage(usage)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .samples(samples);
+                Ok(SharedResource::Image(unsafe { self.device.create_image(&image_info, None)? }))
+            }
+        }
+    }
+
+    fn destroy_handles(&self, resources: &[SharedResource]) {
+        for resource in resources {
+            match *resource {
+                SharedResource::Buffer(buffer) => unsafe { self.device.destroy_buffer(buffer, None) },
+                SharedResource::Image(image) => unsafe { self.device.destroy_image(image, None) },
+            }
+        }
+    }
+
+    // Records copies of up to `max_bytes` of buffers into `command_buffer`, packing them into fewer
+    // memory blocks. Only buffers created with TRANSFER_SRC and TRANSFER_DST usage are moved; images
+    // stay where they are since their layouts aren't known here. Submit the command buffer and call
+    // `finish_defragmentation` once it has completed.
+    pub fn begin_defragmentation(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        max_bytes: vk::DeviceSize,
+    ) -> Result<Defragmentation> {
+        let transfer = vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST;
+        let mut buffers = self.tracker.buffers.lock().unwrap();
+        let by_location: HashMap<_, _> = buffers
+            .iter()
+            .filter(|(_, tracked)| {
+                tracked.usage.contains(transfer) && tracked.views.is_empty() && tracked.pending_move.is_none()
+            })
+            .map(|(&buffer, tracked)| ((tracked.memory, tracked.offset), buffer))
+            .collect();
+
+        let mut memory_moves = self
+            .memory_manager
+            .plan_defragmentation(max_bytes, |src| by_location.contains_key(&(src.memory, src.offset)))
+            .into_iter();
+
+        let mut moves = Vec::with_capacity(memory_moves.len());
+        let mut regions = Vec::with_capacity(memory_moves.len());
+        while let Some(memory_move) = memory_moves.next() {
+            let old_buffer = by_location[&(memory_move.src.memory, memory_move.src.offset)];
+            let size = buffers[&old_buffer].size;
+            let new_buffer = match self.create_bound_buffer(&buffers[&old_buffer], &memory_move.dst) {
+                Ok(buffer) => buffer,
+                Err(error) => {
+                    for defrag_move in &moves {
+                        self.cancel_move(&mut buffers, defrag_move);
+                    }
+                    for unstarted in std::iter::once(memory_move).chain(memory_moves) {
+                        self.memory_manager.free(unstarted.dst);
+                    }
+                    return Err(error);
+                }
+            };
+
+            regions.push(vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size,
+            });
+            if let Some(tracked) = buffers.get_mut(&old_buffer) {
+                tracked.pending_move = Some(PendingMove {
+                    new_buffer,
+                    allocation: memory_move.dst,
+                    finished: false,
+                });
+            }
+            moves.push(BufferMove { old_buffer, new_buffer });
+        }
 
-        let mem_requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
-        let memory_type = self.find_memory_type(mem_requirements.memory_type_bits, properties);
+        if moves.is_empty() {
+            return Ok(Defragmentation { moves });
+        }
 
-        let allocate_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(memory_type)
+        // Earlier transfers or shaders may have written the sources in the same submission.
+        let before = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
             .build();
+        let after = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)
+            .build();
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[before],
+                &[],
+                &[],
+            );
+            for (defrag_move, region) in moves.iter().zip(&regions) {
+                self.device.cmd_copy_buffer(
+                    command_buffer,
+                    defrag_move.old_buffer,
+                    defrag_move.new_buffer,
+                    std::slice::from_ref(region),
+                );
+            }
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[after],
+                &[],
+                &[],
+            );
+        }
 
-        let buffer_memory = unsafe { self.device.allocate_memory(&allocate_info, None).unwrap() };
-        unsafe { self.device.bind_buffer_memory(buffer, buffer_memory, 0).unwrap() };
+        Ok(Defragmentation { moves })
+    }
 
-        (buffer, buffer_memory)
+    // Marks the copies as complete. `Buffer` handles switch over with `apply_moves`, which retires
+    // the old buffer and its memory; descriptors and command buffers referencing an old buffer have
+    // to be updated with `new_buffer`. Buffers dropped since `begin_defragmentation` are left out.
+    pub fn finish_defragmentation(&self, defragmentation: Defragmentation) -> Vec<BufferMove> {
+        let mut buffers = self.tracker.buffers.lock().unwrap();
+        let mut moves = defragmentation.moves;
+        // A dropped buffer has already retired its copy along with itself.
+        moves.retain(|defrag_move| {
+            match buffers
+                .get_mut(&defrag_move.old_buffer)
+                .and_then(|tracked| tracked.pending_move.as_mut())
+            {
+                Some(pending) => {
+                    pending.finished = true;
+                    true
+                }
+                None => false,
+            }
+        });
+        moves
+    }
+
+    // Drops a pass that was never submitted.
+    pub fn cancel_defragmentation(&self, defragmentation: Defragmentation) {
+        let mut buffers = self.tracker.buffers.lock().unwrap();
+        for defrag_move in &defragmentation.moves {
+            self.cancel_move(&mut buffers, defrag_move);
+        }
+    }
+
+    fn cancel_move(&self, buffers: &mut HashMap<vk::Buffer, TrackedBuffer>, defrag_move: &BufferMove) {
+        let pending = buffers
+            .get_mut(&defrag_move.old_buffer)
+            .and_then(|tracked| tracked.pending_move.take());
+        if let Some(pending) = pending {
+            unsafe { self.device.destroy_buffer(pending.new_buffer, None) };
+            self.memory_manager.free(pending.allocation);
+        }
+    }
+
+    fn create_bound_buffer(&self, tracked: &TrackedBuffer, allocation: &Allocation) -> Result<vk::Buffer> {
+        let (sharing_mode, queue_family_indices) = sharing(&tracked.queue_family_indices);
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(tracked.size)
+            .usage(tracked.usage)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices);
+
+        let buffer = unsafe { self.device.create_buffer(&buffer_info, None)? };
+        if let Err(error) = unsafe { self.device.bind_buffer_memory(buffer, allocation.memory(), allocation.offset()) } {
+            unsafe { self.device.destroy_buffer(buffer, None) };
+            return Err(error.into());
+        }
+        Ok(buffer)
     }
 
     pub fn create_image(
@@ -48,56 +809,151 @@ impl ResourceManager {
         format: vk::Format,
         tiling: vk::ImageTiling,
         usage: vk::ImageUsageFlags,
-        properties: vk::MemoryPropertyFlags,
-    ) -> (vk::Image, vk::DeviceMemory) {
-        let image_info = vk::ImageCreateInfo::builder()
-            .image_type(vk::ImageType::TYPE_2D)
-            .extent(vk::Extent3D {
-                width,
-                height,
-                depth: 1,
-            })
-            .mip_levels(1)
-            .array_layers(1)
-            .format(format)
+        memory_usage: impl Into<MemoryRequest>,
+    ) -> Result<Image> {
+        self.create_image_with(&ImageDesc::new_2d(width, height, format, usage).tiling(tiling), memory_usage)
+    }
+
+    pub fn create_image_concurrent(
+        &self,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        tiling: vk::ImageTiling,
+        usage: vk::ImageUsageFlags,
+        memory_usage: impl Into<MemoryRequest>,
+        queue_family_indices: &[u32],
+    ) -> Result<Image> {
+        let desc = ImageDesc::new_2d(width, height, format, usage)
             .tiling(tiling)
+            .queue_family_indices(queue_family_indices);
+        self.create_image_with(&desc, memory_usage)
+    }
+
+    pub fn create_image_with(&self, desc: &ImageDesc, memory_usage: impl Into<MemoryRequest>) -> Result<Image> {
+        desc.validate()?;
+
+        let (sharing_mode, queue_family_indices) = sharing(&desc.queue_family_indices);
+        let mut format_list = vk::ImageFormatListCreateInfo::builder().view_formats(&desc.view_formats);
+        let mut image_info = vk::ImageCreateInfo::builder()
+            .flags(desc.create_flags())
+            .image_type(desc.image_type)
+            .extent(desc.extent)
+            .mip_levels(desc.mip_levels)
+            .array_layers(desc.array_layers)
+            .format(desc.format)
+            .tiling(desc.tiling)
             .initial_layout(vk::ImageLayout::UNDEFINED)
-            .usage(usage)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .samples(vk::SampleCountFlags::TYPE_1)
-            .build();
+            .usage(desc.usage)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices)
+            .samples(desc.samples);
+        // The list is only a hint; MUTABLE_FORMAT alone is enough without it.
+        if !desc.view_formats.is_empty() && self.image_format_list {
+            image_info = image_info.push_next(&mut format_list);
+        }
 
-        let image = unsafe { self.device.create_image(&image_info, None).unwrap() };
+        let image = unsafe { self.device.create_image(&image_info, None)? };
 
-        let mem_requirements = unsafe { self.device.get_image_memory_requirements(image) };
-        let memory_type = self.find_memory_type(mem_requirements.memory_type_bits, properties);
+        let allocation = match self.memory_manager.allocate_for_image(image, desc.tiling, memory_usage) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                unsafe { self.device.destroy_image(image, None) };
+                return Err(error);
+            }
+        };
 
-        let allocate_info = vk::MemoryAllocateInfo::builder()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(memory_type)
-            .build();
+        self.tracker.images.lock().unwrap().insert(image);
+        Ok(Image {
+            raw: image,
+            allocation: Some(allocation),
+            desc: desc.clone(),
+            tracker: self.tracker.clone(),
+        })
+    }
 
-        let image_memory = unsafe { self.device.allocate_memory(&allocate_info, None).unwrap() };
-        unsafe { self.device.bind_image_memory(image, image_memory, 0).unwrap() };
+    // Retire the view through the deletion queue before the image is dropped.
+    pub fn create_image_view(&self, image: &Image, view_desc: &ImageViewDesc) -> Result<vk::ImageView> {
+        let desc = image.desc();
+        let format = view_desc.format.unwrap_or(desc.format);
+        let subresource_range =
+            view_desc.subresource_range(desc, view_desc.aspect_mask.unwrap_or_else(|| view_aspect(format)))?;
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image.raw)
+            .view_type(view_desc.view_type.unwrap_or_else(|| desc.default_view_type()))
+            .format(format)
+            .components(view_desc.components)
+            .subresource_range(subresource_range);
 
-        (image, image_memory)
+        Ok(unsafe { self.device.create_image_view(&view_info, None)? })
     }
 
-    pub fn find_memory_type(
-        &self,
-        type_filter: u32,
-        properties: vk::MemoryPropertyFlags,
-    ) -> u32 {
-        for i in 0..self.memory_properties.memory_type_count {
-            if type_filter & (1 << i) != 0
-                && self.memory_properties.memory_types[i as usize]
-                    .property_flags
-                    .contains(properties)
-            {
-                return i;
+    // Every mip and layer, with the view type matching the image.
+    pub fn create_default_view(&self, image: &Image) -> Result<vk::ImageView> {
+        self.create_image_view(image, &ImageViewDesc::default())
+    }
+}
+
+// The owner has to make sure the device is idle first; `VulkanQueue` waits once for all of its
+// managers.
+impl Drop for ResourceManager {
+    fn drop(&mut self) {
+        // Handles that outlive the manager find nothing left to retire.
+        let buffers: Vec<_> = self.tracker.buffers.lock().unwrap().drain().collect();
+        let images: Vec<_> = self.tracker.images.lock().unwrap().drain().collect();
+        let alias_groups = std::mem::take(self.alias_groups.get_mut().unwrap());
+        self.tracker.deletion_queue.destroy_pending();
+        if buffers.is_empty() && images.is_empty() && alias_groups.is_empty() {
+            return;
+        }
+
+        report_leaks("ResourceManager", "buffer", &buffers);
+        report_leaks("ResourceManager", "image", &images);
+        report_leaks("ResourceManager", "alias group", &alias_groups);
+
+        // The allocations still belong to the leaked handles and are released with the blocks.
+        unsafe {
+            for (buffer, tracked) in buffers {
+                for view in tracked.views {
+                    self.device.destroy_buffer_view(view, None);
+                }
+                self.device.destroy_buffer(buffer, None);
+                if let Some(pending) = tracked.pending_move {
+                    self.device.destroy_buffer(pending.new_buffer, None);
+                    self.memory_manager.free(pending.allocation);
+                }
+            }
+            for image in images {
+                self.device.destroy_image(image, None);
             }
         }
+        for (_, resources) in alias_groups {
+            self.destroy_handles(&resources);
+        }
+    }
+}
+
+// The format features a view has to support for every texel usage of the buffer.
+fn texel_buffer_features(usage: vk::BufferUsageFlags) -> vk::FormatFeatureFlags {
+    let mut features = vk::FormatFeatureFlags::empty();
+    if usage.contains(vk::BufferUsageFlags::UNIFORM_TEXEL_BUFFER) {
+        features |= vk::FormatFeatureFlags::UNIFORM_TEXEL_BUFFER;
+    }
+    if usage.contains(vk::BufferUsageFlags::STORAGE_TEXEL_BUFFER) {
+        features |= vk::FormatFeatureFlags::STORAGE_TEXEL_BUFFER;
+    }
+    features
+}
 
-        panic!("Failed to find suitable memory type.")
+// CONCURRENT sharing only makes sense across more than one distinct family, and the spec
+// forbids repeating a family in the list.
+fn sharing(queue_family_indices: &[u32]) -> (vk::SharingMode, Vec<u32>) {
+    let mut families = queue_family_indices.to_vec();
+    families.sort_unstable();
+    families.dedup();
+    if families.len() > 1 {
+        (vk::SharingMode::CONCURRENT, families)
+    } else {
+        (vk::SharingMode::EXCLUSIVE, Vec::new())
     }
 }
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use ash::vk;
+
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+    pub mip_lod_bias: f32,
+    // Clamped to maxSamplerAnisotropy, and ignored without the samplerAnisotropy feature.
+    pub max_anisotropy: Option<f32>,
+    pub compare_op: Option<vk::CompareOp>,
+    pub min_lod: f32,
+    pub max_lod: f32,
+    pub border_color: vk::BorderColor,
+    pub unnormalized_coordinates: bool,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self::linear()
+    }
+}
+
+impl SamplerDesc {
+    // Trilinear, repeating, every mip level.
+    pub fn linear() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            address_mode_w: vk::SamplerAddressMode::REPEAT,
+            mip_lod_bias: 0.0,
+            max_anisotropy: None,
+            compare_op: None,
+            min_lod: 0.0,
+            max_lod: vk::LOD_CLAMP_NONE,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            unnormalized_coordinates: false,
+        }
+    }
+
+    pub fn nearest() -> Self {
+        Self::linear()
+            .filter(vk::Filter::NEAREST)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+    }
+
+    // Depth comparison for shadow maps; everything outside the map is lit.
+    pub fn shadow() -> Self {
+        Self::linear()
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
+            .compare_op(vk::CompareOp::LESS_OR_EQUAL)
+    }
+
+    pub fn filter(mut self, filter: vk::Filter) -> Self {
+        self.mag_filter = filter;
+        self.min_filter = filter;
+        self
+    }
+
+    pub fn mipmap_mode(mut self, mipmap_mode: vk::SamplerMipmapMode) -> Self {
+        self.mipmap_mode = mipmap_mode;
+        self
+    }
+
+    pub fn address_mode(mut self, address_mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode_u = address_mode;
+        self.address_mode_v = address_mode;
+        self.address_mode_w = address_mode;
+        self
+    }
+
+    pub fn anisotropy(mut self, max_anisotropy: f32) -> Self {
+        self.max_anisotropy = Some(max_anisotropy);
+        self
+    }
+
+    pub fn compare_op(mut self, compare_op: vk::CompareOp) -> Self {
+        self.compare_op = Some(compare_op);
+        self
+    }
+
+    pub fn border_color(mut self, border_color: vk::BorderColor) -> Self {
+        self.border_color = border_color;
+        self
+    }
+
+    pub fn lod(mut self, min_lod: f32, max_lod: f32) -> Self {
+        self.min_lod = min_lod;
+        self.max_lod = max_lod;
+        self
+    }
+
+    pub fn mip_lod_bias(mut self, mip_lod_bias: f32) -> Self {
+        self.mip_lod_bias = mip_lod_bias;
+        self
+    }
+
+    // What the device will actually get. `max_supported` is maxSamplerAnisotropy, or None when
+    // the samplerAnisotropy feature isn't enabled.
+    pub fn resolve(mut self, max_supported: Option<f32>) -> Self {
+        self.max_anisotropy = match (self.max_anisotropy, max_supported) {
+            (Some(requested), Some(limit)) if requested > 1.0 => Some(requested.min(limit)),
+            _ => None,
+        };
+        self
+    }
+
+    // Floats compared by bits, so the description can key a hash map.
+    fn key(&self) -> impl Eq + Hash {
+        (
+            (self.mag_filter, self.min_filter, self.mipmap_mode),
+            (self.address_mode_u, self.address_mode_v, self.address_mode_w),
+            (self.mip_lod_bias.to_bits(), self.max_anisotropy.map(f32::to_bits)),
+            (self.compare_op, self.min_lod.to_bits(), self.max_lod.to_bits()),
+            (self.border_color, self.unnormalized_coordinates),
+        )
+    }
+}
+
+impl PartialEq for SamplerDesc {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for SamplerDesc {}
+
+impl Hash for SamplerDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+// Samplers are immutable and few, so identical descriptions share one. They live as long as
+// the manager.
+pub struct SamplerManager {
+    device: ash::Device,
+    max_anisotropy: Option<f32>,
+    samplers: Mutex<HashMap<SamplerDesc, vk::Sampler>>,
+}
+
+impl SamplerManager {
+    // `max_anisotropy` is maxSamplerAnisotropy if the samplerAnisotropy feature is enabled.
+    pub fn new(device: ash::Device, max_anisotropy: Option<f32>) -> Self {
+        Self {
+            device,
+            max_anisotropy,
+            samplers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_sampler(&self, desc: &SamplerDesc) -> Result<vk::Sampler> {
+        let desc = desc.resolve(self.max_anisotropy);
+        let mut samplers = self.samplers.lock().unwrap();
+        if let Some(&sampler) = samplers.get(&desc) {
+            return Ok(sampler);
+        }
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(desc.address_mode_u)
+            .address_mode_v(desc.address_mode_v)
+            .address_mode_w(desc.address_mode_w)
+            .mip_lod_bias(desc.mip_lod_bias)
+            .anisotropy_enable(desc.max_anisotropy.is_some())
+            .max_anisotropy(desc.max_anisotropy.unwrap_or(1.0))
+            .compare_enable(desc.compare_op.is_some())
+            .compare_op(desc.compare_op.unwrap_or(vk::CompareOp::ALWAYS))
+            .min_lod(desc.min_lod)
+            .max_lod(desc.max_lod)
+            .border_color(desc.border_color)
+            .unnormalized_coordinates(desc.unnormalized_coordinates);
+
+        let sampler = unsafe { self.device.create_sampler(&sampler_info, None)? };
+        samplers.insert(desc, sampler);
+        Ok(sampler)
+    }
+
+    pub fn sampler_count(&self) -> usize {
+        self.samplers.lock().unwrap().len()
+    }
+}
+
+// Expects the device to be idle, as the samplers may still be referenced by descriptors in use.
+impl Drop for SamplerManager {
+    fn drop(&mut self) {
+        let samplers = self.samplers.get_mut().unwrap();
+        if samplers.is_empty() {
+            return;
+        }
+        unsafe {
+            for (_, sampler) in samplers.drain() {
+                self.device.destroy_sampler(sampler, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashSet;
+
+    fn hash(desc: &SamplerDesc) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        desc.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn identical_descriptions_share_a_key() {
+        let a = SamplerDesc::linear().anisotropy(8.0).lod(0.0, 4.0);
+        let b = SamplerDesc::default().lod(0.0, 4.0).anisotropy(8.0);
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        let distinct: HashSet<_> = [a, b, SamplerDesc::nearest(), SamplerDesc::shadow(), a.mip_lod_bias(0.5)]
+            .into_iter()
+            .collect();
+        assert_eq!(distinct.len(), 4);
+    }
+
+    #[test]
+    fn resolve_without_the_feature_disables_anisotropy() {
+        let resolved = SamplerDesc::linear().anisotropy(16.0).resolve(None);
+        assert_eq!(resolved.max_anisotropy, None);
+        assert_eq!(resolved, SamplerDesc::linear());
+    }
+
+    #[test]
+    fn resolve_clamps_to_the_limit() {
+        assert_eq!(SamplerDesc::linear().anisotropy(64.0).resolve(Some(16.0)).max_anisotropy, Some(16.0));
+        assert_eq!(SamplerDesc::linear().anisotropy(4.0).resolve(Some(16.0)).max_anisotropy, Some(4.0));
+        // 1x is no anisotropy at all.
+        assert_eq!(SamplerDesc::linear().anisotropy(1.0).resolve(Some(16.0)).max_anisotropy, None);
+        assert_eq!(SamplerDesc::linear().resolve(Some(16.0)).max_anisotropy, None);
+        // Anything above the limit ends up as the same sampler.
+        assert_eq!(
+            SamplerDesc::linear().anisotropy(32.0).resolve(Some(16.0)),
+            SamplerDesc::linear().anisotropy(16.0).resolve(Some(16.0))
+        );
+    }
+}
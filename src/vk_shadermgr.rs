@@ -2,6 +2,9 @@ use ash::vk;
 use std::io::Cursor;
 
 use std::path::Path;
+
+use crate::error::{Result, VkQueueError};
+
 pub struct ShaderManager {
     device: ash::Device,
 }
@@ -11,16 +14,15 @@ impl ShaderManager {
         Self { device }
     }
 
-    pub fn load_shader_module(&self, file_path: &Path) -> vk::ShaderModule {
-        let code = std::fs::read(file_path).expect("Failed to read shader file");
-        let code = ash::util::read_spv(&mut Cursor::new(code)).expect("Failed to read shader code");
+    pub fn load_shader_module(&self, file_path: &Path) -> Result<vk::ShaderModule> {
+        let code = std::fs::read(file_path)?;
+        let code = ash::util::read_spv(&mut Cursor::new(code)).map_err(VkQueueError::SpirV)?;
 
         let shader_info = vk::ShaderModuleCreateInfo::builder().code(&code);
 
-        unsafe {
+        Ok(unsafe {
             self.device
-                .create_shader_module(&shader_info, None)
-                .expect("Failed to create shader module")
-        }
+                .create_shader_module(&shader_info, None)?
+        })
     }
 }
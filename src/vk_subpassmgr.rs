@@ -1,5 +1,6 @@
 use ash::vk;
 
+#[derive(Default)]
 pub struct SubpassManager {}
 
 impl SubpassManager {
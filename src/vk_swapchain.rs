@@ -1,6 +1,8 @@
 use ash::vk;
 use ash::extensions::khr;
 
+use crate::error::{Result, VkQueueError};
+
 pub struct SwapchainSupportDetails {
     pub capabilities: vk::SurfaceCapabilitiesKHR,
     pub formats: Vec<vk::SurfaceFormatKHR>,
@@ -13,28 +15,48 @@ impl SwapchainSupportDetails {
         physical_device: vk::PhysicalDevice,
         surface: vk::SurfaceKHR,
         surface_loader: &khr::Surface,
-    ) -> Self {
+    ) -> Result<Self> {
         let capabilities = unsafe {
             surface_loader
-                .get_physical_device_surface_capabilities(physical_device, surface)
-                .expect("Failed to query for surface capabilities.")
+                .get_physical_device_surface_capabilities(physical_device, surface)?
         };
         let formats = unsafe {
             surface_loader
-                .get_physical_device_surface_formats(physical_device, surface)
-                .expect("Failed to query for surface formats.")
+                .get_physical_device_surface_formats(physical_device, surface)?
         };
         let present_modes = unsafe {
             surface_loader
-                .get_physical_device_surface_present_modes(physical_device, surface)
-                .expect("Failed to query for surface present mode.")
+                .get_physical_device_surface_present_modes(physical_device, surface)?
         };
 
-        Self {
+        Ok(Self {
             capabilities,
             formats,
             present_modes,
-        }
+        })
+    }
+
+    // Takes the first preferred format the surface supports, falling back to whatever it lists first.
+    pub fn choose_surface_format(&self, preferred: &[vk::SurfaceFormatKHR]) -> Result<vk::SurfaceFormatKHR> {
+        preferred
+            .iter()
+            .find(|wanted| {
+                self.formats.iter().any(|format| {
+                    format.format == wanted.format && format.color_space == wanted.color_space
+                })
+            })
+            .copied()
+            .or_else(|| self.formats.first().copied())
+            .ok_or(VkQueueError::NoSurfaceFormat)
+    }
+
+    // FIFO is the only mode the spec guarantees, so it is the fallback.
+    pub fn choose_present_mode(&self, preferred: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
+        preferred
+            .iter()
+            .copied()
+            .find(|mode| self.present_modes.contains(mode))
+            .unwrap_or(vk::PresentModeKHR::FIFO)
     }
 }
 
@@ -43,6 +65,8 @@ pub struct Swapchain {
     pub handle: vk::SwapchainKHR,
     pub images: Vec<vk::Image>,
     pub image_format: vk::Format,
+    pub surface_format: vk::SurfaceFormatKHR,
+    pub present_mode: vk::PresentModeKHR,
     pub extent: vk::Extent2D,
 }
 
@@ -55,25 +79,65 @@ impl Swapchain {
         window_width: u32,
         window_height: u32,
         surface_loader: &khr::Surface,
-    ) -> Self {
-        let swapchain_support = SwapchainSupportDetails::new(instance, physical_device, surface, surface_loader);
+        preferred_formats: &[vk::SurfaceFormatKHR],
+        preferred_present_modes: &[vk::PresentModeKHR],
+    ) -> Result<Self> {
+        Self::create(
+            instance,
+            device,
+            physical_device,
+            surface,
+            window_width,
+            window_height,
+            surface_loader,
+            preferred_formats,
+            preferred_present_modes,
+            vk::SwapchainKHR::null(),
+        )
+    }
 
-        let surface_format = swapchain_support
-            .formats
-            .iter()
-            .cloned()
-            .find(|format| {
-                format.format == vk::Format::B8G8R8A8_SRGB
-                    && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
-            })
-            .unwrap_or(swapchain_support.formats[0]);
+    // A replacement with the same format and present mode, e.g. after a resize. This swapchain is
+    // retired by it and only has to be kept alive until its images are no longer in use.
+    pub fn recreate(
+        &self,
+        instance: &ash::Instance,
+        device: &ash::Device,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+        window_width: u32,
+        window_height: u32,
+        surface_loader: &khr::Surface,
+    ) -> Result<Self> {
+        Self::create(
+            instance,
+            device,
+            physical_device,
+            surface,
+            window_width,
+            window_height,
+            surface_loader,
+            &[self.surface_format],
+            &[self.present_mode],
+            self.handle,
+        )
+    }
 
-        let present_mode = swapchain_support
-            .present_modes
-            .iter()
-            .cloned()
-            .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
-            .unwrap_or(vk::PresentModeKHR::FIFO);
+    fn create(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+        window_width: u32,
+        window_height: u32,
+        surface_loader: &khr::Surface,
+        preferred_formats: &[vk::SurfaceFormatKHR],
+        preferred_present_modes: &[vk::PresentModeKHR],
+        old_swapchain: vk::SwapchainKHR,
+    ) -> Result<Self> {
+        let swapchain_support = SwapchainSupportDetails::new(instance, physical_device, surface, surface_loader)?;
+
+        let surface_format = swapchain_support.choose_surface_format(preferred_formats)?;
+        let present_mode = swapchain_support.choose_present_mode(preferred_present_modes);
 
         let extent = {
             let mut actual_extent = vk::Extent2D {
@@ -116,20 +180,29 @@ impl Swapchain {
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(present_mode)
             .clipped(true)
-            .old_swapchain(vk::SwapchainKHR::null())
+            .old_swapchain(old_swapchain)
             .build();
 
         let loader = khr::Swapchain::new(instance, device);
-        let handle = unsafe { loader.create_swapchain(&swapchain_create_info, None) }
-            .expect("Failed to create Swapchain.");
-        let images = unsafe { loader.get_swapchain_images(handle) }.expect("Failed to get Swapchain Images.");
+        let handle = unsafe { loader.create_swapchain(&swapchain_create_info, None) }?;
+        let images = unsafe { loader.get_swapchain_images(handle) }?;
 
-        Self {
+        Ok(Self {
             loader,
             handle,
             images,
             image_format: surface_format.format,
+            surface_format,
+            present_mode,
             extent,
-        }
+        })
+    }
+}
+
+// Images belong to the swapchain and go away with it. Whoever drops the last reference
+// must make sure none of them is still in use.
+impl Drop for Swapchain {
+    fn drop(&mut self) {
+        unsafe { self.loader.destroy_swapchain(self.handle, None) };
     }
 }
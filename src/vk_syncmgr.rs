@@ -1,5 +1,7 @@
 use ash::vk;
 
+use crate::error::Result;
+
 pub struct SynchronizationManager {
     device: ash::Device,
 }
@@ -9,13 +11,13 @@ impl SynchronizationManager {
         Self { device }
     }
 
-    pub fn create_semaphore(&self) -> vk::Semaphore {
+    pub fn create_semaphore(&self) -> Result<vk::Semaphore> {
         let semaphore_info = vk::SemaphoreCreateInfo::default();
-        unsafe { self.device.create_semaphore(&semaphore_info, None).unwrap() }
+        Ok(unsafe { self.device.create_semaphore(&semaphore_info, None)? })
     }
 
-    pub fn create_fence(&self) -> vk::Fence {
+    pub fn create_fence(&self) -> Result<vk::Fence> {
         let fence_info = vk::FenceCreateInfo::default();
-        unsafe { self.device.create_fence(&fence_info, None).unwrap() }
+        Ok(unsafe { self.device.create_fence(&fence_info, None)? })
     }
 }
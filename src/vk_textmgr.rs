@@ -1,97 +1,486 @@
 use ash::vk;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use image::GenericImageView;
 
+use crate::allocator::Allocation;
+use crate::error::Result;
+use crate::imaging::{generate_mip_chain, is_srgb, mip_extent, mip_level_count, supports_linear_blit, MipFilter};
+use crate::memtype::MemoryUsage;
 use crate::vk_memorymgr::MemoryManager;
 
+#[derive(Debug)]
+struct Texture {
+    image: vk::Image,
+    view: vk::ImageView,
+    allocation: Allocation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MipGeneration {
+    #[default]
+    None,
+    // vkCmdBlitImage when the format supports linear blits, `MipFilter` on the CPU otherwise.
+    Auto(MipFilter),
+    Cpu(MipFilter),
+}
+
+// A queue with a command pool of its own, for one-off submissions that are waited on.
+struct SubmitQueue {
+    queue: Arc<Mutex<vk::Queue>>,
+    family_index: u32,
+    command_pool: Mutex<vk::CommandPool>,
+}
+
+impl SubmitQueue {
+    fn new(device: &ash::Device, queue: Arc<Mutex<vk::Queue>>, family_index: u32) -> Result<Self> {
+        let command_pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = unsafe { device.create_command_pool(&command_pool_info, None)? };
+        Ok(Self {
+            queue,
+            family_index,
+            command_pool: Mutex::new(command_pool),
+        })
+    }
+
+    fn run(&self, device: &ash::Device, record: impl FnOnce(vk::CommandBuffer)) -> Result<()> {
+        let command_pool = self.command_pool.lock().unwrap();
+        let allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(*command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let command_buffer = unsafe { device.allocate_command_buffers(&allocate_info)?[0] };
+
+        let result = (|| unsafe {
+            let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            device.begin_command_buffer(command_buffer, &begin_info)?;
+            record(command_buffer);
+            device.end_command_buffer(command_buffer)?;
+
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            let command_buffers = [command_buffer];
+            let submit_info = vk::SubmitInfo::builder().command_buffers(&command_buffers).build();
+            let submitted = {
+                let queue = self.queue.lock().unwrap();
+                device.queue_submit(*queue, &[submit_info], fence)
+            };
+            let waited = submitted.and_then(|_| device.wait_for_fences(&[fence], true, u64::MAX));
+            device.destroy_fence(fence, None);
+            waited
+        })();
+
+        unsafe { device.free_command_buffers(*command_pool, &[command_buffer]) };
+        Ok(result?)
+    }
+
+    unsafe fn destroy(&mut self, device: &ash::Device) {
+        device.destroy_command_pool(*self.command_pool.get_mut().unwrap(), None);
+    }
+}
+
+// Uploads go through a staging buffer on the transfer queue given to the builder, or the graphics
+// queue without one. Mips generated with blits are always done on the graphics queue.
 pub struct TextureManager {
     device: ash::Device,
     memory_manager: MemoryManager,
+    graphics: SubmitQueue,
+    transfer: SubmitQueue,
+    format_query: Option<(ash::Instance, vk::PhysicalDevice)>,
+    textures: Mutex<Vec<Texture>>,
 }
 
 impl TextureManager {
-    pub fn new(device: ash::Device, memory_manager: MemoryManager) -> Self {
-        Self {
+    pub fn new(
+        device: ash::Device,
+        memory_manager: MemoryManager,
+        graphics_queue: Arc<Mutex<vk::Queue>>,
+        graphics_queue_family_index: u32,
+        transfer_queue: Arc<Mutex<vk::Queue>>,
+        transfer_queue_family_index: u32,
+    ) -> Result<Self> {
+        let mut graphics = SubmitQueue::new(&device, graphics_queue, graphics_queue_family_index)?;
+        let transfer = match SubmitQueue::new(&device, transfer_queue, transfer_queue_family_index) {
+            Ok(transfer) => transfer,
+            Err(error) => {
+                unsafe { graphics.destroy(&device) };
+                return Err(error);
+            }
+        };
+
+        Ok(Self {
             device,
             memory_manager,
+            graphics,
+            transfer,
+            format_query: None,
+            textures: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Without it the blit support of a format is unknown and mips are always made on the CPU.
+    pub fn with_format_query(mut self, instance: ash::Instance, physical_device: vk::PhysicalDevice) -> Self {
+        self.format_query = Some((instance, physical_device));
+        self
+    }
+
+    // The caller must make sure the GPU is done with the texture.
+    pub fn destroy_texture(&self, image: vk::Image) {
+        let mut textures = self.textures.lock().unwrap();
+        if let Some(index) = textures.iter().position(|texture| texture.image == image) {
+            let texture = textures.swap_remove(index);
+            unsafe { self.destroy(texture) };
         }
     }
 
+    unsafe fn destroy(&self, texture: Texture) {
+        self.device.destroy_image_view(texture.view, None);
+        self.device.destroy_image(texture.image, None);
+        self.memory_manager.free(texture.allocation);
+    }
+
+    // Blocks until the upload has finished. The image is left in SHADER_READ_ONLY_OPTIMAL.
     pub fn load_texture(
         &self,
         file_path: &Path,
         format: vk::Format,
         usage: vk::ImageUsageFlags,
         aspect_mask: vk::ImageAspectFlags,
-    ) -> (vk::Image, vk::ImageView) {
-        let img = image::open(file_path).expect("Failed to open texture file");
+    ) -> Result<(vk::Image, vk::ImageView)> {
+        self.load_texture_with_mips(file_path, format, usage, aspect_mask, MipGeneration::None)
+    }
+
+    // `format` has to take RGBA8 texels, which is what the file is decoded to.
+    pub fn load_texture_with_mips(
+        &self,
+        file_path: &Path,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        aspect_mask: vk::ImageAspectFlags,
+        mips: MipGeneration,
+    ) -> Result<(vk::Image, vk::ImageView)> {
+        let img = image::open(file_path)?;
         let (width, height) = img.dimensions();
         let data = img.to_rgba8().into_raw();
+        let extent = vk::Extent3D {
+            width,
+            height,
+            depth: 1,
+        };
+
+        let blit = match mips {
+            MipGeneration::Auto(_) => self.format_query.as_ref().is_some_and(|(instance, physical_device)| {
+                let properties = unsafe { instance.get_physical_device_format_properties(*physical_device, format) };
+                supports_linear_blit(&properties)
+            }),
+            MipGeneration::None | MipGeneration::Cpu(_) => false,
+        };
+        let (mip_levels, levels) = match mips {
+            MipGeneration::None => (1, vec![data]),
+            MipGeneration::Auto(_) if blit => (mip_level_count(extent), vec![data]),
+            MipGeneration::Auto(filter) | MipGeneration::Cpu(filter) => {
+                let mut levels = generate_mip_chain(&data, width, height, filter, is_srgb(format))?;
+                levels.insert(0, data);
+                (levels.len() as u32, levels)
+            }
+        };
+        let submit_queue = if blit { &self.graphics } else { &self.transfer };
+
+        // A separate transfer family would otherwise need an ownership transfer to the graphics queue.
+        let queue_family_indices = [self.transfer.family_index, self.graphics.family_index];
+        let queue_family_indices = if self.transfer.family_index == self.graphics.family_index {
+            &queue_family_indices[..1]
+        } else {
+            &queue_family_indices[..]
+        };
+        let sharing_mode = if queue_family_indices.len() > 1 {
+            vk::SharingMode::CONCURRENT
+        } else {
+            vk::SharingMode::EXCLUSIVE
+        };
+        let transfer_usage = if blit {
+            vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC
+        } else {
+            vk::ImageUsageFlags::TRANSFER_DST
+        };
 
         let image_create_info = vk::ImageCreateInfo::builder()
             .image_type(vk::ImageType::TYPE_2D)
             .format(format)
-            .extent(vk::Extent3D {
-                width,
-                height,
-                depth: 1,
-            })
-            .mip_levels(1)
+            .extent(extent)
+            .mip_levels(mip_levels)
             .array_layers(1)
             .samples(vk::SampleCountFlags::TYPE_1)
             .tiling(vk::ImageTiling::OPTIMAL)
-            .usage(usage)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .usage(usage | transfer_usage)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(queue_family_indices)
             .initial_layout(vk::ImageLayout::UNDEFINED);
 
         let image = unsafe {
             self.device
-                .create_image(&image_create_info, None)
-                .expect("Failed to create image")
+                .create_image(&image_create_info, None)?
+        };
+
+        let allocation = match self.memory_manager.allocate_for_image(
+            image,
+            vk::ImageTiling::OPTIMAL,
+            MemoryUsage::GpuOnly,
+        ) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                unsafe { self.device.destroy_image(image, None) };
+                return Err(error);
+            }
+        };
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(aspect_mask)
+            .base_mip_level(0)
+            .level_count(mip_levels)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        let view = self
+            .upload(submit_queue, image, extent, &levels, subresource_range, blit)
+            .and_then(|_| {
+                let view_create_info = vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(format)
+                    .subresource_range(subresource_range);
+                Ok(unsafe { self.device.create_image_view(&view_create_info, None)? })
+            });
+        let view = match view {
+            Ok(view) => view,
+            Err(error) => {
+                unsafe { self.device.destroy_image(image, None) };
+                self.memory_manager.free(allocation);
+                return Err(error);
+            }
         };
 
-        let mem_requirements = unsafe { self.device.get_image_memory_requirements(image) };
-        let memory_type_index = self
+        self.textures.lock().unwrap().push(Texture { image, view, allocation });
+        Ok((image, view))
+    }
+
+    // `levels` holds the data of every level to copy; with `blit` only level 0 is given and the
+    // rest of `range` is filled from it.
+    fn upload(
+        &self,
+        submit_queue: &SubmitQueue,
+        image: vk::Image,
+        extent: vk::Extent3D,
+        levels: &[Vec<u8>],
+        range: vk::ImageSubresourceRange,
+        blit: bool,
+    ) -> Result<()> {
+        let size: usize = levels.iter().map(Vec::len).sum();
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size as vk::DeviceSize)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let staging_buffer = unsafe { self.device.create_buffer(&buffer_info, None)? };
+
+        let result = self
             .memory_manager
-            .find_memory_type_index(
-                mem_requirements.memory_type_bits,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            )
-            .expect("Failed to find suitable memory type for image");
+            .allocate_for_buffer(staging_buffer, MemoryUsage::Staging)
+            .and_then(|mut staging| {
+                let result = self
+                    .memory_manager
+                    .copy_to_allocation(&levels.concat(), &mut staging, 0)
+                    .and_then(|_| {
+                        submit_queue.run(&self.device, |command_buffer| unsafe {
+                            self.record_upload(submit_queue, command_buffer, staging_buffer, image, extent, levels, range, blit)
+                        })
+                    });
+                self.memory_manager.free(staging);
+                result
+            });
+        unsafe { self.device.destroy_buffer(staging_buffer, None) };
+        result
+    }
 
-        let memory = self.memory_manager.allocate_memory(mem_requirements.size, memory_type_index);
-        unsafe {
-            self.device
-                .bind_image_memory(image, memory, 0)
-                .expect("Failed to bind image memory");
-        }
+    unsafe fn record_upload(
+        &self,
+        submit_queue: &SubmitQueue,
+        command_buffer: vk::CommandBuffer,
+        staging_buffer: vk::Buffer,
+        image: vk::Image,
+        extent: vk::Extent3D,
+        levels: &[Vec<u8>],
+        range: vk::ImageSubresourceRange,
+        blit: bool,
+    ) {
+        // A transfer-only queue can't name the fragment stage; the fence wait orders later use instead.
+        let (dst_stage_mask, dst_access_mask) = if submit_queue.family_index == self.graphics.family_index {
+            (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ)
+        } else {
+            (vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::AccessFlags::empty())
+        };
+        let barrier = |base_mip_level, level_count, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    base_mip_level,
+                    level_count,
+                    ..range
+                })
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .build()
+        };
 
-        self.memory_manager.copy_data_to_memory(
-            &data,
-            memory,
-            0,
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier(
+                0,
+                range.level_count,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_WRITE,
+            )],
         );
 
-        let view_create_info = vk::ImageViewCreateInfo::builder()
-            .image(image)
-            .view_type(vk::ImageViewType::TYPE_2D)
-            .format(format)
-            .subresource_range(
-                vk::ImageSubresourceRange::builder()
-                    .aspect_mask(aspect_mask)
-                    .base_mip_level(0)
-                    .level_count(1)
-                    .base_array_layer(0)
-                    .layer_count(1)
-                    .build(),
+        let mut buffer_offset = 0;
+        let regions: Vec<_> = levels
+            .iter()
+            .enumerate()
+            .map(|(level, data)| {
+                let region = vk::BufferImageCopy::builder()
+                    .buffer_offset(buffer_offset)
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: range.aspect_mask,
+                        mip_level: level as u32,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(mip_extent(extent, level as u32))
+                    .build();
+                buffer_offset += data.len() as vk::DeviceSize;
+                region
+            })
+            .collect();
+        self.device.cmd_copy_buffer_to_image(
+            command_buffer,
+            staging_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &regions,
+        );
+
+        // Each level is blitted from the one above, which then moves on to SHADER_READ_ONLY_OPTIMAL.
+        let blitted_levels = if blit { range.level_count } else { 1 };
+        for level in 1..blitted_levels {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier(
+                    level - 1,
+                    1,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::TRANSFER_READ,
+                )],
             );
 
-        let view = unsafe {
-            self.device
-                .create_image_view(&view_create_info, None)
-                .expect("Failed to create image view")
-        };
+            let corner = |level| {
+                let extent = mip_extent(extent, level);
+                vk::Offset3D {
+                    x: extent.width as i32,
+                    y: extent.height as i32,
+                    z: 1,
+                }
+            };
+            let subresource = |level| vk::ImageSubresourceLayers {
+                aspect_mask: range.aspect_mask,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 1,
+            };
+            let region = vk::ImageBlit::builder()
+                .src_subresource(subresource(level - 1))
+                .src_offsets([vk::Offset3D::default(), corner(level - 1)])
+                .dst_subresource(subresource(level))
+                .dst_offsets([vk::Offset3D::default(), corner(level)])
+                .build();
+            self.device.cmd_blit_image(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+                vk::Filter::LINEAR,
+            );
 
-        (image, view)
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier(
+                    level - 1,
+                    1,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::TRANSFER_READ,
+                    dst_access_mask,
+                )],
+            );
+        }
+
+        // Whatever is still in TRANSFER_DST_OPTIMAL: the last blitted level, or every copied one.
+        let first_remaining = blitted_levels - 1;
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            dst_stage_mask,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier(
+                first_remaining,
+                range.level_count - first_remaining,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask,
+            )],
+        );
+    }
+}
+
+// Expects the device to be idle, as the textures may still be sampled.
+impl Drop for TextureManager {
+    fn drop(&mut self) {
+        // Textures live as long as the manager unless destroyed earlier, so none of them is a leak.
+        let textures = std::mem::take(self.textures.get_mut().unwrap());
+        unsafe {
+            for texture in textures {
+                self.destroy(texture);
+            }
+            self.graphics.destroy(&self.device);
+            self.transfer.destroy(&self.device);
+        }
     }
 }
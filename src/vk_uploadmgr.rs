@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+
+use crate::allocator::RingAllocator;
+use crate::error::{Result, VkQueueError};
+use crate::imaging::{copy_size, mip_extent};
+use crate::memtype::MemoryUsage;
+use crate::vk_memorymgr::{MemoryArena, MemoryManager};
+use crate::vk_resmgr::{Buffer, Image};
+
+// Enough for vkCmdCopyBufferToImage with any uncompressed or block-compressed format.
+const STAGING_ALIGNMENT: vk::DeviceSize = 16;
+
+// Completes when the batch the upload went into has finished on the GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UploadHandle(u64);
+
+impl UploadHandle {
+    // Direct writes are visible as soon as they return.
+    pub const COMPLETE: UploadHandle = UploadHandle(0);
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PendingCopy {
+    Buffer {
+        buffer: vk::Buffer,
+        region: vk::BufferCopy,
+    },
+    Image {
+        image: vk::Image,
+        region: vk::BufferImageCopy,
+        range: vk::ImageSubresourceRange,
+    },
+}
+
+#[derive(Debug)]
+struct InFlight {
+    batch: u64,
+    fence: vk::Fence,
+    command_buffer: vk::CommandBuffer,
+}
+
+struct UploadState {
+    arena: MemoryArena<RingAllocator>,
+    pending: Vec<PendingCopy>,
+    in_flight: VecDeque<InFlight>,
+    // Uploads recorded now go into `batch`; everything up to `completed` has finished.
+    batch: u64,
+    completed: u64,
+}
+
+// Collects uploads from any thread into a persistently mapped staging ring and submits them in
+// batches with `flush`, typically once per frame. Resources written from another queue family
+// than the one they are used on have to be created CONCURRENT.
+pub struct UploadManager {
+    device: ash::Device,
+    memory_manager: MemoryManager,
+    queue: Arc<Mutex<vk::Queue>>,
+    queue_family_index: u32,
+    graphics_queue_family_index: u32,
+    staging_buffer: vk::Buffer,
+    command_pool: vk::CommandPool,
+    state: Mutex<UploadState>,
+}
+
+impl UploadManager {
+    pub fn new(
+        device: ash::Device,
+        memory_manager: MemoryManager,
+        queue: Arc<Mutex<vk::Queue>>,
+        queue_family_index: u32,
+        graphics_queue_family_index: u32,
+        staging_size: vk::DeviceSize,
+    ) -> Result<Self> {
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(staging_size)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let staging_buffer = unsafe { device.create_buffer(&buffer_info, None)? };
+        let requirements = unsafe { device.get_buffer_memory_requirements(staging_buffer) };
+
+        let arena = match memory_manager.create_ring_arena(
+            requirements.size,
+            requirements.memory_type_bits,
+            MemoryUsage::Staging,
+        ) {
+            // The memory may be larger than the buffer; only the buffer's range is handed out.
+            Ok(mut arena) => {
+                arena.allocator = RingAllocator::new(staging_size);
+                arena
+            }
+            Err(error) => {
+                unsafe { device.destroy_buffer(staging_buffer, None) };
+                return Err(error);
+            }
+        };
+
+        let command_pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = unsafe { device.bind_buffer_memory(staging_buffer, arena.memory(), 0) }
+            .and_then(|_| unsafe { device.create_command_pool(&command_pool_info, None) });
+        let command_pool = match command_pool {
+            Ok(command_pool) => command_pool,
+            Err(error) => {
+                unsafe { device.destroy_buffer(staging_buffer, None) };
+                memory_manager.destroy_arena(arena);
+                return Err(error.into());
+            }
+        };
+
+        Ok(Self {
+            device,
+            memory_manager,
+            queue,
+            queue_family_index,
+            graphics_queue_family_index,
+            staging_buffer,
+            command_pool,
+            state: Mutex::new(UploadState {
+                arena,
+                pending: Vec::new(),
+                in_flight: VecDeque::new(),
+                batch: 1,
+                completed: 0,
+            }),
+        })
+    }
+
+    // Host-visible buffers (ReBAR, UMA) are written in place, so the caller has to make sure the
+    // GPU isn't using that range. Everything else is staged.
+    pub fn upload_buffer(&self, buffer: &mut Buffer, offset: vk::DeviceSize, data: &[u8]) -> Result<UploadHandle> {
+        if offset
+            .checked_add(data.len() as vk::DeviceSize)
+            .is_none_or(|end| end > buffer.size())
+        {
+            return Err(VkQueueError::InvalidParameter("upload exceeds the buffer"));
+        }
+
+        let memory_type_index = buffer.allocation().memory_type_index();
+        let flags = self.memory_manager.memory_properties().memory_types[memory_type_index as usize].property_flags;
+        if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            buffer.write(&self.memory_manager, offset, data)?;
+            return Ok(UploadHandle::COMPLETE);
+        }
+
+        self.stage(data, |src_offset| PendingCopy::Buffer {
+            buffer: buffer.raw(),
+            region: vk::BufferCopy {
+                src_offset,
+                dst_offset: offset,
+                size: data.len() as vk::DeviceSize,
+            },
+        })
+    }
+
+    // Replaces a whole mip level of the given layers, which end up in SHADER_READ_ONLY_OPTIMAL.
+    // `data` holds the level's texels tightly packed, one layer after the other.
+    pub fn upload_image(&self, image: &Image, subresource: vk::ImageSubresourceLayers, data: &[u8]) -> Result<UploadHandle> {
+        if subresource.mip_level >= image.mip_levels()
+            || subresource
+                .base_array_layer
+                .checked_add(subresource.layer_count)
+                .is_none_or(|end| end > image.array_layers())
+        {
+            return Err(VkQueueError::InvalidParameter("subresource lies outside of the image"));
+        }
+        let extent = mip_extent(image.extent(), subresource.mip_level);
+        let expected = copy_size(image.format(), subresource.aspect_mask, extent, subresource.layer_count)
+            .ok_or(VkQueueError::InvalidParameter("the texel size of the format and aspect is not known"))?;
+        if data.len() as u64 != expected {
+            return Err(VkQueueError::InvalidParameter("data size does not match the mip level"));
+        }
+
+        self.stage(data, |src_offset| PendingCopy::Image {
+            image: image.raw(),
+            region: vk::BufferImageCopy {
+                buffer_offset: src_offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: subresource,
+                image_offset: vk::Offset3D::default(),
+                image_extent: extent,
+            },
+            range: vk::ImageSubresourceRange {
+                aspect_mask: subresource.aspect_mask,
+                base_mip_level: subresource.mip_level,
+                level_count: 1,
+                base_array_layer: subresource.base_array_layer,
+                layer_count: subresource.layer_count,
+            },
+        })
+    }
+
+    fn stage(&self, data: &[u8], copy: impl FnOnce(vk::DeviceSize) -> PendingCopy) -> Result<UploadHandle> {
+        let size = data.len() as vk::DeviceSize;
+        let mut state = self.state.lock().unwrap();
+        if size > state.arena.allocator.capacity() {
+            return Err(VkQueueError::InvalidParameter("upload is larger than the staging ring"));
+        }
+
+        // When the ring is full, submit what is pending and wait for the oldest batch to free up space.
+        let src_offset = loop {
+            if let Some(offset) = state.arena.allocator.allocate(size, STAGING_ALIGNMENT) {
+                break offset;
+            }
+            if !state.pending.is_empty() {
+                self.submit(&mut state)?;
+            }
+            let oldest = state.in_flight.front().map(|in_flight| in_flight.fence);
+            if let Some(fence) = oldest {
+                unsafe { self.device.wait_for_fences(&[fence], true, u64::MAX)? };
+            }
+            self.retire(&mut state)?;
+        };
+
+        let mut staging = self
+            .memory_manager
+            .arena_slice_mut::<_, u8>(&mut state.arena, src_offset, data.len())?;
+        staging.copy_from_slice(data);
+        drop(staging);
+
+        state.pending.push(copy(src_offset));
+        Ok(UploadHandle(state.batch))
+    }
+
+    // Submits everything recorded since the last flush as one batch.
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.pending.is_empty() {
+            self.submit(&mut state)?;
+        }
+        self.retire(&mut state)
+    }
+
+    pub fn is_complete(&self, handle: UploadHandle) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        self.retire(&mut state)?;
+        Ok(handle.0 <= state.completed)
+    }
+
+    // Flushes the handle's batch if it is still being collected.
+    pub fn wait(&self, handle: UploadHandle) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if handle.0 == state.batch && !state.pending.is_empty() {
+            self.submit(&mut state)?;
+        }
+        let fences: Vec<_> = state
+            .in_flight
+            .iter()
+            .filter(|in_flight| in_flight.batch <= handle.0)
+            .map(|in_flight| in_flight.fence)
+            .collect();
+        if !fences.is_empty() {
+            unsafe { self.device.wait_for_fences(&fences, true, u64::MAX)? };
+        }
+        self.retire(&mut state)
+    }
+
+    pub fn staging_used(&self) -> vk::DeviceSize {
+        self.state.lock().unwrap().arena.allocator.used()
+    }
+
+    fn submit(&self, state: &mut UploadState) -> Result<()> {
+        let allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let command_buffer = unsafe { self.device.allocate_command_buffers(&allocate_info)?[0] };
+
+        let fence = unsafe { self.record(command_buffer, &state.pending) }
+            .and_then(|_| unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None) });
+        let fence = match fence {
+            Ok(fence) => fence,
+            Err(error) => {
+                unsafe { self.device.free_command_buffers(self.command_pool, &[command_buffer]) };
+                return Err(error.into());
+            }
+        };
+
+        let command_buffers = [command_buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(&command_buffers).build();
+        let result = {
+            let queue = self.queue.lock().unwrap();
+            unsafe { self.device.queue_submit(*queue, &[submit_info], fence) }
+        };
+        if let Err(error) = result {
+            unsafe {
+                self.device.destroy_fence(fence, None);
+                self.device.free_command_buffers(self.command_pool, &[command_buffer]);
+            }
+            return Err(error.into());
+        }
+
+        state.arena.allocator.submit(fence);
+        state.in_flight.push_back(InFlight {
+            batch: state.batch,
+            fence,
+            command_buffer,
+        });
+        state.pending.clear();
+        state.batch += 1;
+        Ok(())
+    }
+
+    unsafe fn record(&self, command_buffer: vk::CommandBuffer, copies: &[PendingCopy]) -> ash::prelude::VkResult<()> {
+        // A transfer-only queue can't name the shader stages; waiting on the handle orders later use instead.
+        let (dst_stage_mask, dst_access_mask) = if self.queue_family_index == self.graphics_queue_family_index {
+            (vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::MEMORY_READ)
+        } else {
+            (vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::AccessFlags::empty())
+        };
+        let image_barrier = |image, range, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .build()
+        };
+
+        let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        self.device.begin_command_buffer(command_buffer, &begin_info)?;
+
+        let to_transfer: Vec<_> = copies
+            .iter()
+            .filter_map(|copy| match *copy {
+                PendingCopy::Image { image, range, .. } => Some(image_barrier(
+                    image,
+                    range,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::TRANSFER_WRITE,
+                )),
+                PendingCopy::Buffer { .. } => None,
+            })
+            .collect();
+        if !to_transfer.is_empty() {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_transfer,
+            );
+        }
+
+        for copy in copies {
+            match *copy {
+                PendingCopy::Buffer { buffer, region } => {
+                    self.device.cmd_copy_buffer(command_buffer, self.staging_buffer, buffer, &[region]);
+                }
+                PendingCopy::Image { image, region, .. } => {
+                    self.device.cmd_copy_buffer_to_image(
+                        command_buffer,
+                        self.staging_buffer,
+                        image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                    );
+                }
+            }
+        }
+
+        let to_shader_read: Vec<_> = copies
+            .iter()
+            .filter_map(|copy| match *copy {
+                PendingCopy::Image { image, range, .. } => Some(image_barrier(
+                    image,
+                    range,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    dst_access_mask,
+                )),
+                PendingCopy::Buffer { .. } => None,
+            })
+            .collect();
+        let buffer_writes = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(dst_access_mask)
+            .build();
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            dst_stage_mask,
+            vk::DependencyFlags::empty(),
+            &[buffer_writes],
+            &[],
+            &to_shader_read,
+        );
+
+        self.device.end_command_buffer(command_buffer)
+    }
+
+    // Batches complete in submission order, so only a prefix of `in_flight` can be done.
+    fn retire(&self, state: &mut UploadState) -> Result<()> {
+        let mut finished = Vec::new();
+        for in_flight in &state.in_flight {
+            if !unsafe { self.device.get_fence_status(in_flight.fence)? } {
+                break;
+            }
+            finished.push(in_flight.fence);
+        }
+        if finished.is_empty() {
+            return Ok(());
+        }
+
+        state.arena.allocator.reclaim(|fence| finished.contains(&fence));
+        for in_flight in state.in_flight.drain(..finished.len()) {
+            state.completed = in_flight.batch;
+            unsafe {
+                self.device.destroy_fence(in_flight.fence, None);
+                self.device.free_command_buffers(self.command_pool, &[in_flight.command_buffer]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UploadManager {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        // Only its own batches read from the staging buffer, so those are all it waits for.
+        let fences: Vec<_> = state.in_flight.iter().map(|in_flight| in_flight.fence).collect();
+        unsafe {
+            if !fences.is_empty() {
+                let _ = self.device.wait_for_fences(&fences, true, u64::MAX);
+            }
+            for in_flight in state.in_flight.drain(..) {
+                self.device.destroy_fence(in_flight.fence, None);
+            }
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_buffer(self.staging_buffer, None);
+        }
+        self.memory_manager.release_memory(state.arena.memory());
+    }
+}